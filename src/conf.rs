@@ -1,39 +1,89 @@
-use anyhow::Result;
+pub mod convert;
+mod option;
+mod validate;
+
+use anyhow::{Context, Result};
+use dhcproto::v4;
 use mac_address::MacAddress;
 use once_cell::sync::Lazy;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::{
-    fs::File,
-    io::{BufReader, Read},
     net::{Ipv4Addr, SocketAddr},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+pub use self::option::{
+    find_option, format_hex, parse_hex, Dhcp4OptionConfig, Dhcp4OptionType, Dhcp4OptionValue,
 };
+pub use self::validate::ConfigErrors;
 
 const DEFAULT_OMOI_CONFIG_PATH: &str = "/etc/omoi.toml";
 const OMOI_CONFIG_PATH_ENV_KEY: &str = "OMOI_CONFIG_PATH";
-pub static OMOI_CONFIG: Lazy<OmoiConfig> = Lazy::new(OmoiConfig::load);
+const DEFAULT_DECLINE_PROBATION_PERIOD: u32 = 86400;
+const DEFAULT_OFFER_HOLD_TIME: u32 = 60;
+const DEFAULT_RECLAIM_INTERVAL: u32 = 60;
+const DEFAULT_RECLAIM_BATCH_SIZE: usize = 100;
+pub static OMOI_CONFIG: Lazy<LiveConfig> = Lazy::new(LiveConfig::load);
 
-#[derive(Deserialize, Clone, PartialEq, Eq, Debug)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct DebugConfig {
     pub hw_prefix: Option<Vec<u8>>,
+    /// リースの変更をすべて書き出す
+    #[serde(default)]
+    pub log_leases: bool,
 }
 
-#[derive(Deserialize, Clone, PartialEq, Eq, Debug)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct CommonConfig {
     pub database_dir: PathBuf,
+    #[serde(default)]
+    pub lease_store: LeaseStoreKind,
+    /// `lease-store = "sqlite"` のときに使うファイル
+    pub sqlite_path: Option<PathBuf>,
+}
+
+#[cfg(feature = "sqlite")]
+impl CommonConfig {
+    /// 無ければ `database-dir` の隣に置く
+    pub fn sqlite_path(&self) -> PathBuf {
+        self.sqlite_path
+            .clone()
+            .unwrap_or_else(|| self.database_dir.with_extension("sqlite3"))
+    }
+}
+
+/// リースの保存先
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum LeaseStoreKind {
+    /// `database-dir` に置く sled
+    #[default]
+    Sled,
+    /// メモリ上, 再起動すると失われる
+    Memory,
+    /// `sqlite-path` に置く SQLite, `sqlite` feature を有効にしてビルドする必要がある
+    Sqlite,
 }
 
-#[derive(Deserialize, Clone, PartialEq, Eq, Debug)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct HttpConfig {
     pub addr: SocketAddr,
+    /// `POST /config/reload` と `POST /leases4/import` に `Authorization: Bearer` で渡すトークン
+    ///
+    /// 省略するとそれらの API は使えない
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin_token: Option<String>,
 }
 
-#[derive(Deserialize, Clone, PartialEq, Eq, Debug)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct Dhcp4SubnetConfig {
+    /// リースに記録するサブネットの id, 省略すると `subnet` を数値にしたもの
+    pub id: Option<u32>,
     pub subnet: Ipv4Addr,
     pub netmask: Ipv4Addr,
     pub range: (Ipv4Addr, Ipv4Addr),
@@ -41,26 +91,368 @@ pub struct Dhcp4SubnetConfig {
     pub routers: Vec<Ipv4Addr>,
     pub broadcast_address: Ipv4Addr,
     pub address_lease_time: u32,
+    /// 省略すると `dhcp4.server-identifier` を使う
+    pub server_identifier: Option<Ipv4Addr>,
+    /// T1, 省略するとリース時間の 50%
+    pub renewal_time: Option<u32>,
+    /// T2, 省略するとリース時間の 87.5%
+    pub rebinding_time: Option<u32>,
+    /// クライアントが要求できるリース時間の下限, 省略すると address-lease-time
+    pub min_lease_time: Option<u32>,
+    /// クライアントが要求できるリース時間の上限, 省略すると address-lease-time
+    pub max_lease_time: Option<u32>,
+    /// 省略すると `dhcp4.domain-name` を使う
+    pub domain_name: Option<String>,
+    /// このポートからリレーされたパケットにはこのサブネットを使う
+    #[serde(
+        default,
+        deserialize_with = "deserialize_hex",
+        serialize_with = "serialize_hex",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub circuit_id: Option<Vec<u8>>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_hex",
+        serialize_with = "serialize_hex",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub remote_id: Option<Vec<u8>>,
+    #[serde(default, rename = "option", skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<Dhcp4OptionConfig>,
+    #[serde(default, rename = "pool", skip_serializing_if = "Vec::is_empty")]
+    pub pools: Vec<Dhcp4PoolConfig>,
+}
+
+impl Dhcp4SubnetConfig {
+    pub fn id(&self) -> u32 {
+        self.id.unwrap_or_else(|| self.subnet.into())
+    }
+
+    /// クライアントが `requested` 秒を要求したときに与えるリース時間
+    pub fn lease_time(&self, requested: Option<u32>) -> u32 {
+        let min = self.min_lease_time.unwrap_or(self.address_lease_time);
+        let max = self.max_lease_time.unwrap_or(self.address_lease_time);
+        requested
+            .unwrap_or(self.address_lease_time)
+            .clamp(min.min(max), max)
+    }
+
+    /// T2 より短い T1
+    ///
+    /// 設定された T1 が T2 以上ならリース時間の半分を、それも T2 以上なら T2 の 4/7 を使う
+    pub fn renewal_time(&self, lease_time: u32) -> u32 {
+        let rebinding_time = self.rebinding_time(lease_time);
+        self.renewal_time
+            .into_iter()
+            .chain([lease_time / 2])
+            .find(|renewal_time| *renewal_time < rebinding_time)
+            .unwrap_or((u64::from(rebinding_time) * 4 / 7) as u32)
+    }
+
+    /// 設定された T2 がリース時間以上なら既定の値を使う
+    pub fn rebinding_time(&self, lease_time: u32) -> u32 {
+        self.rebinding_time
+            .filter(|rebinding_time| *rebinding_time < lease_time)
+            .unwrap_or((u64::from(lease_time) * 7 / 8) as u32)
+    }
+
+    pub fn contains(&self, address: &Ipv4Addr) -> bool {
+        let mask = u32::from(self.netmask);
+        u32::from(self.subnet) & mask == u32::from(*address) & mask
+    }
+}
+
+/// 予約やサブネットを探すときにクライアントを見分ける値
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Dhcp4HostKey<'a> {
+    pub hardware_address: &'a [u8],
+    /// Client Identifier (option 61)
+    pub client_id: Option<&'a [u8]>,
+    /// Relay Agent Information (option 82) の Agent Circuit ID
+    pub circuit_id: Option<&'a [u8]>,
+    /// Relay Agent Information (option 82) の Agent Remote ID
+    pub remote_id: Option<&'a [u8]>,
+}
+
+impl Dhcp4HostKey<'_> {
+    /// 設定された circuit-id, remote-id がすべて一致するか
+    ///
+    /// どちらも設定されていなければ一致しない
+    fn matches_relay(&self, circuit_id: &Option<Vec<u8>>, remote_id: &Option<Vec<u8>>) -> bool {
+        let matches = |expected: &Option<Vec<u8>>, actual: Option<&[u8]>| {
+            expected
+                .as_deref()
+                .is_none_or(|expected| Some(expected) == actual)
+        };
+        (circuit_id.is_some() || remote_id.is_some())
+            && matches(circuit_id, self.circuit_id)
+            && matches(remote_id, self.remote_id)
+    }
+}
+
+/// サブネットの一部のアドレスにだけ付けるオプション
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Dhcp4PoolConfig {
+    pub range: (Ipv4Addr, Ipv4Addr),
+    #[serde(default, rename = "option", skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<Dhcp4OptionConfig>,
+}
+
+impl Dhcp4PoolConfig {
+    pub fn contains(&self, address: &Ipv4Addr) -> bool {
+        (self.range.0..=self.range.1).contains(address)
+    }
 }
 
-#[derive(Deserialize, Clone, PartialEq, Eq, Debug)]
+/// Vendor Class Identifier (option 60) で分けたクライアントに付けるオプション
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Dhcp4ClassConfig {
+    pub name: String,
+    /// option 60 がこれで始まるクライアントが属する
+    pub vendor_class: String,
+    #[serde(default, rename = "option", skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<Dhcp4OptionConfig>,
+}
+
+impl Dhcp4ClassConfig {
+    pub fn matches(&self, vendor_class: &[u8]) -> bool {
+        vendor_class.starts_with(self.vendor_class.as_bytes())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct Dhcp4HostConfig {
     pub name: String,
-    pub hardware_ethernet: MacAddress,
+    pub hardware_ethernet: Option<MacAddress>,
+    /// Client Identifier (option 61) を `01:00:11:22:33:44:55` のように書く
+    #[serde(
+        default,
+        deserialize_with = "deserialize_hex",
+        serialize_with = "serialize_hex",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub client_id: Option<Vec<u8>>,
+    /// リレーエージェントのポートで予約する
+    #[serde(
+        default,
+        deserialize_with = "deserialize_hex",
+        serialize_with = "serialize_hex",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub circuit_id: Option<Vec<u8>>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_hex",
+        serialize_with = "serialize_hex",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub remote_id: Option<Vec<u8>>,
     pub fixed_address: Ipv4Addr,
+    #[serde(default, rename = "option", skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<Dhcp4OptionConfig>,
 }
 
-#[derive(Deserialize, Clone, PartialEq, Eq, Debug)]
+/// `01:02:ff` のような 16 進数のバイト列
+fn deserialize_hex<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<u8>>, D::Error> {
+    let Some(hex) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    let bytes = option::parse_hex(&hex).map_err(serde::de::Error::custom)?;
+    Ok(Some(bytes))
+}
+
+fn serialize_hex<S: Serializer>(bytes: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+    bytes
+        .as_deref()
+        .map(option::format_hex)
+        .serialize(serializer)
+}
+
+/// 応答の送り方
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Dhcp4Transport {
+    /// UDP ソケット
+    #[default]
+    Udp,
+    /// AF_PACKET (アドレス未設定のクライアントにもユニキャストできる)
+    Packet,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct Dhcp4Config {
     #[serde(rename = "subnet")]
     pub subnets: Vec<Dhcp4SubnetConfig>,
     #[serde(rename = "host")]
     pub hosts: Vec<Dhcp4HostConfig>,
+    /// DHCPDECLINE されたアドレスを隔離しておく秒数
+    #[serde(default = "default_decline_probation_period")]
+    pub decline_probation_period: u32,
+    /// DHCPOFFER したアドレスを DHCPREQUEST を待って確保しておく秒数
+    #[serde(default = "default_offer_hold_time")]
+    pub offer_hold_time: u32,
+    /// DHCPOFFER をデータベースにも記録して、再起動しても引き継ぐ
+    #[serde(default)]
+    pub persist_offers: bool,
+    #[serde(default)]
+    pub reclaim: Dhcp4ReclaimConfig,
+    #[serde(default)]
+    pub transport: Dhcp4Transport,
+    /// 省略すると受信したインターフェースのアドレスを使う
+    pub server_identifier: Option<Ipv4Addr>,
+    pub domain_name: Option<String>,
+    #[serde(default, rename = "option", skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<Dhcp4OptionConfig>,
+    #[serde(default, rename = "class", skip_serializing_if = "Vec::is_empty")]
+    pub classes: Vec<Dhcp4ClassConfig>,
+}
+
+fn default_decline_probation_period() -> u32 {
+    DEFAULT_DECLINE_PROBATION_PERIOD
 }
 
-#[derive(Deserialize, Clone, PartialEq, Eq, Debug)]
+fn default_offer_hold_time() -> u32 {
+    DEFAULT_OFFER_HOLD_TIME
+}
+
+/// 期限の切れたリースの回収
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Dhcp4ReclaimConfig {
+    /// 回収する間隔 (秒)
+    #[serde(default = "default_reclaim_interval")]
+    pub interval: u32,
+    /// 一度に回収するリースの数
+    #[serde(default = "default_reclaim_batch_size")]
+    pub batch_size: usize,
+    /// 期限切れにしたリースを削除するまでの秒数, 省略すると削除しない
+    pub grace_period: Option<u32>,
+}
+
+impl Default for Dhcp4ReclaimConfig {
+    fn default() -> Self {
+        Dhcp4ReclaimConfig {
+            interval: DEFAULT_RECLAIM_INTERVAL,
+            batch_size: DEFAULT_RECLAIM_BATCH_SIZE,
+            grace_period: None,
+        }
+    }
+}
+
+fn default_reclaim_interval() -> u32 {
+    DEFAULT_RECLAIM_INTERVAL
+}
+
+fn default_reclaim_batch_size() -> usize {
+    DEFAULT_RECLAIM_BATCH_SIZE
+}
+
+impl Dhcp4Config {
+    /// `address` が属するサブネットを探す
+    pub fn find_subnet(&self, address: &Ipv4Addr) -> Option<&Dhcp4SubnetConfig> {
+        self.subnets.iter().find(|subnet| subnet.contains(address))
+    }
+
+    /// リレーエージェントのポートに割り当てたサブネットを探す
+    pub fn find_subnet_by_relay(&self, key: &Dhcp4HostKey) -> Option<&Dhcp4SubnetConfig> {
+        self.subnets
+            .iter()
+            .find(|subnet| key.matches_relay(&subnet.circuit_id, &subnet.remote_id))
+    }
+
+    /// `subnet` 内に固定アドレスを予約しているホストを探す
+    ///
+    /// client-id, hardware-ethernet, リレーエージェントのポートの順に優先する
+    pub fn find_host(
+        &self,
+        key: &Dhcp4HostKey,
+        subnet: &Dhcp4SubnetConfig,
+    ) -> Option<&Dhcp4HostConfig> {
+        let hosts = || {
+            self.hosts
+                .iter()
+                .filter(|host| subnet.contains(&host.fixed_address))
+        };
+        let by_client_id = key.client_id.and_then(|client_id| {
+            hosts().find(|host| host.client_id.as_deref() == Some(client_id))
+        });
+        by_client_id
+            .or_else(|| {
+                hosts().find(|host| {
+                    host.hardware_ethernet
+                        .is_some_and(|mac| mac.bytes() == key.hardware_address)
+                })
+            })
+            .or_else(|| hosts().find(|host| key.matches_relay(&host.circuit_id, &host.remote_id)))
+    }
+
+    /// `address` を払い出すクライアントに追加で載せるオプションを集める
+    ///
+    /// 全体, サブネット, プール, クラス, ホストの順に、後のものが同じコードを上書きする
+    pub fn options_for(
+        &self,
+        subnet: &Dhcp4SubnetConfig,
+        address: &Ipv4Addr,
+        vendor_class: Option<&[u8]>,
+        host: Option<&Dhcp4HostConfig>,
+    ) -> Vec<v4::DhcpOption> {
+        let domain_name =
+            |domain_name: &Option<String>| domain_name.clone().map(Dhcp4OptionConfig::domain_name);
+        let pools = subnet
+            .pools
+            .iter()
+            .filter(|pool| pool.contains(address))
+            .flat_map(|pool| pool.options.iter().cloned());
+        let classes = self
+            .classes
+            .iter()
+            .filter(|class| vendor_class.is_some_and(|vendor_class| class.matches(vendor_class)))
+            .flat_map(|class| class.options.iter().cloned());
+        let hosts = host
+            .into_iter()
+            .flat_map(|host| host.options.iter().cloned());
+
+        let mut options = std::collections::BTreeMap::new();
+        for option in domain_name(&self.domain_name)
+            .into_iter()
+            .chain(self.options.iter().cloned())
+            .chain(domain_name(&subnet.domain_name))
+            .chain(subnet.options.iter().cloned())
+            .chain(pools)
+            .chain(classes)
+            .chain(hosts)
+        {
+            options.insert(option.code, option);
+        }
+        options
+            .into_values()
+            .filter_map(|option| match option.to_dhcp_option() {
+                Ok(dhcp_option) => Some(dhcp_option),
+                // 検証を通った設定なら起きない
+                Err(e) => {
+                    eprintln!("option {} is not sent: {e:#}", option.code);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// `address` を固定アドレスとして予約しているホストを探す
+    pub fn find_host_by_address(&self, address: &Ipv4Addr) -> Option<&Dhcp4HostConfig> {
+        self.hosts
+            .iter()
+            .find(|host| host.fixed_address == *address)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct OmoiConfig {
     pub common: CommonConfig,
@@ -70,29 +462,103 @@ pub struct OmoiConfig {
 }
 
 impl OmoiConfig {
-    fn try_load() -> Result<OmoiConfig> {
-        let file = match std::env::var(OMOI_CONFIG_PATH_ENV_KEY) {
-            Ok(path) => File::open(path)?,
-            Err(_) => File::open(DEFAULT_OMOI_CONFIG_PATH)?,
-        };
-        let size = file
-            .metadata()
-            .map(|meta| meta.len() as usize)
-            .unwrap_or(1024);
-        let mut reader = BufReader::new(file);
-        let mut buffer = Vec::with_capacity(size);
-        let size = reader.read_to_end(&mut buffer)?;
-        let config: OmoiConfig = toml::from_slice(&buffer[..size])?;
+    /// `OMOI_CONFIG_PATH`, 無ければ `/etc/omoi.toml`
+    pub fn path() -> PathBuf {
+        std::env::var_os(OMOI_CONFIG_PATH_ENV_KEY)
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_OMOI_CONFIG_PATH))
+    }
+
+    /// TOML として読むだけで、中身は確かめない
+    pub fn read(path: &Path) -> Result<OmoiConfig> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let config =
+            toml::from_str(&text).with_context(|| format!("failed to parse {}", path.display()))?;
+        Ok(config)
+    }
+
+    /// 読んで、矛盾が無いか確かめる
+    pub fn try_load_from(path: &Path) -> Result<OmoiConfig> {
+        let config = Self::read(path)?;
+        config.validate()?;
         Ok(config)
     }
-    fn load() -> OmoiConfig {
-        Self::try_load().expect("OmoiConfig Read Error")
+
+    pub fn log_leases(&self) -> bool {
+        self.debug.as_ref().is_some_and(|debug| debug.log_leases)
+    }
+
+    /// 読み込み直しても反映されず、再起動が必要な設定の中で変わったもの
+    fn restart_required(&self, new: &OmoiConfig) -> Vec<&'static str> {
+        let changes = [
+            ("common", self.common != new.common),
+            ("http.addr", self.http.addr != new.http.addr),
+            (
+                "dhcp4.transport",
+                self.dhcp4.transport != new.dhcp4.transport,
+            ),
+            (
+                "dhcp4.persist-offers",
+                self.dhcp4.persist_offers != new.dhcp4.persist_offers,
+            ),
+            ("dhcp4.reclaim", self.dhcp4.reclaim != new.dhcp4.reclaim),
+            ("debug.log-leases", self.log_leases() != new.log_leases()),
+        ];
+        changes
+            .into_iter()
+            .filter_map(|(path, changed)| changed.then_some(path))
+            .collect()
+    }
+}
+
+/// 動いている間に読み込み直せる設定
+///
+/// 処理中のパケットは受信したときの設定を使い続け、差し替えた後に受信したパケットから新しい設定を使う
+#[derive(Debug)]
+pub struct LiveConfig {
+    path: PathBuf,
+    current: RwLock<Arc<OmoiConfig>>,
+}
+
+impl LiveConfig {
+    pub fn open(path: PathBuf) -> Result<LiveConfig> {
+        let config = OmoiConfig::try_load_from(&path)?;
+        Ok(LiveConfig {
+            path,
+            current: RwLock::new(Arc::new(config)),
+        })
+    }
+
+    fn load() -> LiveConfig {
+        Self::open(OmoiConfig::path()).expect("OmoiConfig Read Error")
+    }
+
+    pub fn current(&self) -> Arc<OmoiConfig> {
+        self.current
+            .read()
+            .expect("config lock is poisoned")
+            .clone()
+    }
+
+    /// 設定ファイルを読み直し、矛盾が無ければ差し替える
+    ///
+    /// 読めないか誤りがあれば今の設定のまま。再起動しないと反映されない設定のうち変わったものを返す
+    pub fn reload(&self) -> Result<Vec<&'static str>> {
+        let config = OmoiConfig::try_load_from(&self.path)?;
+        let mut current = self.current.write().expect("config lock is poisoned");
+        let restart_required = current.restart_required(&config);
+        *current = Arc::new(config);
+        eprintln!("config reloaded from {}", self.path.display());
+        for path in &restart_required {
+            eprintln!("{path} was changed, the old value is used until omoi is restarted");
+        }
+        Ok(restart_required)
     }
 }
 
 #[test]
 fn parse_test() {
-    use std::path::Path;
     const TOML_TEXT: &str = r#"
 [common]
 database-dir = "omoi-db"
@@ -123,9 +589,12 @@ fixed-address = "192.168.0.11"
     let expected = OmoiConfig {
         common: CommonConfig {
             database_dir: Path::new("omoi-db").to_owned(),
+            lease_store: LeaseStoreKind::Sled,
+            sqlite_path: None,
         },
         dhcp4: Dhcp4Config {
             subnets: vec![Dhcp4SubnetConfig {
+                id: None,
                 subnet: Ipv4Addr::new(192, 168, 0, 1),
                 netmask: Ipv4Addr::new(255, 255, 255, 0),
                 range: (
@@ -136,21 +605,213 @@ fixed-address = "192.168.0.11"
                 routers: vec![Ipv4Addr::new(192, 168, 0, 1)],
                 broadcast_address: Ipv4Addr::new(192, 168, 0, 255),
                 address_lease_time: 172800,
+                server_identifier: None,
+                renewal_time: None,
+                rebinding_time: None,
+                min_lease_time: None,
+                max_lease_time: None,
+                domain_name: None,
+                circuit_id: None,
+                remote_id: None,
+                options: vec![],
+                pools: vec![],
             }],
             hosts: vec![Dhcp4HostConfig {
                 name: "host1".to_string(),
-                hardware_ethernet: MacAddress::new([0x00, 0x00, 0x00, 0x11, 0x11, 0x11]),
+                hardware_ethernet: Some(MacAddress::new([0x00, 0x00, 0x00, 0x11, 0x11, 0x11])),
+                client_id: None,
+                circuit_id: None,
+                remote_id: None,
                 fixed_address: Ipv4Addr::new(192, 168, 0, 11),
+                options: vec![],
             }],
+            decline_probation_period: DEFAULT_DECLINE_PROBATION_PERIOD,
+            offer_hold_time: DEFAULT_OFFER_HOLD_TIME,
+            persist_offers: false,
+            reclaim: Dhcp4ReclaimConfig::default(),
+            transport: Dhcp4Transport::Udp,
+            server_identifier: None,
+            domain_name: Some("example.local".to_string()),
+            options: vec![],
+            classes: vec![],
         },
         debug: Some(DebugConfig {
             hw_prefix: Some(vec![0x00, 0x00, 0x00]),
+            log_leases: false,
         }),
         http: HttpConfig {
             addr: SocketAddr::from(([0, 0, 0, 0], 11003)),
+            admin_token: None,
         },
     };
 
     let config = toml::from_str::<OmoiConfig>(TOML_TEXT);
     assert_eq!(Ok(expected), config);
 }
+
+#[test]
+fn options_for_test() {
+    const TOML_TEXT: &str = r#"
+subnet = []
+host = []
+domain-name = "example.local"
+
+[[option]]
+name = "ntp-servers"
+value = ["192.168.0.1"]
+
+[[option]]
+name = "interface-mtu"
+value = 1500
+
+[[class]]
+name = "pxe"
+vendor-class = "PXEClient"
+
+[[class.option]]
+name = "bootfile-name"
+value = "pxelinux.0"
+
+[[class.option]]
+name = "interface-mtu"
+value = 1400
+"#;
+    let config = toml::from_str::<Dhcp4Config>(TOML_TEXT).unwrap();
+    let subnet = toml::from_str::<Dhcp4SubnetConfig>(
+        r#"
+subnet = "192.168.0.0"
+netmask = "255.255.255.0"
+range = ["192.168.0.101", "192.168.0.250"]
+domain-name-servers = []
+routers = []
+broadcast-address = "192.168.0.255"
+address-lease-time = 3600
+domain-name = "lab.example.local"
+
+[[pool]]
+range = ["192.168.0.201", "192.168.0.250"]
+
+[[pool.option]]
+name = "ntp-servers"
+value = ["192.168.0.2"]
+"#,
+    )
+    .unwrap();
+    let host = toml::from_str::<Dhcp4HostConfig>(
+        r#"
+name = "host1"
+hardware-ethernet = "00:00:00:11:11:11"
+fixed-address = "192.168.0.11"
+
+[[option]]
+name = "host-name"
+value = "host1"
+"#,
+    )
+    .unwrap();
+
+    let domain_name = |name: &str| v4::DhcpOption::DomainName(name.to_string());
+    let ntp = |last| v4::DhcpOption::NTPServers(vec![Ipv4Addr::new(192, 168, 0, last)]);
+
+    // サブネットの domain-name が全体のものを上書きする
+    assert_eq!(
+        config.options_for(&subnet, &Ipv4Addr::new(192, 168, 0, 101), None, None),
+        vec![
+            domain_name("lab.example.local"),
+            v4::DhcpOption::InterfaceMtu(1500),
+            ntp(1),
+        ]
+    );
+    // プール, クラス, ホストの順に上書きする
+    assert_eq!(
+        config.options_for(
+            &subnet,
+            &Ipv4Addr::new(192, 168, 0, 201),
+            Some(b"PXEClient:Arch:00000"),
+            Some(&host),
+        ),
+        vec![
+            v4::DhcpOption::Hostname("host1".to_string()),
+            domain_name("lab.example.local"),
+            v4::DhcpOption::InterfaceMtu(1400),
+            ntp(2),
+            v4::DhcpOption::Unknown(v4::UnknownOption::new(
+                v4::OptionCode::from(67),
+                b"pxelinux.0".to_vec()
+            )),
+        ]
+    );
+}
+
+#[test]
+fn lease_time_test() {
+    let mut subnet = toml::from_str::<Dhcp4SubnetConfig>(
+        r#"
+subnet = "192.168.0.0"
+netmask = "255.255.255.0"
+range = ["192.168.0.101", "192.168.0.250"]
+domain-name-servers = []
+routers = []
+broadcast-address = "192.168.0.255"
+address-lease-time = 3600
+"#,
+    )
+    .unwrap();
+    // 上下限が無ければ要求は無視する
+    assert_eq!(subnet.lease_time(None), 3600);
+    assert_eq!(subnet.lease_time(Some(60)), 3600);
+
+    subnet.min_lease_time = Some(300);
+    subnet.max_lease_time = Some(604800);
+    assert_eq!(subnet.lease_time(None), 3600);
+    assert_eq!(subnet.lease_time(Some(60)), 300);
+    assert_eq!(subnet.lease_time(Some(86400)), 86400);
+    assert_eq!(subnet.lease_time(Some(u32::MAX)), 604800);
+    assert_eq!(subnet.renewal_time(300), 150);
+    assert_eq!(subnet.rebinding_time(300), 262);
+
+    subnet.renewal_time = Some(1800);
+    assert_eq!(subnet.renewal_time(86400), 1800);
+    assert_eq!(subnet.renewal_time(300), 150);
+
+    // T1 は T2 より短くする
+    subnet.renewal_time = None;
+    subnet.rebinding_time = Some(1000);
+    assert_eq!(subnet.rebinding_time(86400), 1000);
+    assert_eq!(subnet.renewal_time(86400), 571);
+    subnet.renewal_time = Some(1800);
+    assert_eq!(subnet.renewal_time(86400), 571);
+    subnet.renewal_time = Some(600);
+    assert_eq!(subnet.renewal_time(86400), 600);
+}
+
+#[test]
+fn reload_test() {
+    let path = std::env::temp_dir().join(format!("omoi-reload-test-{}.toml", std::process::id()));
+    let write = |config: &OmoiConfig| {
+        let text = toml::to_string(&toml::Value::try_from(config).unwrap()).unwrap();
+        std::fs::write(&path, text).unwrap();
+    };
+    let config = crate::dhcp::v4::test_config();
+    write(&config);
+    let live = LiveConfig::open(path.clone()).unwrap();
+    let before = live.current();
+
+    let mut changed = config.clone();
+    changed.dhcp4.subnets[0].domain_name_servers = vec![Ipv4Addr::new(192, 168, 0, 53)];
+    changed.dhcp4.offer_hold_time = 10;
+    changed.dhcp4.persist_offers = true;
+    write(&changed);
+    assert_eq!(live.reload().unwrap(), vec!["dhcp4.persist-offers"]);
+    assert_eq!(*live.current(), changed);
+    // 差し替える前に取り出した設定はそのまま
+    assert_eq!(*before, config);
+
+    // 誤りがあれば差し替えない
+    let mut broken = changed.clone();
+    broken.dhcp4.hosts[1].fixed_address = broken.dhcp4.hosts[0].fixed_address;
+    write(&broken);
+    assert!(live.reload().is_err());
+    assert_eq!(*live.current(), changed);
+    std::fs::remove_file(&path).unwrap();
+}
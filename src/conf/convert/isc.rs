@@ -0,0 +1,460 @@
+use std::net::Ipv4Addr;
+
+use anyhow::{anyhow, bail, Result};
+use mac_address::MacAddress;
+
+use super::{
+    global_options, merge_options, omoi_config, option_with_type, Conversion, Report,
+    SubnetParameters,
+};
+use crate::{
+    conf::{
+        find_option,
+        option::{format_hex, parse_hex},
+        Dhcp4Config, Dhcp4HostConfig, Dhcp4OptionConfig, Dhcp4OptionType, Dhcp4PoolConfig,
+        Dhcp4SubnetConfig, DEFAULT_DECLINE_PROBATION_PERIOD, DEFAULT_OFFER_HOLD_TIME,
+    },
+    isc::{self, Statement, Token},
+};
+
+/// 入れ子になった宣言から引き継ぐ設定
+#[derive(Clone, Default)]
+struct Scope {
+    options: Vec<Dhcp4OptionConfig>,
+    /// `group` に書かれたオプション, その中の `host` に付ける
+    host_options: Vec<Dhcp4OptionConfig>,
+    lease_time: Option<u32>,
+    min_lease_time: Option<u32>,
+    max_lease_time: Option<u32>,
+    server_identifier: Option<Ipv4Addr>,
+}
+
+#[derive(Default)]
+struct Converter {
+    report: Report,
+    global: Scope,
+    subnets: Vec<Dhcp4SubnetConfig>,
+    hosts: Vec<Dhcp4HostConfig>,
+}
+
+/// `,` で区切られた値, 引用符で囲まれた文字列は hex 型なら 16 進数にする
+fn values(tokens: &[Token], kind: Dhcp4OptionType) -> Vec<String> {
+    let mut values = vec![];
+    let mut value = String::new();
+    for token in tokens {
+        match token {
+            Token::Word(word) if word == "," => values.push(std::mem::take(&mut value)),
+            Token::Word(word) => value.push_str(word),
+            Token::Str(bytes) if kind == Dhcp4OptionType::Hex => value.push_str(&format_hex(bytes)),
+            Token::Str(bytes) => value.push_str(&String::from_utf8_lossy(bytes)),
+        }
+    }
+    values.push(value);
+    values
+}
+
+/// 16 進数か引用符で囲まれた文字列
+fn bytes(tokens: &[Token]) -> Result<Vec<u8>> {
+    match tokens {
+        [Token::Str(bytes)] => Ok(bytes.clone()),
+        [Token::Word(hex)] => parse_hex(hex),
+        _ => bail!("expected a string or hex bytes"),
+    }
+}
+
+fn location(statement: &Statement) -> String {
+    format!("line {}", statement.line)
+}
+
+impl Converter {
+    /// `option 名前 値, ...;` を読む
+    fn option(&mut self, statement: &Statement) -> Option<Dhcp4OptionConfig> {
+        let words = statement.words();
+        let name = words.get(1).copied().unwrap_or_default();
+        if words.get(2) == Some(&"code") {
+            self.report.push(
+                location(statement),
+                format!("option definition {name} is not supported"),
+            );
+            return None;
+        }
+        let Some((code, kind)) = find_option(name) else {
+            self.report.push(
+                location(statement),
+                format!("option {name} is not supported"),
+            );
+            return None;
+        };
+        let result = option_with_type(code, kind, &values(&statement.tokens[2..], kind));
+        match result {
+            Ok(option) => Some(option),
+            Err(e) => {
+                self.report
+                    .push(location(statement), format!("{name}: {e:#}"));
+                None
+            }
+        }
+    }
+
+    /// 宣言の中の `;` で終わる文を `scope` に読み込む
+    ///
+    /// `handled` で始まる文は呼び出し元が読むので飛ばす
+    fn parameters(
+        &mut self,
+        statements: &[Statement],
+        scope: &Scope,
+        in_group: bool,
+        handled: &[&str],
+    ) -> Scope {
+        let mut scope = scope.clone();
+        let mut options = vec![];
+        for statement in statements.iter().filter(|s| s.block.is_none()) {
+            let words = statement.words();
+            let number = |i: usize| words.get(i).and_then(|word| word.parse::<u32>().ok());
+            match words.as_slice() {
+                [first, ..] if handled.contains(first) => {}
+                ["option", ..] => options.extend(self.option(statement)),
+                ["default-lease-time", _] if number(1).is_some() => scope.lease_time = number(1),
+                ["min-lease-time", _] if number(1).is_some() => scope.min_lease_time = number(1),
+                ["max-lease-time", _] if number(1).is_some() => scope.max_lease_time = number(1),
+                ["server-identifier", ip] if ip.parse::<Ipv4Addr>().is_ok() => {
+                    scope.server_identifier = ip.parse().ok()
+                }
+                [first, ..] => self
+                    .report
+                    .push(location(statement), format!("{first} is not supported")),
+                [] => {}
+            }
+        }
+        scope.options = merge_options(&scope.options, &options);
+        if in_group {
+            scope.host_options = merge_options(&scope.host_options, &options);
+        }
+        scope
+    }
+
+    /// `subnet`, `shared-network`, `group`, `host` を読む
+    fn declarations(&mut self, statements: &[Statement], scope: &Scope) {
+        for statement in statements {
+            let Some(block) = &statement.block else {
+                continue;
+            };
+            match statement.words().as_slice() {
+                ["subnet", subnet, "netmask", netmask] => match (subnet.parse(), netmask.parse()) {
+                    (Ok(subnet), Ok(netmask)) => {
+                        self.subnet(statement, block, scope, subnet, netmask)
+                    }
+                    _ => self.report.push(location(statement), "invalid subnet"),
+                },
+                ["shared-network", ..] => {
+                    let scope = self.parameters(block, scope, false, &[]);
+                    self.declarations(block, &scope);
+                }
+                ["group"] => {
+                    let scope = self.parameters(block, scope, true, &[]);
+                    self.declarations(block, &scope);
+                }
+                ["host", ..] => self.host(statement, block, scope),
+                [first, ..] => self
+                    .report
+                    .push(location(statement), format!("{first} is not supported")),
+                [] => self.report.push(location(statement), "unexpected block"),
+            }
+        }
+    }
+
+    fn subnet(
+        &mut self,
+        statement: &Statement,
+        block: &[Statement],
+        scope: &Scope,
+        subnet: Ipv4Addr,
+        netmask: Ipv4Addr,
+    ) {
+        let scope = self.parameters(block, scope, false, &["range"]);
+        let mut ranges = self.ranges(block);
+        let mut pools = vec![];
+        let mut declarations = vec![];
+        for declaration in block {
+            let (["pool"], Some(pool)) = (declaration.words().as_slice(), &declaration.block)
+            else {
+                declarations.push(declaration.clone());
+                continue;
+            };
+            // プールにはプールに書かれたオプションだけを付ける
+            let pool_scope = Scope {
+                options: vec![],
+                ..scope.clone()
+            };
+            let pool_scope = self.parameters(pool, &pool_scope, false, &["range"]);
+            for (line, range) in self.ranges(pool) {
+                ranges.push((line, range));
+                if !pool_scope.options.is_empty() {
+                    pools.push(Dhcp4PoolConfig {
+                        range,
+                        options: pool_scope.options.clone(),
+                    });
+                }
+            }
+            self.declarations(pool, &scope);
+        }
+        self.declarations(&declarations, &scope);
+
+        let Some((&(_, range), rest)) = ranges.split_first() else {
+            self.report.push(
+                location(statement),
+                format!("subnet {subnet} has no range and is not translated"),
+            );
+            return;
+        };
+        for (line, (start, end)) in rest {
+            self.report.push(
+                format!("line {line}"),
+                format!("range {start} {end} is not translated, omoi serves one range per subnet"),
+            );
+        }
+        let mut config = SubnetParameters {
+            subnet,
+            netmask,
+            range,
+            options: scope.options,
+            inherited: self.global.options.clone(),
+            lease_time: scope.lease_time,
+            min_lease_time: scope.min_lease_time,
+            max_lease_time: scope.max_lease_time,
+            renewal_time: None,
+            rebinding_time: None,
+            server_identifier: scope
+                .server_identifier
+                .filter(|_| scope.server_identifier != self.global.server_identifier),
+        }
+        .into_config(None);
+        config.pools = pools;
+        self.subnets.push(config);
+    }
+
+    /// `range [dynamic-bootp] 開始 [終了];`
+    fn ranges(&mut self, statements: &[Statement]) -> Vec<(usize, (Ipv4Addr, Ipv4Addr))> {
+        let mut ranges = vec![];
+        for statement in statements.iter().filter(|s| s.block.is_none()) {
+            let words = statement.words();
+            let addresses = match words.as_slice() {
+                ["range", "dynamic-bootp", addresses @ ..] | ["range", addresses @ ..] => addresses,
+                _ => continue,
+            };
+            let addresses = addresses
+                .iter()
+                .map(|address| address.parse::<Ipv4Addr>())
+                .collect::<Result<Vec<_>, _>>();
+            match addresses.as_deref() {
+                Ok([start]) => ranges.push((statement.line, (*start, *start))),
+                Ok([start, end]) => ranges.push((statement.line, (*start, *end))),
+                _ => self.report.push(location(statement), "invalid range"),
+            }
+        }
+        ranges
+    }
+
+    fn host(&mut self, statement: &Statement, block: &[Statement], scope: &Scope) {
+        let name = match (statement.words().get(1), statement.string()) {
+            (Some(name), _) => name.to_string(),
+            (None, Some(name)) => String::from_utf8_lossy(name).into_owned(),
+            (None, None) => String::new(),
+        };
+        let mut host = Dhcp4HostConfig {
+            name: name.clone(),
+            hardware_ethernet: None,
+            client_id: None,
+            circuit_id: None,
+            remote_id: None,
+            fixed_address: Ipv4Addr::UNSPECIFIED,
+            options: vec![],
+        };
+        let mut fixed_address = None;
+        let mut parameters = vec![];
+        for statement in block {
+            let words = statement.words();
+            let result = match words.as_slice() {
+                _ if statement.block.is_some() => Err(anyhow!("block in host is not supported")),
+                ["hardware", "ethernet", mac] => mac
+                    .parse::<MacAddress>()
+                    .map(|mac| host.hardware_ethernet = Some(mac))
+                    .map_err(anyhow::Error::from),
+                ["hardware", kind, ..] => Err(anyhow!("hardware {kind} is not supported")),
+                ["fixed-address", address, rest @ ..] => {
+                    if !rest.is_empty() {
+                        self.report.push(
+                            location(statement),
+                            format!("host {name} uses only the first fixed-address"),
+                        );
+                    }
+                    address
+                        .parse::<Ipv4Addr>()
+                        .map(|address| fixed_address = Some(address))
+                        .map_err(|_| anyhow!("fixed-address {address} is not an IPv4 address"))
+                }
+                ["option", "dhcp-client-identifier", ..] => {
+                    bytes(&statement.tokens[2..]).map(|id| host.client_id = Some(id))
+                }
+                ["host-identifier", "option", "agent.circuit-id", ..] => {
+                    bytes(&statement.tokens[3..]).map(|id| host.circuit_id = Some(id))
+                }
+                ["host-identifier", "option", "agent.remote-id", ..] => {
+                    bytes(&statement.tokens[3..]).map(|id| host.remote_id = Some(id))
+                }
+                ["host-identifier", ..] => Err(anyhow!("host-identifier is not supported")),
+                _ => {
+                    parameters.push(statement.clone());
+                    Ok(())
+                }
+            };
+            if let Err(e) = result {
+                self.report.push(location(statement), format!("{e:#}"));
+            }
+        }
+        // `group` に書かれたオプションも引き継ぐ
+        let host_scope = Scope {
+            options: scope.host_options.clone(),
+            ..Default::default()
+        };
+        host.options = self
+            .parameters(&parameters, &host_scope, false, &[])
+            .options;
+        let Some(fixed_address) = fixed_address else {
+            self.report.push(
+                location(statement),
+                format!("host {name} has no fixed-address and is not translated"),
+            );
+            return;
+        };
+        host.fixed_address = fixed_address;
+        self.hosts.push(host);
+    }
+}
+
+pub fn convert(text: &str) -> Result<Conversion> {
+    let statements = isc::parse(text)?;
+    let mut converter = Converter::default();
+    converter.global = converter.parameters(&statements, &Scope::default(), false, &[]);
+    let scope = Scope {
+        options: vec![],
+        ..converter.global.clone()
+    };
+    converter.declarations(&statements, &scope);
+
+    let mut untranslated = converter.report.0;
+    untranslated.sort_by_key(|untranslated| {
+        untranslated
+            .location
+            .trim_start_matches("line ")
+            .parse::<usize>()
+            .ok()
+    });
+    let (options, domain_name) = global_options(converter.global.options);
+    let dhcp4 = Dhcp4Config {
+        subnets: converter.subnets,
+        hosts: converter.hosts,
+        decline_probation_period: DEFAULT_DECLINE_PROBATION_PERIOD,
+        offer_hold_time: DEFAULT_OFFER_HOLD_TIME,
+        persist_offers: false,
+        reclaim: Default::default(),
+        transport: Default::default(),
+        server_identifier: converter.global.server_identifier,
+        domain_name,
+        options,
+        classes: vec![],
+    };
+    Ok(Conversion {
+        config: omoi_config(dhcp4),
+        untranslated,
+    })
+}
+
+#[test]
+fn convert_test() {
+    use crate::conf::{Dhcp4OptionValue, OmoiConfig};
+
+    let text = r#"
+option domain-name "example.org";
+option domain-name-servers 192.168.0.1, 192.168.0.2;
+default-lease-time 600;
+authoritative;
+
+subnet 192.168.0.0 netmask 255.255.255.0 {
+  range 192.168.0.100 192.168.0.200;
+  option routers 192.168.0.1;
+  pool {
+    range 192.168.0.210 192.168.0.220;
+    option ntp-servers 192.168.0.1;
+  }
+  host printer {
+    hardware ethernet 00:11:22:33:44:55;
+    fixed-address 192.168.0.10;
+  }
+}
+
+shared-network office {
+  option domain-name "office.example.org";
+  subnet 10.0.0.0 netmask 255.255.255.0 {
+    range 10.0.0.10 10.0.0.50;
+    default-lease-time 3600;
+  }
+}
+
+group {
+  option interface-mtu 1400;
+  host pc {
+    option dhcp-client-identifier "\001\000\021\"3DU";
+    fixed-address 10.0.0.5;
+  }
+}
+"#;
+    let conversion = convert(text).unwrap();
+    let config = &conversion.config.dhcp4;
+    assert_eq!(config.domain_name.as_deref(), Some("example.org"));
+    assert!(config.options.is_empty());
+    let [subnet, office] = config.subnets.as_slice() else {
+        panic!("expected 2 subnets: {:?}", config.subnets);
+    };
+    assert_eq!(
+        subnet.range,
+        (
+            Ipv4Addr::new(192, 168, 0, 100),
+            Ipv4Addr::new(192, 168, 0, 200)
+        )
+    );
+    assert_eq!(subnet.routers, vec![Ipv4Addr::new(192, 168, 0, 1)]);
+    assert_eq!(subnet.domain_name_servers.len(), 2);
+    assert_eq!(subnet.broadcast_address, Ipv4Addr::new(192, 168, 0, 255));
+    assert_eq!(subnet.address_lease_time, 600);
+    assert_eq!(subnet.pools[0].options[0].code, 42);
+    assert_eq!(office.domain_name.as_deref(), Some("office.example.org"));
+    assert_eq!(office.address_lease_time, 3600);
+    assert_eq!(config.hosts.len(), 2);
+    assert_eq!(
+        config.hosts[1].client_id,
+        Some(vec![1, 0, 0x11, b'"', b'3', b'D', b'U'])
+    );
+    assert_eq!(
+        config.hosts[1].options[0].value,
+        Dhcp4OptionValue::U16(1400)
+    );
+    assert_eq!(
+        conversion
+            .untranslated
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>(),
+        vec![
+            "line 5: authoritative is not supported",
+            "line 11: range 192.168.0.210 192.168.0.220 is not translated, omoi serves one range per subnet",
+        ]
+    );
+
+    // 書き出した omoi.toml をそのまま読める
+    let toml = conversion.to_toml().unwrap();
+    assert_eq!(
+        toml::from_str::<OmoiConfig>(&toml).unwrap(),
+        conversion.config
+    );
+}
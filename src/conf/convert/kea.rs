@@ -0,0 +1,460 @@
+use std::net::Ipv4Addr;
+
+use anyhow::{anyhow, bail, Result};
+use ipnet::Ipv4Net;
+use mac_address::MacAddress;
+use serde_json::{Map, Value};
+
+use super::{
+    global_options, merge_options, omoi_config, option, option_with_type, Conversion, Report,
+    SubnetParameters,
+};
+use crate::conf::{
+    find_option, option::parse_hex, Dhcp4Config, Dhcp4HostConfig, Dhcp4OptionConfig,
+    Dhcp4OptionType, Dhcp4PoolConfig, Dhcp4SubnetConfig, DEFAULT_DECLINE_PROBATION_PERIOD,
+    DEFAULT_OFFER_HOLD_TIME,
+};
+
+/// Kea の設定は `#`, `//`, `/* */` のコメントを書ける JSON
+fn strip_comments(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    let mut in_string = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => in_string = !in_string,
+            '\\' if in_string => {
+                stripped.push(c);
+                stripped.extend(chars.next());
+                continue;
+            }
+            '#' if !in_string => {
+                while chars.next_if(|&c| c != '\n').is_some() {}
+                continue;
+            }
+            '/' if !in_string && chars.peek() == Some(&'/') => {
+                while chars.next_if(|&c| c != '\n').is_some() {}
+                continue;
+            }
+            '/' if !in_string && chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = ' ';
+                for c in chars.by_ref() {
+                    if last == '*' && c == '/' {
+                        break;
+                    }
+                    last = c;
+                }
+                continue;
+            }
+            _ => {}
+        }
+        stripped.push(c);
+    }
+    stripped
+}
+
+/// 外側から引き継ぐリース時間
+#[derive(Clone, Copy, Default)]
+struct Lifetimes {
+    valid: Option<u32>,
+    min: Option<u32>,
+    max: Option<u32>,
+    renew: Option<u32>,
+    rebind: Option<u32>,
+}
+
+impl Lifetimes {
+    const KEYS: &[&'static str] = &[
+        "valid-lifetime",
+        "min-valid-lifetime",
+        "max-valid-lifetime",
+        "renew-timer",
+        "rebind-timer",
+    ];
+
+    fn read(self, map: &Map<String, Value>) -> Lifetimes {
+        let get = |key: &str| {
+            map.get(key)
+                .and_then(Value::as_u64)
+                .and_then(|n| u32::try_from(n).ok())
+        };
+        Lifetimes {
+            valid: get("valid-lifetime").or(self.valid),
+            min: get("min-valid-lifetime").or(self.min),
+            max: get("max-valid-lifetime").or(self.max),
+            renew: get("renew-timer").or(self.renew),
+            rebind: get("rebind-timer").or(self.rebind),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Converter {
+    report: Report,
+    global_options: Vec<Dhcp4OptionConfig>,
+    subnets: Vec<Dhcp4SubnetConfig>,
+    hosts: Vec<Dhcp4HostConfig>,
+}
+
+/// `'foo'` は文字列, それ以外は 16 進数
+fn identifier(value: &str) -> Result<Vec<u8>> {
+    match value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')) {
+        Some(value) => Ok(value.as_bytes().to_vec()),
+        None => parse_hex(value),
+    }
+}
+
+/// `192.168.0.10 - 192.168.0.20` か `192.168.0.0/28`
+fn pool_range(pool: &str) -> Result<(Ipv4Addr, Ipv4Addr)> {
+    if let Some((start, end)) = pool.split_once('-') {
+        return Ok((start.trim().parse()?, end.trim().parse()?));
+    }
+    let network = pool.trim().parse::<Ipv4Net>()?;
+    Ok((network.network(), network.broadcast()))
+}
+
+impl Converter {
+    /// `handled` 以外のキーを設定できなかったものとして報告する
+    fn unhandled(&mut self, path: &str, map: &Map<String, Value>, handled: &[&str]) {
+        for key in map.keys().filter(|key| !handled.contains(&key.as_str())) {
+            let location = match path {
+                "" => key.clone(),
+                path => format!("{path}.{key}"),
+            };
+            self.report.push(location, "not translated");
+        }
+    }
+
+    fn array<'a>(
+        &mut self,
+        path: &str,
+        value: Option<&'a Value>,
+    ) -> Vec<(String, &'a Map<String, Value>)> {
+        let Some(value) = value else {
+            return vec![];
+        };
+        let Some(values) = value.as_array() else {
+            self.report.push(path, "expected an array");
+            return vec![];
+        };
+        let mut maps = vec![];
+        for (i, value) in values.iter().enumerate() {
+            let path = format!("{path}[{i}]");
+            match value.as_object() {
+                Some(map) => maps.push((path, map)),
+                None => self.report.push(path, "expected an object"),
+            }
+        }
+        maps
+    }
+
+    fn options(&mut self, path: &str, map: &Map<String, Value>) -> Vec<Dhcp4OptionConfig> {
+        let mut options = vec![];
+        for (path, option) in self.array(&format!("{path}.option-data"), map.get("option-data")) {
+            self.unhandled(
+                &path,
+                option,
+                &["name", "code", "data", "csv-format", "space", "always-send"],
+            );
+            match self::option_data(option) {
+                Ok(option) => options.push(option),
+                Err(e) => self.report.push(path, format!("{e:#}")),
+            }
+        }
+        options
+    }
+
+    fn subnet(
+        &mut self,
+        path: &str,
+        map: &Map<String, Value>,
+        options: &[Dhcp4OptionConfig],
+        lifetimes: Lifetimes,
+    ) -> Result<()> {
+        let mut handled = vec!["id", "subnet", "pools", "option-data", "reservations"];
+        handled.extend(Lifetimes::KEYS);
+        self.unhandled(path, map, &handled);
+
+        let Some(subnet) = map.get("subnet").and_then(Value::as_str) else {
+            bail!("subnet is missing");
+        };
+        let network = subnet.parse::<Ipv4Net>()?;
+        let id = map
+            .get("id")
+            .and_then(Value::as_u64)
+            .and_then(|id| u32::try_from(id).ok());
+        let lifetimes = lifetimes.read(map);
+        let options = merge_options(options, &self.options(path, map));
+        self.reservations(path, map);
+
+        let mut ranges = vec![];
+        let mut pools = vec![];
+        for (path, pool) in self.array(&format!("{path}.pools"), map.get("pools")) {
+            self.unhandled(&path, pool, &["pool", "option-data"]);
+            let range = pool
+                .get("pool")
+                .and_then(Value::as_str)
+                .ok_or_else(|| anyhow!("pool is missing"))
+                .and_then(pool_range);
+            let range = match range {
+                Ok(range) => range,
+                Err(e) => {
+                    self.report.push(path, format!("{e:#}"));
+                    continue;
+                }
+            };
+            ranges.push((path.clone(), range));
+            let options = self.options(&path, pool);
+            if !options.is_empty() {
+                pools.push(Dhcp4PoolConfig { range, options });
+            }
+        }
+        let Some(((_, range), rest)) = ranges.split_first() else {
+            bail!("subnet {subnet} has no pools and is not translated");
+        };
+        for (path, (start, end)) in rest {
+            self.report.push(
+                path,
+                format!("pool {start} - {end} is not translated, omoi serves one range per subnet"),
+            );
+        }
+        let mut config = SubnetParameters {
+            subnet: network.network(),
+            netmask: network.netmask(),
+            range: *range,
+            options,
+            inherited: self.global_options.clone(),
+            lease_time: lifetimes.valid,
+            min_lease_time: lifetimes.min,
+            max_lease_time: lifetimes.max,
+            renewal_time: lifetimes.renew,
+            rebinding_time: lifetimes.rebind,
+            server_identifier: None,
+        }
+        .into_config(id);
+        config.pools = pools;
+        self.subnets.push(config);
+        Ok(())
+    }
+
+    fn reservations(&mut self, path: &str, map: &Map<String, Value>) {
+        for (path, reservation) in
+            self.array(&format!("{path}.reservations"), map.get("reservations"))
+        {
+            self.unhandled(
+                &path,
+                reservation,
+                &[
+                    "hw-address",
+                    "client-id",
+                    "circuit-id",
+                    "ip-address",
+                    "hostname",
+                    "option-data",
+                ],
+            );
+            let options = self.options(&path, reservation);
+            let name = format!("reservation-{}", self.hosts.len() + 1);
+            match self::reservation(reservation, name, options) {
+                Ok(host) => self.hosts.push(host),
+                Err(e) => self.report.push(path, format!("{e:#}")),
+            }
+        }
+    }
+}
+
+/// `option-data` の要素
+fn option_data(map: &Map<String, Value>) -> Result<Dhcp4OptionConfig> {
+    if let Some(space) = map.get("space").and_then(Value::as_str) {
+        if space != "dhcp4" {
+            bail!("options in space {space} are not supported");
+        }
+    }
+    let data = map.get("data").and_then(Value::as_str).unwrap_or_default();
+    let csv_format = map
+        .get("csv-format")
+        .and_then(Value::as_bool)
+        .unwrap_or(true);
+    let name = map.get("name").and_then(Value::as_str);
+    let code = map
+        .get("code")
+        .and_then(Value::as_u64)
+        .and_then(|code| u8::try_from(code).ok());
+    let known = name.and_then(find_option).is_some();
+    match (name, code, csv_format) {
+        (Some(name), _, true) if known || code.is_none() => {
+            let values = data
+                .split(',')
+                .map(|value| value.trim().to_string())
+                .collect::<Vec<_>>();
+            option(name, &values)
+        }
+        (_, Some(code), false) if !known => {
+            option_with_type(code, Dhcp4OptionType::Hex, &[data.to_string()])
+        }
+        (Some(name), _, false) => {
+            bail!("binary data for option {name} is not supported, write it with csv-format")
+        }
+        (name, code, _) => bail!(
+            "option {} is not supported",
+            name.map_or_else(|| code.unwrap_or_default().to_string(), str::to_string)
+        ),
+    }
+}
+
+fn reservation(
+    map: &Map<String, Value>,
+    name: String,
+    options: Vec<Dhcp4OptionConfig>,
+) -> Result<Dhcp4HostConfig> {
+    let get = |key: &str| map.get(key).and_then(Value::as_str);
+    let Some(fixed_address) = get("ip-address") else {
+        bail!("reservation without ip-address is not translated");
+    };
+    if ["hw-address", "client-id", "circuit-id"]
+        .iter()
+        .all(|key| get(key).is_none())
+    {
+        bail!("reservation without hw-address, client-id or circuit-id is not translated");
+    }
+    Ok(Dhcp4HostConfig {
+        name: get("hostname").map_or(name, str::to_string),
+        hardware_ethernet: get("hw-address")
+            .map(str::parse::<MacAddress>)
+            .transpose()?,
+        client_id: get("client-id").map(identifier).transpose()?,
+        circuit_id: get("circuit-id").map(identifier).transpose()?,
+        remote_id: None,
+        fixed_address: fixed_address.parse()?,
+        options,
+    })
+}
+
+pub fn convert(text: &str) -> Result<Conversion> {
+    let root: Value = serde_json::from_str(&strip_comments(text))?;
+    let Some(root) = root.as_object() else {
+        bail!("expected an object");
+    };
+    let Some(dhcp4) = root.get("Dhcp4").and_then(Value::as_object) else {
+        bail!("Dhcp4 is missing");
+    };
+    let mut converter = Converter::default();
+    converter.unhandled("", root, &["Dhcp4"]);
+    let mut handled = vec![
+        "option-data",
+        "subnet4",
+        "shared-networks",
+        "reservations",
+        "decline-probation-period",
+    ];
+    handled.extend(Lifetimes::KEYS);
+    converter.unhandled("Dhcp4", dhcp4, &handled);
+
+    converter.global_options = converter.options("Dhcp4", dhcp4);
+    let lifetimes = Lifetimes::default().read(dhcp4);
+    converter.reservations("Dhcp4", dhcp4);
+    for (path, subnet) in converter.array("Dhcp4.subnet4", dhcp4.get("subnet4")) {
+        if let Err(e) = converter.subnet(&path, subnet, &[], lifetimes) {
+            converter.report.push(path, format!("{e:#}"));
+        }
+    }
+    for (path, network) in converter.array("Dhcp4.shared-networks", dhcp4.get("shared-networks")) {
+        let mut handled = vec!["name", "subnet4", "option-data"];
+        handled.extend(Lifetimes::KEYS);
+        converter.unhandled(&path, network, &handled);
+        let options = converter.options(&path, network);
+        let lifetimes = lifetimes.read(network);
+        for (path, subnet) in converter.array(&format!("{path}.subnet4"), network.get("subnet4")) {
+            if let Err(e) = converter.subnet(&path, subnet, &options, lifetimes) {
+                converter.report.push(path, format!("{e:#}"));
+            }
+        }
+    }
+
+    let (options, domain_name) = global_options(converter.global_options);
+    let dhcp4 = Dhcp4Config {
+        subnets: converter.subnets,
+        hosts: converter.hosts,
+        decline_probation_period: dhcp4
+            .get("decline-probation-period")
+            .and_then(Value::as_u64)
+            .and_then(|n| u32::try_from(n).ok())
+            .unwrap_or(DEFAULT_DECLINE_PROBATION_PERIOD),
+        offer_hold_time: DEFAULT_OFFER_HOLD_TIME,
+        persist_offers: false,
+        reclaim: Default::default(),
+        transport: Default::default(),
+        server_identifier: None,
+        domain_name,
+        options,
+        classes: vec![],
+    };
+    Ok(Conversion {
+        config: omoi_config(dhcp4),
+        untranslated: converter.report.0,
+    })
+}
+
+#[test]
+fn convert_test() {
+    let text = r#"
+// comment
+{
+  "Dhcp4": {
+    "interfaces-config": { "interfaces": [ "eth0" ] },
+    "valid-lifetime": 4000,
+    "renew-timer": 1000,
+    /* "rebind-timer": 2000, */
+    "option-data": [
+      { "name": "domain-name-servers", "data": "192.0.2.1, 192.0.2.2" },
+      { "name": "domain-name", "data": "example.org" },
+      { "code": 224, "data": "01:02:ff", "csv-format": false }
+    ],
+    "subnet4": [
+      {
+        "id": 1,
+        "subnet": "192.0.2.0/24",
+        "pools": [ { "pool": "192.0.2.10 - 192.0.2.100" }, { "pool": "192.0.2.128/28" } ],
+        "option-data": [ { "name": "routers", "data": "192.0.2.1" } ],
+        "reservations": [
+          { "hw-address": "1a:1b:1c:1d:1e:1f", "ip-address": "192.0.2.201", "hostname": "special" },
+          { "duid": "01:02", "ip-address": "192.0.2.202" }
+        ]
+      }
+    ]
+  }
+}
+"#;
+    let conversion = convert(text).unwrap();
+    let config = conversion.config.dhcp4;
+    assert_eq!(config.domain_name.as_deref(), Some("example.org"));
+    assert_eq!(config.options.len(), 1);
+    let subnet = &config.subnets[0];
+    assert_eq!(subnet.id, Some(1));
+    assert_eq!(subnet.netmask, Ipv4Addr::new(255, 255, 255, 0));
+    assert_eq!(
+        subnet.range,
+        (Ipv4Addr::new(192, 0, 2, 10), Ipv4Addr::new(192, 0, 2, 100))
+    );
+    assert_eq!(subnet.routers, vec![Ipv4Addr::new(192, 0, 2, 1)]);
+    assert_eq!(subnet.domain_name_servers.len(), 2);
+    assert_eq!(subnet.address_lease_time, 4000);
+    assert_eq!(subnet.renewal_time, Some(1000));
+    assert_eq!(subnet.rebinding_time, None);
+    assert_eq!(config.hosts.len(), 1);
+    assert_eq!(config.hosts[0].name, "special");
+    assert_eq!(
+        conversion
+            .untranslated
+            .iter()
+            .map(|untranslated| untranslated.location.as_str())
+            .collect::<Vec<_>>(),
+        vec![
+            "Dhcp4.interfaces-config",
+            "Dhcp4.subnet4[0].reservations[1].duid",
+            "Dhcp4.subnet4[0].reservations[1]",
+            "Dhcp4.subnet4[0].pools[1]",
+        ]
+    );
+}
@@ -0,0 +1,273 @@
+//! 他の DHCP サーバーの設定を omoi.toml にする
+
+mod isc;
+mod kea;
+
+use std::{fmt, net::Ipv4Addr, path::PathBuf};
+
+use anyhow::{bail, ensure, Result};
+use clap::ValueEnum;
+
+use super::{
+    find_option, CommonConfig, Dhcp4Config, Dhcp4OptionConfig, Dhcp4OptionType, Dhcp4OptionValue,
+    Dhcp4SubnetConfig, HttpConfig, LeaseStoreKind, OmoiConfig,
+};
+
+/// ISC dhcpd の既定のリース時間
+const DEFAULT_LEASE_TIME: u32 = 43200;
+
+/// 読み込める設定の形式
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum ConfigFormat {
+    /// ISC dhcpd の dhcpd.conf
+    Isc,
+    /// Kea の kea-dhcp4.conf
+    Kea,
+}
+
+/// omoi の設定にできなかったもの
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Untranslated {
+    /// dhcpd.conf なら `line 12`, Kea なら `Dhcp4.subnet4[0].relay` のような場所
+    pub location: String,
+    pub message: String,
+}
+
+impl fmt::Display for Untranslated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Conversion {
+    pub config: OmoiConfig,
+    pub untranslated: Vec<Untranslated>,
+}
+
+impl Conversion {
+    /// 設定できなかったものをコメントとして先頭に書いた omoi.toml
+    pub fn to_toml(&self) -> Result<String> {
+        let mut text = String::from("# converted by omoi convert-config\n");
+        if !self.untranslated.is_empty() {
+            text.push_str("#\n# not translated:\n");
+            for untranslated in &self.untranslated {
+                text.push_str(&format!("#   {untranslated}\n"));
+            }
+        }
+        text.push('\n');
+        // 値の後に表を書くように一度 toml::Value にする
+        text.push_str(&toml::to_string(&toml::Value::try_from(&self.config)?)?);
+        Ok(text)
+    }
+}
+
+impl ConfigFormat {
+    pub fn convert(self, text: &str) -> Result<Conversion> {
+        match self {
+            ConfigFormat::Isc => isc::convert(text),
+            ConfigFormat::Kea => kea::convert(text),
+        }
+    }
+}
+
+/// 変換しながら設定できなかったものを集める
+#[derive(Default)]
+struct Report(Vec<Untranslated>);
+
+impl Report {
+    fn push(&mut self, location: impl fmt::Display, message: impl fmt::Display) {
+        self.0.push(Untranslated {
+            location: location.to_string(),
+            message: message.to_string(),
+        });
+    }
+}
+
+fn omoi_config(dhcp4: Dhcp4Config) -> OmoiConfig {
+    OmoiConfig {
+        common: CommonConfig {
+            database_dir: PathBuf::from("omoi-db"),
+            lease_store: LeaseStoreKind::default(),
+            sqlite_path: None,
+        },
+        dhcp4,
+        debug: None,
+        http: HttpConfig {
+            addr: ([0, 0, 0, 0], 11003).into(),
+            admin_token: None,
+        },
+    }
+}
+
+/// 他のサーバーでの名前が omoi と違うオプション
+const OPTION_ALIASES: &[(&str, &str)] = &[("boot-file-name", "bootfile-name")];
+
+/// 名前の分かるオプションを、カンマで区切られた値から作る
+fn option(name: &str, values: &[String]) -> Result<Dhcp4OptionConfig> {
+    let name = OPTION_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == name)
+        .map_or(name, |(_, name)| name);
+    let Some((code, kind)) = find_option(name) else {
+        bail!("option {name} is not supported");
+    };
+    option_with_type(code, kind, values)
+}
+
+fn option_with_type(
+    code: u8,
+    kind: Dhcp4OptionType,
+    values: &[String],
+) -> Result<Dhcp4OptionConfig> {
+    use toml::Value;
+
+    let single = || {
+        ensure!(values.len() == 1, "option {code} takes one value");
+        Ok(values[0].clone())
+    };
+    let value = match kind {
+        Dhcp4OptionType::IpList => {
+            Value::Array(values.iter().cloned().map(Value::String).collect())
+        }
+        Dhcp4OptionType::U8 | Dhcp4OptionType::U16 | Dhcp4OptionType::U32 => {
+            Value::Integer(single()?.parse()?)
+        }
+        Dhcp4OptionType::String => Value::String(values.join(",")),
+        Dhcp4OptionType::Ip | Dhcp4OptionType::Hex => Value::String(single()?),
+    };
+    Dhcp4OptionConfig::new(code, kind, value)
+}
+
+/// サブネットの項目に書くオプション
+const ROUTERS: u8 = 3;
+const DOMAIN_NAME_SERVERS: u8 = 6;
+const DOMAIN_NAME: u8 = 15;
+const BROADCAST_ADDRESS: u8 = 28;
+/// `netmask` から決まるので書かない
+const SUBNET_MASK: u8 = 1;
+
+/// サブネットの項目に書くオプションを取り除く
+fn take_option(options: &mut Vec<Dhcp4OptionConfig>, code: u8) -> Option<Dhcp4OptionValue> {
+    let mut value = None;
+    options.retain(|option| {
+        if option.code == code {
+            value = Some(option.value.clone());
+        }
+        option.code != code
+    });
+    value
+}
+
+/// 後に書かれたオプションが同じコードを上書きする
+fn merge_options(
+    options: &[Dhcp4OptionConfig],
+    overrides: &[Dhcp4OptionConfig],
+) -> Vec<Dhcp4OptionConfig> {
+    let mut merged = options
+        .iter()
+        .filter(|option| overrides.iter().all(|o| o.code != option.code))
+        .cloned()
+        .collect::<Vec<_>>();
+    merged.extend(overrides.iter().cloned());
+    merged
+}
+
+/// サブネットに設定するもの
+///
+/// `inherited` は全体の設定で、サブネットの `options` に書いたものは全体のオプションより優先される
+struct SubnetParameters {
+    subnet: Ipv4Addr,
+    netmask: Ipv4Addr,
+    range: (Ipv4Addr, Ipv4Addr),
+    options: Vec<Dhcp4OptionConfig>,
+    inherited: Vec<Dhcp4OptionConfig>,
+    lease_time: Option<u32>,
+    min_lease_time: Option<u32>,
+    max_lease_time: Option<u32>,
+    renewal_time: Option<u32>,
+    rebinding_time: Option<u32>,
+    server_identifier: Option<Ipv4Addr>,
+}
+
+impl SubnetParameters {
+    /// routers や domain-name-servers はオプションからサブネットの項目に移す
+    fn into_config(self, id: Option<u32>) -> Dhcp4SubnetConfig {
+        let mut options = self.options;
+        let mut inherited = self.inherited;
+        let mut take = |code| {
+            let value = take_option(&mut options, code);
+            value.or_else(|| take_option(&mut inherited, code))
+        };
+        let ip_list = |value: Option<Dhcp4OptionValue>| match value {
+            Some(Dhcp4OptionValue::IpList(ips)) => ips,
+            _ => vec![],
+        };
+        let routers = ip_list(take(ROUTERS));
+        let domain_name_servers = ip_list(take(DOMAIN_NAME_SERVERS));
+        let broadcast_address = match take(BROADCAST_ADDRESS) {
+            Some(Dhcp4OptionValue::Ip(ip)) => ip,
+            _ => Ipv4Addr::from(u32::from(self.subnet) | !u32::from(self.netmask)),
+        };
+        take(SUBNET_MASK);
+        let domain_name = match take_option(&mut options, DOMAIN_NAME) {
+            Some(Dhcp4OptionValue::String(domain_name)) => Some(domain_name),
+            _ => None,
+        };
+        Dhcp4SubnetConfig {
+            id,
+            subnet: self.subnet,
+            netmask: self.netmask,
+            range: self.range,
+            domain_name_servers,
+            routers,
+            broadcast_address,
+            address_lease_time: self.lease_time.unwrap_or(DEFAULT_LEASE_TIME),
+            server_identifier: self.server_identifier,
+            renewal_time: self.renewal_time,
+            rebinding_time: self.rebinding_time,
+            min_lease_time: self.min_lease_time,
+            max_lease_time: self.max_lease_time,
+            domain_name,
+            circuit_id: None,
+            remote_id: None,
+            options,
+            pools: vec![],
+        }
+    }
+}
+
+/// サブネットの項目にしたオプションを全体のオプションから除く
+fn global_options(mut options: Vec<Dhcp4OptionConfig>) -> (Vec<Dhcp4OptionConfig>, Option<String>) {
+    for code in [ROUTERS, DOMAIN_NAME_SERVERS, BROADCAST_ADDRESS, SUBNET_MASK] {
+        take_option(&mut options, code);
+    }
+    let domain_name = match take_option(&mut options, DOMAIN_NAME) {
+        Some(Dhcp4OptionValue::String(domain_name)) => Some(domain_name),
+        _ => None,
+    };
+    (options, domain_name)
+}
+
+#[test]
+fn option_test() {
+    let values = |values: &[&str]| values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        option("ntp-servers", &values(&["192.168.0.1", "192.168.0.2"])).unwrap(),
+        Dhcp4OptionConfig {
+            code: 42,
+            value: Dhcp4OptionValue::IpList(vec![
+                Ipv4Addr::new(192, 168, 0, 1),
+                Ipv4Addr::new(192, 168, 0, 2)
+            ]),
+        }
+    );
+    assert_eq!(
+        option("boot-file-name", &values(&["pxelinux.0"]))
+            .unwrap()
+            .code,
+        67
+    );
+    assert!(option("interface-mtu", &values(&["1400", "1500"])).is_err());
+    assert!(option("no-such-option", &values(&["1"])).is_err());
+}
@@ -0,0 +1,355 @@
+use std::{fmt::Write, net::Ipv4Addr, str::FromStr};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use dhcproto::{v4, Decodable, Decoder};
+use serde::{Deserialize, Serialize};
+
+/// オプションの値の型
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Dhcp4OptionType {
+    Ip,
+    IpList,
+    U8,
+    U16,
+    U32,
+    String,
+    Hex,
+}
+
+/// 名前で指定できるオプション (RFC 2132)
+const OPTION_NAMES: &[(&str, u8, Dhcp4OptionType)] = &[
+    ("subnet-mask", 1, Dhcp4OptionType::Ip),
+    ("time-offset", 2, Dhcp4OptionType::U32),
+    ("routers", 3, Dhcp4OptionType::IpList),
+    ("time-servers", 4, Dhcp4OptionType::IpList),
+    ("ien116-name-servers", 5, Dhcp4OptionType::IpList),
+    ("domain-name-servers", 6, Dhcp4OptionType::IpList),
+    ("log-servers", 7, Dhcp4OptionType::IpList),
+    ("lpr-servers", 9, Dhcp4OptionType::IpList),
+    ("host-name", 12, Dhcp4OptionType::String),
+    ("merit-dump", 14, Dhcp4OptionType::String),
+    ("domain-name", 15, Dhcp4OptionType::String),
+    ("swap-server", 16, Dhcp4OptionType::Ip),
+    ("root-path", 17, Dhcp4OptionType::String),
+    ("extensions-path", 18, Dhcp4OptionType::String),
+    ("default-ip-ttl", 23, Dhcp4OptionType::U8),
+    ("interface-mtu", 26, Dhcp4OptionType::U16),
+    ("broadcast-address", 28, Dhcp4OptionType::Ip),
+    ("arp-cache-timeout", 35, Dhcp4OptionType::U32),
+    ("default-tcp-ttl", 37, Dhcp4OptionType::U8),
+    ("tcp-keepalive-interval", 38, Dhcp4OptionType::U32),
+    ("nis-domain", 40, Dhcp4OptionType::String),
+    ("nis-servers", 41, Dhcp4OptionType::IpList),
+    ("ntp-servers", 42, Dhcp4OptionType::IpList),
+    ("vendor-encapsulated-options", 43, Dhcp4OptionType::Hex),
+    ("netbios-name-servers", 44, Dhcp4OptionType::IpList),
+    ("netbios-dd-server", 45, Dhcp4OptionType::IpList),
+    ("netbios-node-type", 46, Dhcp4OptionType::U8),
+    ("netbios-scope", 47, Dhcp4OptionType::String),
+    ("font-servers", 48, Dhcp4OptionType::IpList),
+    ("x-display-manager", 49, Dhcp4OptionType::IpList),
+    ("tftp-server-name", 66, Dhcp4OptionType::String),
+    ("bootfile-name", 67, Dhcp4OptionType::String),
+    ("smtp-server", 69, Dhcp4OptionType::IpList),
+    ("pop-server", 70, Dhcp4OptionType::IpList),
+    ("www-server", 72, Dhcp4OptionType::IpList),
+];
+
+/// 名前で指定できるオプションのコードと型
+pub fn find_option(name: &str) -> Option<(u8, Dhcp4OptionType)> {
+    OPTION_NAMES
+        .iter()
+        .find(|(n, _, _)| *n == name)
+        .map(|(_, code, kind)| (*code, *kind))
+}
+
+/// 設定に書かれたままのオプション
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct RawOptionConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<u8>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    kind: Option<Dhcp4OptionType>,
+    value: toml::Value,
+}
+
+/// 型付けされたオプションの値
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Dhcp4OptionValue {
+    Ip(Ipv4Addr),
+    IpList(Vec<Ipv4Addr>),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    String(String),
+    Hex(Vec<u8>),
+}
+
+impl Dhcp4OptionValue {
+    fn parse(kind: Dhcp4OptionType, value: toml::Value) -> Result<Dhcp4OptionValue> {
+        use toml::Value;
+
+        let ip = |value: Value| -> Result<Ipv4Addr> {
+            match value {
+                Value::String(s) => Ok(Ipv4Addr::from_str(&s)?),
+                value => bail!("expected an address, found {value}"),
+            }
+        };
+        let integer = |value: Value| match value {
+            Value::Integer(n) => Ok(n),
+            value => Err(anyhow!("expected an integer, found {value}")),
+        };
+        let value = match (kind, value) {
+            (Dhcp4OptionType::Ip, value) => Dhcp4OptionValue::Ip(ip(value)?),
+            (Dhcp4OptionType::IpList, Value::Array(values)) => {
+                Dhcp4OptionValue::IpList(values.into_iter().map(ip).collect::<Result<Vec<_>>>()?)
+            }
+            (Dhcp4OptionType::IpList, value) => Dhcp4OptionValue::IpList(vec![ip(value)?]),
+            (Dhcp4OptionType::U8, value) => Dhcp4OptionValue::U8(integer(value)?.try_into()?),
+            (Dhcp4OptionType::U16, value) => Dhcp4OptionValue::U16(integer(value)?.try_into()?),
+            (Dhcp4OptionType::U32, value) => Dhcp4OptionValue::U32(integer(value)?.try_into()?),
+            (Dhcp4OptionType::String, Value::String(s)) => Dhcp4OptionValue::String(s),
+            (Dhcp4OptionType::Hex, Value::String(s)) => Dhcp4OptionValue::Hex(parse_hex(&s)?),
+            (kind, value) => bail!("expected {kind:?}, found {value}"),
+        };
+        Ok(value)
+    }
+
+    fn kind(&self) -> Dhcp4OptionType {
+        match self {
+            Dhcp4OptionValue::Ip(_) => Dhcp4OptionType::Ip,
+            Dhcp4OptionValue::IpList(_) => Dhcp4OptionType::IpList,
+            Dhcp4OptionValue::U8(_) => Dhcp4OptionType::U8,
+            Dhcp4OptionValue::U16(_) => Dhcp4OptionType::U16,
+            Dhcp4OptionValue::U32(_) => Dhcp4OptionType::U32,
+            Dhcp4OptionValue::String(_) => Dhcp4OptionType::String,
+            Dhcp4OptionValue::Hex(_) => Dhcp4OptionType::Hex,
+        }
+    }
+
+    fn to_value(&self) -> toml::Value {
+        use toml::Value;
+
+        match self {
+            Dhcp4OptionValue::Ip(ip) => Value::String(ip.to_string()),
+            Dhcp4OptionValue::IpList(ips) => {
+                Value::Array(ips.iter().map(|ip| Value::String(ip.to_string())).collect())
+            }
+            Dhcp4OptionValue::U8(n) => Value::Integer((*n).into()),
+            Dhcp4OptionValue::U16(n) => Value::Integer((*n).into()),
+            Dhcp4OptionValue::U32(n) => Value::Integer((*n).into()),
+            Dhcp4OptionValue::String(s) => Value::String(s.clone()),
+            Dhcp4OptionValue::Hex(bytes) => Value::String(format_hex(bytes)),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Dhcp4OptionValue::Ip(ip) => ip.octets().to_vec(),
+            Dhcp4OptionValue::IpList(ips) => ips.iter().flat_map(|ip| ip.octets()).collect(),
+            Dhcp4OptionValue::U8(n) => vec![*n],
+            Dhcp4OptionValue::U16(n) => n.to_be_bytes().to_vec(),
+            Dhcp4OptionValue::U32(n) => n.to_be_bytes().to_vec(),
+            Dhcp4OptionValue::String(s) => s.as_bytes().to_vec(),
+            Dhcp4OptionValue::Hex(bytes) => bytes.clone(),
+        }
+    }
+}
+
+/// `01:02:ff` や `0102ff` をバイト列にする
+pub fn parse_hex(s: &str) -> Result<Vec<u8>> {
+    let digits = s
+        .chars()
+        .filter(|c| *c != ':' && !c.is_whitespace())
+        .map(|c| {
+            c.to_digit(16)
+                .ok_or_else(|| anyhow!("invalid hex digit {c:?}: {s}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    ensure!(digits.len() % 2 == 0, "odd number of hex digits: {s}");
+    Ok(digits
+        .chunks(2)
+        .map(|pair| (pair[0] * 16 + pair[1]) as u8)
+        .collect())
+}
+
+/// バイト列を `01:02:ff` のように書く
+pub fn format_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        if !hex.is_empty() {
+            hex.push(':');
+        }
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+/// 応答に載せるオプション
+///
+/// `name` か `code` で指定する。名前の分かるオプションは `type` を省略できる。
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(try_from = "RawOptionConfig", into = "RawOptionConfig")]
+pub struct Dhcp4OptionConfig {
+    pub code: u8,
+    pub value: Dhcp4OptionValue,
+}
+
+impl TryFrom<RawOptionConfig> for Dhcp4OptionConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawOptionConfig) -> Result<Self> {
+        let (code, known_kind) = match (&raw.name, raw.code) {
+            (Some(name), code) => {
+                let Some((_, known, kind)) = OPTION_NAMES.iter().find(|(n, _, _)| n == name) else {
+                    bail!("unknown option name: {name}");
+                };
+                if code.is_some_and(|code| code != *known) {
+                    bail!("option {name} is code {known}");
+                }
+                (*known, Some(*kind))
+            }
+            (None, Some(code)) => (
+                code,
+                OPTION_NAMES
+                    .iter()
+                    .find(|(_, c, _)| *c == code)
+                    .map(|(_, _, kind)| *kind),
+            ),
+            (None, None) => bail!("option needs name or code"),
+        };
+        let kind = match (known_kind, raw.kind) {
+            (Some(kind), Some(given)) if kind != given => {
+                bail!("option {code} is {kind:?}, not {given:?}")
+            }
+            (Some(kind), _) | (None, Some(kind)) => kind,
+            (None, None) => bail!("option {code} needs type"),
+        };
+        Dhcp4OptionConfig::new(code, kind, raw.value)
+    }
+}
+
+/// 名前の分かるオプションは名前で書く
+impl From<Dhcp4OptionConfig> for RawOptionConfig {
+    fn from(option: Dhcp4OptionConfig) -> Self {
+        let name = OPTION_NAMES
+            .iter()
+            .find(|(_, code, _)| *code == option.code)
+            .map(|(name, _, _)| name.to_string());
+        RawOptionConfig {
+            kind: name.is_none().then(|| option.value.kind()),
+            code: name.is_none().then_some(option.code),
+            name,
+            value: option.value.to_value(),
+        }
+    }
+}
+
+impl Dhcp4OptionConfig {
+    /// `value` を `kind` の値として読み、応答に載せられるか確かめる
+    pub fn new(code: u8, kind: Dhcp4OptionType, value: toml::Value) -> Result<Self> {
+        let option = Dhcp4OptionConfig {
+            code,
+            value: Dhcp4OptionValue::parse(kind, value)
+                .with_context(|| format!("option {code}"))?,
+        };
+        option.check()?;
+        Ok(option)
+    }
+
+    /// `domain-name` の設定を option 15 にする
+    pub fn domain_name(domain_name: String) -> Dhcp4OptionConfig {
+        Dhcp4OptionConfig {
+            code: u8::from(v4::OptionCode::DomainName),
+            value: Dhcp4OptionValue::String(domain_name),
+        }
+    }
+
+    /// 応答に載せられるか確かめる
+    pub fn check(&self) -> Result<()> {
+        let code = self.code;
+        ensure!(
+            !matches!(code, 0 | 255),
+            "option code {code} can not be configured"
+        );
+        ensure!(
+            self.value.to_bytes().len() <= usize::from(u8::MAX),
+            "option {code} is too long"
+        );
+        self.to_dhcp_option()?;
+        Ok(())
+    }
+
+    /// dhcproto のオプションにする
+    ///
+    /// 知っているコードなら型付きのオプションに、そうでなければ `Unknown` になる
+    pub fn to_dhcp_option(&self) -> Result<v4::DhcpOption> {
+        let data = self.value.to_bytes();
+        let mut buffer = vec![self.code, data.len() as u8];
+        buffer.extend(data);
+        buffer.push(u8::from(v4::OptionCode::End));
+        let option = v4::DhcpOption::decode(&mut Decoder::new(&buffer))
+            .with_context(|| format!("invalid value for option {}", self.code))?;
+        Ok(option)
+    }
+}
+
+#[test]
+fn option_config_test() {
+    #[derive(Deserialize)]
+    struct Options {
+        option: Vec<Dhcp4OptionConfig>,
+    }
+    let parse = |text: &str| toml::from_str::<Options>(text).map(|options| options.option);
+
+    let options = parse(
+        r#"
+[[option]]
+name = "domain-name"
+value = "example.local"
+
+[[option]]
+name = "ntp-servers"
+value = ["192.168.0.1", "192.168.0.2"]
+
+[[option]]
+code = 26
+value = 1400
+
+[[option]]
+code = 224
+type = "hex"
+value = "01:02:ff"
+"#,
+    )
+    .unwrap();
+    let options = options
+        .iter()
+        .map(|option| option.to_dhcp_option().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        options,
+        vec![
+            v4::DhcpOption::DomainName("example.local".to_string()),
+            v4::DhcpOption::NTPServers(vec![
+                Ipv4Addr::new(192, 168, 0, 1),
+                Ipv4Addr::new(192, 168, 0, 2)
+            ]),
+            v4::DhcpOption::InterfaceMtu(1400),
+            v4::DhcpOption::Unknown(v4::UnknownOption::new(224.into(), vec![1, 2, 0xff])),
+        ]
+    );
+
+    // 型が分からない、合わない、範囲外
+    assert!(parse("[[option]]\ncode = 224\nvalue = 1").is_err());
+    assert!(parse("[[option]]\nname = \"routers\"\ntype = \"string\"\nvalue = \"a\"").is_err());
+    assert!(parse("[[option]]\nname = \"default-ip-ttl\"\nvalue = 256").is_err());
+    assert!(parse("[[option]]\nname = \"no-such-option\"\nvalue = 1").is_err());
+
+    assert_eq!(parse_hex("01:02:ff").unwrap(), vec![1, 2, 0xff]);
+    assert!(parse_hex("0").is_err());
+    assert!(parse_hex("aé").is_err());
+}
@@ -0,0 +1,327 @@
+//! 読み込んだ設定の中身が矛盾していないか確かめる
+
+use std::{collections::HashMap, fmt, hash::Hash, net::Ipv4Addr};
+
+use super::{Dhcp4Config, Dhcp4OptionConfig, Dhcp4SubnetConfig, LeaseStoreKind, OmoiConfig};
+
+/// 設定の誤り
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ConfigError {
+    /// `dhcp4.subnet[0].range` のような設定の中の場所
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// 見つかったすべての誤り
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} errors in config", self.0.len())?;
+        for error in &self.0 {
+            write!(f, "\n  {error}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+impl ConfigErrors {
+    fn push(&mut self, path: impl fmt::Display, message: impl fmt::Display) {
+        self.0.push(ConfigError {
+            path: path.to_string(),
+            message: message.to_string(),
+        });
+    }
+
+    /// `key` が既に `paths` にあれば重複として報告する
+    fn unique<K: Eq + Hash>(
+        &mut self,
+        paths: &mut HashMap<K, String>,
+        key: K,
+        path: String,
+        what: impl fmt::Display,
+    ) {
+        match paths.get(&key) {
+            Some(first) => self.push(path, format!("{what} is also used by {first}")),
+            None => {
+                paths.insert(key, path);
+            }
+        }
+    }
+}
+
+/// 上位のビットから 1 が続くか
+fn is_contiguous(netmask: Ipv4Addr) -> bool {
+    let mask = u32::from(netmask);
+    mask.leading_ones() + mask.trailing_zeros() == 32
+}
+
+fn range(
+    errors: &mut ConfigErrors,
+    path: &str,
+    (start, end): (Ipv4Addr, Ipv4Addr),
+    subnet: &Dhcp4SubnetConfig,
+) {
+    if start > end {
+        errors.push(path, format!("{start} is after {end}"));
+    }
+    for address in [start, end] {
+        if !subnet.contains(&address) {
+            errors.push(
+                path,
+                format!("{address} is outside {}/{}", subnet.subnet, subnet.netmask),
+            );
+        }
+    }
+}
+
+/// 応答に載せられないオプションを報告する
+fn options(errors: &mut ConfigErrors, path: &str, options: &[Dhcp4OptionConfig]) {
+    for (i, option) in options.iter().enumerate() {
+        if let Err(e) = option.check() {
+            errors.push(format!("{path}.option[{i}]"), format!("{e:#}"));
+        }
+    }
+}
+
+fn domain_name(errors: &mut ConfigErrors, path: &str, domain_name: &Option<String>) {
+    let Some(domain_name) = domain_name else {
+        return;
+    };
+    if let Err(e) = Dhcp4OptionConfig::domain_name(domain_name.clone()).check() {
+        errors.push(format!("{path}.domain-name"), format!("{e:#}"));
+    }
+}
+
+fn subnet(errors: &mut ConfigErrors, path: &str, subnet: &Dhcp4SubnetConfig) {
+    if !is_contiguous(subnet.netmask) {
+        errors.push(
+            format!("{path}.netmask"),
+            format!("{} is not a valid netmask", subnet.netmask),
+        );
+    }
+    range(errors, &format!("{path}.range"), subnet.range, subnet);
+    let broadcast_address = Ipv4Addr::from(u32::from(subnet.subnet) | !u32::from(subnet.netmask));
+    if subnet.broadcast_address != broadcast_address {
+        errors.push(
+            format!("{path}.broadcast-address"),
+            format!(
+                "{} does not match the netmask, expected {broadcast_address}",
+                subnet.broadcast_address
+            ),
+        );
+    }
+    if subnet.address_lease_time == 0 {
+        errors.push(format!("{path}.address-lease-time"), "must not be 0");
+    }
+    if let (Some(min), Some(max)) = (subnet.min_lease_time, subnet.max_lease_time) {
+        if min > max {
+            errors.push(
+                format!("{path}.min-lease-time"),
+                format!("{min} is longer than max-lease-time {max}"),
+            );
+        }
+    }
+    if let (Some(renewal), Some(rebinding)) = (subnet.renewal_time, subnet.rebinding_time) {
+        if renewal >= rebinding {
+            errors.push(
+                format!("{path}.renewal-time"),
+                format!("{renewal} is not shorter than rebinding-time {rebinding}"),
+            );
+        }
+    }
+    for (i, pool) in subnet.pools.iter().enumerate() {
+        range(
+            errors,
+            &format!("{path}.pool[{i}].range"),
+            pool.range,
+            subnet,
+        );
+        options(errors, &format!("{path}.pool[{i}]"), &pool.options);
+    }
+    domain_name(errors, path, &subnet.domain_name);
+    options(errors, path, &subnet.options);
+}
+
+fn dhcp4(errors: &mut ConfigErrors, config: &Dhcp4Config) {
+    if config.subnets.is_empty() {
+        errors.push("dhcp4.subnet", "at least one subnet is required");
+    }
+    domain_name(errors, "dhcp4", &config.domain_name);
+    options(errors, "dhcp4", &config.options);
+    for (i, class) in config.classes.iter().enumerate() {
+        options(errors, &format!("dhcp4.class[{i}]"), &class.options);
+    }
+    let mut ids = HashMap::new();
+    for (i, config) in config.subnets.iter().enumerate() {
+        let path = format!("dhcp4.subnet[{i}]");
+        subnet(errors, &path, config);
+        errors.unique(
+            &mut ids,
+            config.id(),
+            format!("{path}.id"),
+            format!("id {}", config.id()),
+        );
+    }
+    // 重なったサブネットは先に書かれたものしか使われない
+    for (i, later) in config.subnets.iter().enumerate() {
+        if let Some(j) = config.subnets[..i]
+            .iter()
+            .position(|earlier| earlier.contains(&later.subnet) || later.contains(&earlier.subnet))
+        {
+            errors.push(
+                format!("dhcp4.subnet[{i}].subnet"),
+                format!("overlaps dhcp4.subnet[{j}]"),
+            );
+        }
+    }
+
+    let mut fixed_addresses = HashMap::new();
+    let mut hardware_addresses = HashMap::new();
+    let mut client_ids = HashMap::new();
+    for (i, host) in config.hosts.iter().enumerate() {
+        let path = format!("dhcp4.host[{i}]");
+        if host.hardware_ethernet.is_none()
+            && host.client_id.is_none()
+            && host.circuit_id.is_none()
+            && host.remote_id.is_none()
+        {
+            errors.push(
+                &path,
+                format!(
+                    "host {} needs hardware-ethernet, client-id, circuit-id or remote-id",
+                    host.name
+                ),
+            );
+        }
+        let address = host.fixed_address;
+        let fixed_address_path = format!("{path}.fixed-address");
+        match config.find_subnet(&address) {
+            None => errors.push(
+                &fixed_address_path,
+                format!("{address} is not on any subnet"),
+            ),
+            Some(subnet) if (subnet.range.0..=subnet.range.1).contains(&address) => errors.push(
+                &fixed_address_path,
+                format!(
+                    "{address} is inside the dynamic range {} - {}",
+                    subnet.range.0, subnet.range.1
+                ),
+            ),
+            Some(_) => {}
+        }
+        errors.unique(&mut fixed_addresses, address, fixed_address_path, address);
+        options(errors, &path, &host.options);
+        if let Some(mac) = host.hardware_ethernet {
+            errors.unique(
+                &mut hardware_addresses,
+                mac.bytes(),
+                format!("{path}.hardware-ethernet"),
+                mac,
+            );
+        }
+        if let Some(client_id) = &host.client_id {
+            errors.unique(
+                &mut client_ids,
+                client_id.clone(),
+                format!("{path}.client-id"),
+                "client-id",
+            );
+        }
+    }
+
+    if config.reclaim.interval == 0 {
+        errors.push("dhcp4.reclaim.interval", "must not be 0");
+    }
+    if config.reclaim.batch_size == 0 {
+        errors.push("dhcp4.reclaim.batch-size", "must not be 0");
+    }
+}
+
+impl OmoiConfig {
+    /// 読み込めた設定が矛盾していないか確かめ、見つかった誤りをすべて返す
+    pub fn validate(&self) -> Result<(), ConfigErrors> {
+        let mut errors = ConfigErrors::default();
+        if self.common.lease_store == LeaseStoreKind::Sqlite && !cfg!(feature = "sqlite") {
+            errors.push(
+                "common.lease-store",
+                "omoi was built without the sqlite feature",
+            );
+        }
+        if let Some(debug) = &self.debug {
+            if debug
+                .hw_prefix
+                .as_ref()
+                .is_some_and(|prefix| prefix.len() > 6)
+            {
+                errors.push("debug.hw-prefix", "must not be longer than 6 bytes");
+            }
+        }
+        dhcp4(&mut errors, &self.dhcp4);
+        if errors.0.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[test]
+fn validate_test() {
+    use super::Dhcp4PoolConfig;
+
+    let config = crate::dhcp::v4::test_config();
+    assert_eq!(config.validate(), Ok(()));
+
+    let mut broken = config.clone();
+    let subnet = &mut broken.dhcp4.subnets[0];
+    subnet.broadcast_address = Ipv4Addr::new(192, 168, 0, 254);
+    subnet.pools.push(Dhcp4PoolConfig {
+        range: (Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)),
+        options: vec![],
+    });
+    subnet.domain_name = Some("a".repeat(256));
+    let mut overlapped = config.dhcp4.subnets[0].clone();
+    overlapped.range = (
+        Ipv4Addr::new(192, 168, 0, 250),
+        Ipv4Addr::new(192, 168, 0, 240),
+    );
+    broken.dhcp4.subnets.push(overlapped);
+    let mut duplicated = config.dhcp4.hosts[0].clone();
+    duplicated.name = "duplicated".to_string();
+    duplicated.fixed_address = Ipv4Addr::new(192, 168, 0, 12);
+    broken.dhcp4.hosts.push(duplicated);
+    broken.dhcp4.hosts[2].fixed_address = Ipv4Addr::new(192, 168, 0, 200);
+    broken.dhcp4.reclaim.interval = 0;
+
+    let errors = broken.validate().unwrap_err();
+    assert_eq!(
+        errors
+            .0
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>(),
+        vec![
+            "dhcp4.subnet[0].broadcast-address: 192.168.0.254 does not match the netmask, expected 192.168.0.255",
+            "dhcp4.subnet[0].pool[0].range: 10.0.0.1 is outside 192.168.0.0/255.255.255.0",
+            "dhcp4.subnet[0].pool[0].range: 10.0.0.2 is outside 192.168.0.0/255.255.255.0",
+            "dhcp4.subnet[0].domain-name: option 15 is too long",
+            "dhcp4.subnet[1].range: 192.168.0.250 is after 192.168.0.240",
+            "dhcp4.subnet[1].id: id 3232235520 is also used by dhcp4.subnet[0].id",
+            "dhcp4.subnet[1].subnet: overlaps dhcp4.subnet[0]",
+            "dhcp4.host[2].fixed-address: 192.168.0.200 is inside the dynamic range 192.168.0.101 - 192.168.0.250",
+            "dhcp4.host[3].fixed-address: 192.168.0.12 is also used by dhcp4.host[1].fixed-address",
+            "dhcp4.host[3].hardware-ethernet: 00:00:00:11:11:11 is also used by dhcp4.host[0].hardware-ethernet",
+            "dhcp4.reclaim.interval: must not be 0",
+        ]
+    );
+}
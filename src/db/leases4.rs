@@ -1,107 +1,844 @@
-use std::{collections::HashSet, net::Ipv4Addr};
+use std::{convert::Infallible, net::Ipv4Addr};
 
-use anyhow::{bail, Result};
-use chrono::{DateTime, Local};
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Duration, Local};
 use ipnet::Ipv4AddrRange;
 use serde::{Deserialize, Serialize};
+use sled::{
+    transaction::{
+        ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+        TransactionalTree, UnabortableTransactionError,
+    },
+    Transactional,
+};
+use tokio::sync::mpsc;
+
+use super::{Lease4Change, LeaseStore};
+
+/// 現在の形式で保存したレコードの先頭に付ける
+///
+/// 最初の形式は先頭が hardware_address の長さ (u64 LE, 16 以下) なので区別できる
+const RECORD_TAG: u8 = 0xff;
+
+/// 索引のキーの先頭に付けて chaddr と client_id を区別する
+const INDEX_HARDWARE_ADDRESS: u8 = b'h';
+const INDEX_CLIENT_ID: u8 = b'c';
+
+fn index_key(kind: u8, id: &[u8]) -> Vec<u8> {
+    let mut key = vec![kind];
+    key.extend_from_slice(id);
+    key
+}
 
 #[derive(Deserialize, Serialize, PartialEq, Eq, Clone, Debug)]
 pub struct Leases4Record {
     pub hardware_address: Vec<u8>,
     pub ip_addr: Ipv4Addr,
     pub ttl: DateTime<Local>,
+    /// Client Identifier (option 61)
+    pub client_id: Option<Vec<u8>>,
+    /// 与えたリース時間 (秒), 古いレコードでは分からない
+    pub lease_time: Option<u32>,
+    /// リレーエージェントを経由していればその情報
+    pub relay: Option<Relay4Info>,
+    pub state: Leases4State,
+    /// 払い出しを始めた時刻, 古いレコードでは分からない
+    pub started_at: Option<DateTime<Local>>,
+    /// Host Name (option 12)
+    pub hostname: Option<String>,
+    /// 払い出したサブネットの id
+    pub subnet_id: Option<u32>,
+    /// 最後にクライアントからパケットを受け取った時刻
+    pub last_packet_at: Option<DateTime<Local>>,
+}
+
+/// リースの状態
+#[derive(Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Leases4State {
+    /// DHCPOFFER して DHCPREQUEST を待っている
+    Offered,
+    /// DHCPACK した
+    Bound,
+    /// DHCPRELEASE された
+    Released,
+    /// DHCPDECLINE された
+    Declined,
+    /// 払い出したまま期限が切れた
+    Expired,
+    /// DHCPRELEASE や DHCPDECLINE されたアドレスを回収した
+    Reclaimed,
+    /// DHCPOFFER したが DHCPREQUEST が来なかった
+    Abandoned,
+}
+
+impl Leases4State {
+    /// 同じリースのまま `self` から `next` に移れるか
+    ///
+    /// 期限の切れたアドレスを別のクライアントに払い出すのは新しいリースなので、ここでは扱わない
+    pub fn can_transition_to(self, next: Leases4State) -> bool {
+        use Leases4State::*;
+
+        matches!(
+            (self, next),
+            (Offered, Offered | Bound | Abandoned)
+                | (Bound, Bound | Released | Declined | Expired)
+                | (Released, Offered | Bound | Reclaimed)
+                | (Declined, Reclaimed)
+                | (Expired | Reclaimed | Abandoned, Offered | Bound)
+        )
+    }
+}
+
+/// 期限切れのリースを回収したときに通知する
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Lease4Event {
+    /// 期限が切れたので状態を変えた
+    Expired(Leases4Record),
+    /// 猶予期間が過ぎたので削除した
+    Removed(Leases4Record),
+}
+
+/// リレーエージェントが付けた Relay Agent Information (option 82, RFC 3046)
+#[derive(Deserialize, Serialize, PartialEq, Eq, Clone, Debug, Default)]
+pub struct Relay4Info {
+    pub circuit_id: Option<Vec<u8>>,
+    pub remote_id: Option<Vec<u8>>,
+}
+
+/// リースと共に記録する、パケットから分かるクライアントの情報
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct Lease4Info {
+    pub hostname: Option<String>,
+    pub subnet_id: Option<u32>,
+    pub relay: Option<Relay4Info>,
+}
+
+/// 最初の形式のレコード
+#[derive(Deserialize, Serialize)]
+struct Leases4RecordV0 {
+    hardware_address: Vec<u8>,
+    ip_addr: Ipv4Addr,
+    ttl: DateTime<Local>,
+}
+
+impl From<Leases4RecordV0> for Leases4Record {
+    fn from(v0: Leases4RecordV0) -> Self {
+        // リース時間を記録していなかったので DHCPACK したかは分からない
+        Leases4Record {
+            hardware_address: v0.hardware_address,
+            ip_addr: v0.ip_addr,
+            ttl: v0.ttl,
+            client_id: None,
+            lease_time: None,
+            relay: None,
+            state: Leases4State::Offered,
+            started_at: None,
+            hostname: None,
+            subnet_id: None,
+            last_packet_at: None,
+        }
+    }
+}
+
+/// `Leases4Record::reserve` の結果
+#[derive(PartialEq, Eq, Debug)]
+pub enum Reservation {
+    /// 他のクライアントが使っている
+    Taken,
+    /// 既に十分長く確保してある
+    Held,
+    /// このレコードを書き込んで確保する
+    Write(Leases4Record),
 }
 
 impl Leases4Record {
+    /// `client` に `ip_addr` を新しく DHCPOFFER するときのレコード
+    pub fn offer(
+        client: &Client4,
+        ip_addr: Ipv4Addr,
+        until: DateTime<Local>,
+        info: &Lease4Info,
+    ) -> Leases4Record {
+        let now = Local::now();
+        Leases4Record {
+            hardware_address: client.hardware_address.to_vec(),
+            ip_addr,
+            ttl: until,
+            client_id: client.client_id.map(<[u8]>::to_vec),
+            lease_time: None,
+            relay: info.relay.clone(),
+            state: Leases4State::Offered,
+            started_at: Some(now),
+            hostname: info.hostname.clone(),
+            subnet_id: info.subnet_id,
+            last_packet_at: Some(now),
+        }
+    }
+
+    /// `client` に `ip_addr` を `lease_time` 秒 DHCPACK するときのレコード
+    pub fn bind(
+        client: &Client4,
+        ip_addr: Ipv4Addr,
+        lease_time: u32,
+        info: &Lease4Info,
+    ) -> Leases4Record {
+        let now = Local::now();
+        Leases4Record {
+            ttl: now + Duration::seconds(lease_time.into()),
+            lease_time: Some(lease_time),
+            state: Leases4State::Bound,
+            ..Self::offer(client, ip_addr, now, info)
+        }
+    }
+
     pub fn is_expired(&self) -> bool {
         Local::now() >= self.ttl
     }
+
+    /// 状態を `next` に変える。許されない遷移ならエラー
+    pub fn transition(&mut self, next: Leases4State) -> Result<()> {
+        if !self.state.can_transition_to(next) {
+            bail!("{} can not be {next:?}: {:?}", self.ip_addr, self.state);
+        }
+        self.state = next;
+        Ok(())
+    }
+
+    /// `current` が記録されたアドレスを `client` のために `offer.ttl` まで確保する
+    ///
+    /// 払い出し済みのリースは縮めない
+    pub fn reserve(
+        current: Option<Leases4Record>,
+        client: &Client4,
+        offer: Leases4Record,
+    ) -> Reservation {
+        match current {
+            Some(current) if !current.is_expired() && !client.owns(&current) => Reservation::Taken,
+            Some(current) if client.owns(&current) && current.ttl >= offer.ttl => Reservation::Held,
+            Some(current) if client.owns(&current) && !current.is_expired() => {
+                Reservation::Write(Leases4Record {
+                    ttl: offer.ttl,
+                    last_packet_at: offer.last_packet_at,
+                    ..current
+                })
+            }
+            _ => Reservation::Write(offer),
+        }
+    }
+
+    /// `current` が記録されたアドレスを `lease` に書き換える
+    ///
+    /// 他のクライアントの有効なリースや隔離中のアドレスは書き換えない。
+    /// `client` の有効なリースが続いているなら状態の遷移を確かめ、払い出しを始めた時刻を引き継ぐ
+    pub fn renew(
+        current: Option<Leases4Record>,
+        client: &Client4,
+        mut lease: Leases4Record,
+    ) -> Result<Leases4Record> {
+        let Some(mut current) = current.filter(|current| !current.is_expired()) else {
+            return Ok(lease);
+        };
+        if current.state == Leases4State::Declined {
+            bail!("{} is quarantined", current.ip_addr);
+        }
+        if !client.owns(&current) {
+            bail!("{} is leased to another client", current.ip_addr);
+        }
+        let renewal = current.state == Leases4State::Bound;
+        current.transition(lease.state)?;
+        if renewal {
+            lease.started_at = current.started_at.or(lease.started_at);
+        }
+        Ok(lease)
+    }
+
+    /// `client` の DHCPRELEASE でリースをすぐに期限切れにする
+    pub fn release(mut self, client: &Client4) -> Result<Leases4Record> {
+        if !client.owns(&self) {
+            bail!("{} is not leased to {client:?}", self.ip_addr);
+        }
+        self.transition(Leases4State::Released)?;
+        self.ttl = Local::now();
+        self.last_packet_at = Some(self.ttl);
+        Ok(self)
+    }
+
+    /// `current` が記録されたアドレスを `client` の DHCPDECLINE で `until` まで隔離する
+    ///
+    /// 他のクライアントの有効なリースがあれば隔離しない。
+    /// 記録が無くても隔離し、`client` のリースがあれば拒否された状態にして返す
+    pub fn decline(
+        current: Option<Leases4Record>,
+        client: &Client4,
+        until: DateTime<Local>,
+    ) -> Result<Option<Leases4Record>> {
+        let Some(mut current) = current else {
+            return Ok(None);
+        };
+        if !client.owns(&current) {
+            if !current.is_expired() {
+                bail!("{} is leased to another client", current.ip_addr);
+            }
+            return Ok(None);
+        }
+        // 払い出す前のものや既に拒否されたものは隔離だけする
+        if current.transition(Leases4State::Declined).is_err() {
+            return Ok(None);
+        }
+        current.ttl = until;
+        current.last_packet_at = Some(Local::now());
+        Ok(Some(current))
+    }
+
+    /// 期限の切れたリースを回収する
+    ///
+    /// 払い出したリースは期限切れに、解放や拒否されたリースは回収済みにする。
+    /// それらが `grace` の間放置されていたら削除する
+    pub fn reclaim(&self, now: DateTime<Local>, grace: Option<Duration>) -> Option<Lease4Event> {
+        use Leases4State::*;
+
+        let next = match self.state {
+            Offered if self.ttl <= now => Abandoned,
+            Bound if self.ttl <= now => Expired,
+            Released | Declined if self.ttl <= now => Reclaimed,
+            Expired | Reclaimed | Abandoned
+                if grace.is_some_and(|grace| self.ttl + grace <= now) =>
+            {
+                return Some(Lease4Event::Removed(self.clone()));
+            }
+            _ => return None,
+        };
+        let mut record = self.clone();
+        record.transition(next).ok()?;
+        Some(Lease4Event::Expired(record))
+    }
+
+    /// 索引からこのレコードを引くためのキー
+    fn index_keys(&self) -> Vec<Vec<u8>> {
+        let mut keys = vec![index_key(INDEX_HARDWARE_ADDRESS, &self.hardware_address)];
+        if let Some(client_id) = &self.client_id {
+            keys.push(index_key(INDEX_CLIENT_ID, client_id));
+        }
+        keys
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut buffer = vec![RECORD_TAG];
+        bincode::serialize_into(&mut buffer, self)?;
+        Ok(buffer)
+    }
+
+    /// 最初の形式のレコードは足りないフィールドを空にして読む
+    pub fn decode(bytes: &[u8]) -> Result<Leases4Record> {
+        match bytes.split_first() {
+            Some((&RECORD_TAG, record)) => Ok(bincode::deserialize(record)?),
+            _ => Ok(bincode::deserialize::<Leases4RecordV0>(bytes)?.into()),
+        }
+    }
+}
+
+/// リースを受け取るクライアント
+///
+/// Client Identifier (option 61) があれば chaddr より優先して見分ける (RFC 2131 4.2)
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Client4<'a> {
+    pub hardware_address: &'a [u8],
+    pub client_id: Option<&'a [u8]>,
 }
 
+impl Client4<'_> {
+    /// `record` がこのクライアントのリースか
+    pub fn owns(&self, record: &Leases4Record) -> bool {
+        match (self.client_id, &record.client_id) {
+            (Some(client_id), Some(record_id)) => client_id == record_id.as_slice(),
+            _ => self.hardware_address == record.hardware_address,
+        }
+    }
+}
+
+/// DHCPDECLINE により使用中と報告され、隔離しているアドレス
+#[derive(Deserialize, Serialize, PartialEq, Eq, Clone, Debug)]
+pub struct Declined4Record {
+    pub hardware_address: Vec<u8>,
+    pub ip_addr: Ipv4Addr,
+    pub declined_at: DateTime<Local>,
+    pub until: DateTime<Local>,
+}
+
+impl Declined4Record {
+    pub fn new(client: &Client4, ip_addr: Ipv4Addr, until: DateTime<Local>) -> Declined4Record {
+        Declined4Record {
+            hardware_address: client.hardware_address.to_vec(),
+            ip_addr,
+            declined_at: Local::now(),
+            until,
+        }
+    }
+
+    pub fn is_quarantined(&self) -> bool {
+        Local::now() < self.until
+    }
+}
+
+/// sled に保存するリース
+///
+/// `inner` は IP アドレスからリースを、`index` は chaddr と client_id から IP アドレスを引く。
+/// 両方を書き換えるときはトランザクションで同時に更新する
 #[derive(Clone, Debug)]
 pub struct Leases4Tree {
     inner: sled::Tree,
+    index: sled::Tree,
+    declined: sled::Tree,
 }
 
 impl Leases4Tree {
-    pub fn new(inner: sled::Tree) -> Leases4Tree {
-        Leases4Tree { inner }
+    pub fn new(inner: sled::Tree, index: sled::Tree, declined: sled::Tree) -> Leases4Tree {
+        Leases4Tree {
+            inner,
+            index,
+            declined,
+        }
     }
 
     pub fn generate_key(address: &Ipv4Addr) -> Vec<u8> {
         address.octets().to_vec()
     }
 
-    pub fn get_by_ip(&self, address: &Ipv4Addr) -> Result<Leases4Record> {
+    /// `inner` から索引を作り直す
+    pub fn rebuild_index(&self) -> Result<usize> {
+        self.index.clear()?;
+        let mut count = 0;
+        for (key, value) in self.inner.iter().flatten() {
+            let Ok(record) = Leases4Record::decode(&value) else {
+                eprintln!("Key={key:?} deserialize error");
+                continue;
+            };
+            for index_key in record.index_keys() {
+                self.index.insert(index_key, &key)?;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// `start` から `end` のうちレコードの無いアドレス
+    ///
+    /// キーの順に並んだレコードと突き合わせるので、レコードを読まずに済む
+    fn unused(&self, start: Ipv4Addr, end: Ipv4Addr) -> impl Iterator<Item = Ipv4Addr> {
+        let mut used = self
+            .inner
+            .range(Self::generate_key(&start)..=Self::generate_key(&end))
+            .keys()
+            .flatten()
+            .flat_map(|key| <[u8; 4]>::try_from(key.as_ref()).map(Ipv4Addr::from))
+            .peekable();
+        Ipv4AddrRange::new(start, end).filter(move |addr| {
+            while used.next_if(|used| used < addr).is_some() {}
+            used.peek() != Some(addr)
+        })
+    }
+
+    /// `offer` のアドレスが空いていれば書き込んで確保する
+    ///
+    /// 他のクライアントが使っていて確保できなければ `false` を返す
+    fn reserve(&self, client: &Client4, offer: &Leases4Record) -> Result<bool> {
+        let key = Self::generate_key(&offer.ip_addr);
+        let reserved = (&self.inner, &self.index).transaction(
+            |(inner, index)| -> ConflictableTransactionResult<bool, anyhow::Error> {
+                // 壊れているレコードは空きとみなす
+                let current = inner
+                    .get(key.as_slice())?
+                    .and_then(|current| Leases4Record::decode(&current).ok());
+                let record = match Leases4Record::reserve(current, client, offer.clone()) {
+                    Reservation::Taken => return Ok(false),
+                    Reservation::Held => return Ok(true),
+                    Reservation::Write(record) => record,
+                };
+                let value = record
+                    .encode()
+                    .map_err(ConflictableTransactionError::Abort)?;
+                Self::write(inner, index, &key, &value, &record)?;
+                Ok(true)
+            },
+        );
+        match reserved {
+            Ok(reserved) => Ok(reserved),
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+        }
+    }
+
+    fn insert(&self, record: &Leases4Record) -> Result<()> {
+        let key = Self::generate_key(&record.ip_addr);
+        let value = record.encode()?;
+        (&self.inner, &self.index).transaction(
+            |(inner, index)| -> ConflictableTransactionResult<(), Infallible> {
+                Self::write(inner, index, &key, &value, record)?;
+                Ok(())
+            },
+        )?;
+        Ok(())
+    }
+
+    /// `record` を `key` に書き込み、索引を付け替える
+    fn write(
+        inner: &TransactionalTree,
+        index: &TransactionalTree,
+        key: &[u8],
+        value: &[u8],
+        record: &Leases4Record,
+    ) -> Result<(), UnabortableTransactionError> {
+        if let Some(old) = inner.insert(key, value)? {
+            Self::unindex(index, &old, key)?;
+        }
+        for index_key in record.index_keys() {
+            index.insert(index_key, key)?;
+        }
+        Ok(())
+    }
+
+    /// `old` を引くための索引のうち、まだ `key` を指しているものを消す
+    fn unindex(
+        index: &TransactionalTree,
+        old: &[u8],
+        key: &[u8],
+    ) -> Result<(), UnabortableTransactionError> {
+        let Ok(old) = Leases4Record::decode(old) else {
+            return Ok(());
+        };
+        for index_key in old.index_keys() {
+            if index
+                .get(&index_key)?
+                .is_some_and(|value| value.as_ref() == key)
+            {
+                index.remove(index_key)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl LeaseStore for Leases4Tree {
+    fn get_by_ip(&self, address: &Ipv4Addr) -> Result<Leases4Record> {
         let key = Self::generate_key(address);
         let Some(value) = self.inner.get(key)? else {
             bail!("Empty key {address}");
         };
-        let record: Leases4Record = bincode::deserialize(&value)?;
-        Ok(record)
+        Leases4Record::decode(&value)
     }
 
-    pub fn get_by_hw(&self, address: &[u8]) -> Result<Leases4Record> {
-        for (key, value) in self.inner.into_iter().flatten() {
-            let Ok(record) = bincode::deserialize::<Leases4Record>(&value) else {
-                eprintln!("Key={key:?} deserialize error");
+    /// client_id の索引を、無ければ chaddr の索引を引く
+    fn get_by_client(&self, client: &Client4) -> Result<Leases4Record> {
+        let keys = client
+            .client_id
+            .map(|client_id| index_key(INDEX_CLIENT_ID, client_id))
+            .into_iter()
+            .chain([index_key(INDEX_HARDWARE_ADDRESS, client.hardware_address)]);
+        for key in keys {
+            let Some(value) = self.index.get(key)? else {
+                continue;
+            };
+            let Ok(octets) = <[u8; 4]>::try_from(value.as_ref()) else {
                 continue;
             };
-            if record.hardware_address == address {
-                return Ok(record);
+            match self.get_by_ip(&Ipv4Addr::from(octets)) {
+                Ok(record) if client.owns(&record) => return Ok(record),
+                _ => continue,
             }
         }
         bail!("Not found");
     }
 
-    pub fn all(&self) -> Vec<Leases4Record> {
-        self.inner
-            .into_iter()
-            .flatten()
-            .flat_map(|(_, value)| bincode::deserialize(&value))
-            .collect()
+    fn iter(&self) -> Box<dyn Iterator<Item = Leases4Record> + '_> {
+        Box::new(
+            self.inner
+                .iter()
+                .flatten()
+                .flat_map(|(_, value)| Leases4Record::decode(&value)),
+        )
+    }
+
+    fn get_declined(&self, address: &Ipv4Addr) -> Result<Declined4Record> {
+        let key = Self::generate_key(address);
+        let Some(value) = self.declined.get(key)? else {
+            bail!("Empty key {address}");
+        };
+        let record: Declined4Record = bincode::deserialize(&value)?;
+        Ok(record)
+    }
+
+    fn iter_declined(&self) -> Box<dyn Iterator<Item = Declined4Record> + '_> {
+        Box::new(
+            self.declined
+                .iter()
+                .flatten()
+                .flat_map(|(_, value)| bincode::deserialize(&value)),
+        )
     }
 
-    pub fn suggest(
+    /// 一度も払い出していないアドレスを期限切れのアドレスより先に使う
+    fn allocate(
         &self,
-        hw_address: &[u8],
+        client: &Client4,
         start: Ipv4Addr,
         end: Ipv4Addr,
-        excludes: HashSet<Ipv4Addr>,
+        hold: Duration,
+        info: &Lease4Info,
     ) -> Result<Ipv4Addr> {
-        if let Ok(record) = self.get_by_hw(hw_address) {
-            return Ok(record.ip_addr);
-        }
-
-        let addr = Ipv4AddrRange::new(start, end)
+        // 別のサブネットで払い出したアドレスは使いまわさない
+        let current = self
+            .get_by_client(client)
+            .ok()
+            .map(|record| record.ip_addr)
+            .filter(|addr| (start..=end).contains(addr));
+        let candidates = current
             .into_iter()
-            .filter(|addr| excludes.contains(&addr))
-            .find(|addr| {
-                // 壊れているレコードは空きとみなす
-                self.get_by_ip(&addr).map(|record| record.is_expired()).ok() != Some(false)
-            });
-        let Some(addr) = addr else {
-            bail!("No empty address");
-        };
-        Ok(addr)
+            .chain(self.unused(start, end))
+            .chain(Ipv4AddrRange::new(start, end));
+        let until = Local::now() + hold;
+        for addr in candidates {
+            if self.is_available(client, &addr)
+                && self.reserve(client, &Leases4Record::offer(client, addr, until, info))?
+            {
+                return Ok(addr);
+            }
+        }
+        bail!("No empty address");
     }
 
-    pub fn acquire(
+    fn renew(
         &self,
-        hw_addr: Vec<u8>,
+        client: &Client4,
         ip_addr: Ipv4Addr,
-        ttl: DateTime<Local>,
+        lease_time: u32,
+        info: &Lease4Info,
     ) -> Result<Leases4Record> {
         let key = Self::generate_key(&ip_addr);
-        let record = Leases4Record {
-            hardware_address: hw_addr,
-            ip_addr,
-            ttl,
-        };
+        let lease = Leases4Record::bind(client, ip_addr, lease_time, info);
+        // 読んでから書き込むまでに他のクライアントに払い出されないようにする
+        let renewed = (&self.inner, &self.index).transaction(
+            |(inner, index)| -> ConflictableTransactionResult<Leases4Record, anyhow::Error> {
+                let current = inner
+                    .get(key.as_slice())?
+                    .and_then(|current| Leases4Record::decode(&current).ok());
+                let record = Leases4Record::renew(current, client, lease.clone())
+                    .map_err(ConflictableTransactionError::Abort)?;
+                let value = record
+                    .encode()
+                    .map_err(ConflictableTransactionError::Abort)?;
+                Self::write(inner, index, &key, &value, &record)?;
+                Ok(record)
+            },
+        );
+        match renewed {
+            Ok(record) => Ok(record),
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+        }
+    }
+
+    fn release(&self, client: &Client4, ip_addr: &Ipv4Addr) -> Result<Leases4Record> {
+        let key = Self::generate_key(ip_addr);
+        // 読んでから書き込むまでに他のクライアントに払い出されないようにする
+        let released = (&self.inner, &self.index).transaction(
+            |(inner, index)| -> ConflictableTransactionResult<Leases4Record, anyhow::Error> {
+                let Some(current) = inner.get(key.as_slice())? else {
+                    return Err(ConflictableTransactionError::Abort(anyhow!(
+                        "Empty key {ip_addr}"
+                    )));
+                };
+                let record = Leases4Record::decode(&current)
+                    .and_then(|current| current.release(client))
+                    .map_err(ConflictableTransactionError::Abort)?;
+                let value = record
+                    .encode()
+                    .map_err(ConflictableTransactionError::Abort)?;
+                Self::write(inner, index, &key, &value, &record)?;
+                Ok(record)
+            },
+        );
+        match released {
+            Ok(record) => Ok(record),
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+        }
+    }
+
+    fn decline(
+        &self,
+        client: &Client4,
+        ip_addr: &Ipv4Addr,
+        until: DateTime<Local>,
+    ) -> Result<Declined4Record> {
+        let key = Self::generate_key(ip_addr);
+        let record = Declined4Record::new(client, *ip_addr, until);
         let serialized = bincode::serialize(&record)?;
-        let _ = self.inner.insert(key, serialized)?;
-        Ok(record)
+        // 読んでから書き込むまでに他のクライアントに払い出されないようにする
+        let declined = (&self.inner, &self.index, &self.declined).transaction(
+            |(inner, index, declined)| -> ConflictableTransactionResult<(), anyhow::Error> {
+                let current = inner
+                    .get(key.as_slice())?
+                    .and_then(|current| Leases4Record::decode(&current).ok());
+                let lease = Leases4Record::decline(current, client, until)
+                    .map_err(ConflictableTransactionError::Abort)?;
+                if let Some(lease) = lease {
+                    let value = lease
+                        .encode()
+                        .map_err(ConflictableTransactionError::Abort)?;
+                    Self::write(inner, index, &key, &value, &lease)?;
+                }
+                declined.insert(key.as_slice(), serialized.as_slice())?;
+                Ok(())
+            },
+        );
+        match declined {
+            Ok(()) => Ok(record),
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+        }
+    }
+
+    fn reclaim(
+        &self,
+        now: DateTime<Local>,
+        batch_size: usize,
+        grace: Option<Duration>,
+    ) -> Result<Vec<Lease4Event>> {
+        let mut events = Vec::new();
+        for (key, value) in self.inner.iter().flatten() {
+            if events.len() >= batch_size {
+                break;
+            }
+            let Ok(record) = Leases4Record::decode(&value) else {
+                continue;
+            };
+            let Some(event) = record.reclaim(now, grace) else {
+                continue;
+            };
+            let new = match &event {
+                Lease4Event::Expired(record) => Some(record.encode()?),
+                Lease4Event::Removed(_) => None,
+            };
+            let reclaimed = (&self.inner, &self.index).transaction(
+                |(inner, index)| -> ConflictableTransactionResult<bool, Infallible> {
+                    // 読んでから書き換えられていれば次の回収に回す
+                    if inner.get(&key)?.as_ref() != Some(&value) {
+                        return Ok(false);
+                    }
+                    match &new {
+                        Some(new) => {
+                            inner.insert(&key, new.as_slice())?;
+                        }
+                        None => {
+                            inner.remove(&key)?;
+                            Self::unindex(index, &value, &key)?;
+                        }
+                    }
+                    Ok(true)
+                },
+            )?;
+            if reclaimed {
+                events.push(event);
+            }
+        }
+        Ok(events)
+    }
+
+    fn purge_declined(&self, now: DateTime<Local>) -> Result<usize> {
+        let mut count = 0;
+        for (key, value) in self.declined.iter().flatten() {
+            let Ok(record) = bincode::deserialize::<Declined4Record>(&value) else {
+                continue;
+            };
+            // 読んでから再び DHCPDECLINE されていれば残す
+            if record.until <= now
+                && self
+                    .declined
+                    .compare_and_swap(&key, Some(&value), None::<&[u8]>)?
+                    .is_ok()
+            {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn put(&self, record: &Leases4Record) -> Result<()> {
+        self.insert(record)
     }
+
+    fn watch(&self) -> Result<mpsc::UnboundedReceiver<Lease4Change>> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let subscriber = self.inner.watch_prefix(vec![]);
+        std::thread::spawn(move || {
+            for event in subscriber {
+                let change = match event {
+                    sled::Event::Insert { value, .. } => match Leases4Record::decode(&value) {
+                        Ok(record) => Lease4Change::Updated(record),
+                        Err(_) => continue,
+                    },
+                    sled::Event::Remove { key } => match <[u8; 4]>::try_from(key.as_ref()) {
+                        Ok(octets) => Lease4Change::Removed(Ipv4Addr::from(octets)),
+                        Err(_) => continue,
+                    },
+                };
+                // 受け取り手がいなくなったら止める
+                if sender.send(change).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(receiver)
+    }
+}
+
+#[cfg(test)]
+pub fn temporary_tree() -> Leases4Tree {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    Leases4Tree::new(
+        db.open_tree("LEASES4").unwrap(),
+        db.open_tree("LEASES4_INDEX").unwrap(),
+        db.open_tree("DECLINED4").unwrap(),
+    )
+}
+
+#[test]
+fn index_test() {
+    let leases = temporary_tree();
+    let ip_addr = "192.168.1.1".parse().unwrap();
+    let info = Lease4Info::default();
+    let pxe = Client4 {
+        hardware_address: &[1, 2, 3],
+        client_id: Some(&[1, 1, 2, 3]),
+    };
+    let other = Client4 {
+        hardware_address: &[4, 5, 6],
+        client_id: None,
+    };
+    leases.renew(&pxe, ip_addr, 3600, &info).unwrap();
+    assert_eq!(leases.index.len(), 2);
+    assert_eq!(leases.get_by_client(&pxe).unwrap().ip_addr, ip_addr);
+
+    // 同じアドレスを別のクライアントに払い出したら前のクライアントの索引は消える
+    leases.release(&pxe, &ip_addr).unwrap();
+    leases.renew(&other, ip_addr, 3600, &info).unwrap();
+    assert!(leases.get_by_client(&pxe).is_err());
+    assert!(leases
+        .get_by_client(&Client4 {
+            client_id: None,
+            ..pxe
+        })
+        .is_err());
+    assert_eq!(leases.index.len(), 1);
+
+    // 索引が失われても作り直せる
+    leases.release(&other, &ip_addr).unwrap();
+    leases.renew(&pxe, ip_addr, 3600, &info).unwrap();
+    leases.index.clear().unwrap();
+    assert!(leases.get_by_client(&pxe).is_err());
+    assert_eq!(leases.rebuild_index().unwrap(), 1);
+    assert_eq!(leases.get_by_client(&pxe).unwrap().ip_addr, ip_addr);
 }
 
 #[test]
@@ -111,8 +848,88 @@ fn encode_decode_test() {
         hardware_address: vec![1, 2, 3],
         ip_addr: "192.168.1.1".parse().unwrap(),
         ttl: now,
+        client_id: Some(vec![1, 1, 2, 3]),
+        lease_time: Some(3600),
+        relay: Some(Relay4Info {
+            circuit_id: Some(b"eth0/1".to_vec()),
+            remote_id: None,
+        }),
+        state: Leases4State::Released,
+        started_at: Some(now),
+        hostname: Some("host".to_string()),
+        subnet_id: Some(1),
+        last_packet_at: Some(now),
     };
-    let serialized = bincode::serialize(&record).unwrap();
-    let deserialized = bincode::deserialize(&serialized).unwrap();
-    assert_eq!(record, deserialized);
+    let encoded = record.encode().unwrap();
+    assert_eq!(Leases4Record::decode(&encoded).unwrap(), record);
+
+    // 最初の形式のレコードも読める
+    let v0 = Leases4RecordV0 {
+        hardware_address: record.hardware_address.clone(),
+        ip_addr: record.ip_addr,
+        ttl: now,
+    };
+    assert_eq!(
+        Leases4Record::decode(&bincode::serialize(&v0).unwrap()).unwrap(),
+        Leases4Record {
+            client_id: None,
+            lease_time: None,
+            relay: None,
+            state: Leases4State::Offered,
+            started_at: None,
+            hostname: None,
+            subnet_id: None,
+            last_packet_at: None,
+            ..record
+        }
+    );
+}
+
+#[test]
+fn transition_test() {
+    use Leases4State::*;
+
+    assert!(Offered.can_transition_to(Bound));
+    assert!(Bound.can_transition_to(Bound));
+    assert!(Bound.can_transition_to(Released));
+    assert!(!Released.can_transition_to(Released));
+    assert!(!Declined.can_transition_to(Bound));
+    assert!(!Offered.can_transition_to(Expired));
+
+    let client = Client4 {
+        hardware_address: &[1, 2, 3],
+        client_id: None,
+    };
+    let info = Lease4Info::default();
+    let ip_addr = "192.168.1.1".parse().unwrap();
+    let bound = Leases4Record::bind(&client, ip_addr, 0, &info);
+    let now = Local::now();
+    let Some(Lease4Event::Expired(expired)) = bound.reclaim(now, None) else {
+        panic!("not expired");
+    };
+    assert_eq!(expired.state, Expired);
+    assert_eq!(expired.reclaim(now, None), None);
+    assert!(matches!(
+        expired.reclaim(now, Some(Duration::zero())),
+        Some(Lease4Event::Removed(_))
+    ));
+
+    // 有効なリースの延長は払い出しを始めた時刻を引き継ぐ
+    let mut started = Leases4Record::bind(&client, ip_addr, 3600, &info);
+    started.started_at = Some(now - Duration::hours(1));
+    let renewed = Leases4Record::renew(
+        Some(started.clone()),
+        &client,
+        Leases4Record::bind(&client, ip_addr, 3600, &info),
+    )
+    .unwrap();
+    assert_eq!(renewed.started_at, started.started_at);
+    let mut declined = started;
+    declined.transition(Declined).unwrap();
+    assert!(Leases4Record::renew(
+        Some(declined),
+        &client,
+        Leases4Record::bind(&client, ip_addr, 3600, &info),
+    )
+    .is_err());
 }
@@ -0,0 +1,248 @@
+use std::{
+    collections::BTreeMap,
+    net::Ipv4Addr,
+    sync::{Mutex, MutexGuard},
+};
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Duration, Local};
+use ipnet::Ipv4AddrRange;
+use tokio::sync::mpsc;
+
+use super::{
+    Client4, Declined4Record, Lease4Change, Lease4Event, Lease4Info, LeaseStore, Leases4Record,
+    Reservation,
+};
+
+#[derive(Default, Debug)]
+struct MemoryLeases {
+    leases: BTreeMap<Ipv4Addr, Leases4Record>,
+    declined: BTreeMap<Ipv4Addr, Declined4Record>,
+    watchers: Vec<mpsc::UnboundedSender<Lease4Change>>,
+}
+
+impl MemoryLeases {
+    fn notify(&mut self, change: Lease4Change) {
+        self.watchers
+            .retain(|watcher| watcher.send(change.clone()).is_ok());
+    }
+
+    fn insert(&mut self, record: Leases4Record) {
+        self.leases.insert(record.ip_addr, record.clone());
+        self.notify(Lease4Change::Updated(record));
+    }
+
+    fn remove(&mut self, ip_addr: &Ipv4Addr) {
+        if self.leases.remove(ip_addr).is_some() {
+            self.notify(Lease4Change::Removed(*ip_addr));
+        }
+    }
+
+    fn is_quarantined(&self, address: &Ipv4Addr) -> bool {
+        self.declined
+            .get(address)
+            .is_some_and(Declined4Record::is_quarantined)
+    }
+
+    fn is_available(&self, client: &Client4, address: &Ipv4Addr) -> bool {
+        !self.is_quarantined(address)
+            && self
+                .leases
+                .get(address)
+                .is_none_or(|record| client.owns(record) || record.is_expired())
+    }
+
+    fn get_by_client(&self, client: &Client4) -> Option<&Leases4Record> {
+        let owned = self
+            .leases
+            .values()
+            .filter(|record| client.owns(record))
+            .max_by_key(|record| record.ttl);
+        // client_id が一致するものを優先する
+        client
+            .client_id
+            .and_then(|client_id| {
+                self.leases
+                    .values()
+                    .filter(|record| record.client_id.as_deref() == Some(client_id))
+                    .max_by_key(|record| record.ttl)
+            })
+            .or(owned)
+    }
+}
+
+/// メモリ上に置くリース
+///
+/// 再起動すると失われるので、テストや一時的に動かすときに使う
+#[derive(Default, Debug)]
+pub struct MemoryLeaseStore {
+    inner: Mutex<MemoryLeases>,
+}
+
+impl MemoryLeaseStore {
+    pub fn new() -> MemoryLeaseStore {
+        MemoryLeaseStore::default()
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, MemoryLeases>> {
+        self.inner
+            .lock()
+            .map_err(|_| anyhow!("memory lease store lock failed"))
+    }
+}
+
+impl LeaseStore for MemoryLeaseStore {
+    fn get_by_ip(&self, address: &Ipv4Addr) -> Result<Leases4Record> {
+        let Some(record) = self.lock()?.leases.get(address).cloned() else {
+            bail!("Empty key {address}");
+        };
+        Ok(record)
+    }
+
+    fn get_by_client(&self, client: &Client4) -> Result<Leases4Record> {
+        let Some(record) = self.lock()?.get_by_client(client).cloned() else {
+            bail!("Not found");
+        };
+        Ok(record)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Leases4Record> + '_> {
+        let leases = self
+            .lock()
+            .map(|leases| leases.leases.values().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        Box::new(leases.into_iter())
+    }
+
+    fn get_declined(&self, address: &Ipv4Addr) -> Result<Declined4Record> {
+        let Some(record) = self.lock()?.declined.get(address).cloned() else {
+            bail!("Empty key {address}");
+        };
+        Ok(record)
+    }
+
+    fn iter_declined(&self) -> Box<dyn Iterator<Item = Declined4Record> + '_> {
+        let declined = self
+            .lock()
+            .map(|leases| leases.declined.values().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        Box::new(declined.into_iter())
+    }
+
+    /// 一度も払い出していないアドレスを期限切れのアドレスより先に使う
+    fn allocate(
+        &self,
+        client: &Client4,
+        start: Ipv4Addr,
+        end: Ipv4Addr,
+        hold: Duration,
+        info: &Lease4Info,
+    ) -> Result<Ipv4Addr> {
+        let mut leases = self.lock()?;
+        // 別のサブネットで払い出したアドレスは使いまわさない
+        let current = leases
+            .get_by_client(client)
+            .map(|record| record.ip_addr)
+            .filter(|addr| (start..=end).contains(addr));
+        let unused =
+            Ipv4AddrRange::new(start, end).filter(|addr| !leases.leases.contains_key(addr));
+        let Some(addr) = current
+            .into_iter()
+            .chain(unused)
+            .chain(Ipv4AddrRange::new(start, end))
+            .find(|addr| leases.is_available(client, addr))
+        else {
+            bail!("No empty address");
+        };
+        let offer = Leases4Record::offer(client, addr, Local::now() + hold, info);
+        match Leases4Record::reserve(leases.leases.get(&addr).cloned(), client, offer) {
+            Reservation::Write(record) => leases.insert(record),
+            Reservation::Held => {}
+            Reservation::Taken => bail!("{addr} is taken"),
+        }
+        Ok(addr)
+    }
+
+    fn renew(
+        &self,
+        client: &Client4,
+        ip_addr: Ipv4Addr,
+        lease_time: u32,
+        info: &Lease4Info,
+    ) -> Result<Leases4Record> {
+        let mut leases = self.lock()?;
+        let record = Leases4Record::renew(
+            leases.leases.get(&ip_addr).cloned(),
+            client,
+            Leases4Record::bind(client, ip_addr, lease_time, info),
+        )?;
+        leases.insert(record.clone());
+        Ok(record)
+    }
+
+    fn release(&self, client: &Client4, ip_addr: &Ipv4Addr) -> Result<Leases4Record> {
+        let mut leases = self.lock()?;
+        let Some(record) = leases.leases.get(ip_addr).cloned() else {
+            bail!("Empty key {ip_addr}");
+        };
+        let record = record.release(client)?;
+        leases.insert(record.clone());
+        Ok(record)
+    }
+
+    fn decline(
+        &self,
+        client: &Client4,
+        ip_addr: &Ipv4Addr,
+        until: DateTime<Local>,
+    ) -> Result<Declined4Record> {
+        let mut leases = self.lock()?;
+        let current = leases.leases.get(ip_addr).cloned();
+        if let Some(lease) = Leases4Record::decline(current, client, until)? {
+            leases.insert(lease);
+        }
+        let record = Declined4Record::new(client, *ip_addr, until);
+        leases.declined.insert(*ip_addr, record.clone());
+        Ok(record)
+    }
+
+    fn reclaim(
+        &self,
+        now: DateTime<Local>,
+        batch_size: usize,
+        grace: Option<Duration>,
+    ) -> Result<Vec<Lease4Event>> {
+        let mut leases = self.lock()?;
+        let events = leases
+            .leases
+            .values()
+            .filter_map(|record| record.reclaim(now, grace))
+            .take(batch_size)
+            .collect::<Vec<_>>();
+        for event in &events {
+            match event {
+                Lease4Event::Expired(record) => leases.insert(record.clone()),
+                Lease4Event::Removed(record) => leases.remove(&record.ip_addr),
+            }
+        }
+        Ok(events)
+    }
+
+    fn purge_declined(&self, now: DateTime<Local>) -> Result<usize> {
+        let mut leases = self.lock()?;
+        let before = leases.declined.len();
+        leases.declined.retain(|_, record| record.until > now);
+        Ok(before - leases.declined.len())
+    }
+
+    fn put(&self, record: &Leases4Record) -> Result<()> {
+        self.lock()?.insert(record.clone());
+        Ok(())
+    }
+
+    fn watch(&self) -> Result<mpsc::UnboundedReceiver<Lease4Change>> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.lock()?.watchers.push(sender);
+        Ok(receiver)
+    }
+}
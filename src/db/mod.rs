@@ -1,12 +1,48 @@
 mod leases4;
+mod memory;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod store;
+pub mod transfer;
 
 use anyhow::Result;
-use std::{ops::Deref, path::Path};
+use once_cell::sync::Lazy;
+use std::{ops::Deref, path::Path, sync::Arc};
 
-use self::leases4::Leases4Tree;
-use crate::conf::OMOI_CONFIG;
+use crate::conf::{CommonConfig, LeaseStoreKind, OMOI_CONFIG};
 
-pub use self::leases4::Leases4Record;
+pub use self::leases4::{
+    Client4, Declined4Record, Lease4Event, Lease4Info, Leases4Record, Leases4State, Leases4Tree,
+    Relay4Info, Reservation,
+};
+pub use self::memory::MemoryLeaseStore;
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteLeaseStore;
+pub use self::store::{Lease4Change, LeaseStore};
+
+/// sled は同じディレクトリを一つのプロセスで一度しか開けないので、ここで開いたものを使いまわす
+pub static DB: Lazy<Db> = Lazy::new(Db::open);
+
+/// `common.lease-store` で選んだリースの保存先
+pub static LEASES4: Lazy<Arc<dyn LeaseStore>> =
+    Lazy::new(|| open_lease_store(&OMOI_CONFIG.current().common).expect("open lease store error"));
+
+pub fn open_lease_store(config: &CommonConfig) -> Result<Arc<dyn LeaseStore>> {
+    let store: Arc<dyn LeaseStore> = match config.lease_store {
+        LeaseStoreKind::Sled => {
+            let leases = DB.leases_tree()?;
+            // 以前のバージョンで作ったデータベースには索引が無いので、開くときに作り直す
+            leases.rebuild_index()?;
+            Arc::new(leases)
+        }
+        LeaseStoreKind::Memory => Arc::new(MemoryLeaseStore::new()),
+        #[cfg(feature = "sqlite")]
+        LeaseStoreKind::Sqlite => Arc::new(SqliteLeaseStore::open(&config.sqlite_path())?),
+        #[cfg(not(feature = "sqlite"))]
+        LeaseStoreKind::Sqlite => anyhow::bail!("omoi was built without the sqlite feature"),
+    };
+    Ok(store)
+}
 
 #[derive(Clone, Debug)]
 pub struct Db {
@@ -19,11 +55,25 @@ impl Db {
         Ok(Db { inner })
     }
     pub fn open() -> Db {
-        Self::try_open(&OMOI_CONFIG.common.database_dir).expect("open db error")
+        Self::try_open(&OMOI_CONFIG.current().common.database_dir).expect("open db error")
+    }
+    #[cfg(test)]
+    pub fn temporary() -> Db {
+        let inner = sled::Config::new()
+            .temporary(true)
+            .open()
+            .expect("open db error");
+        Db { inner }
     }
     pub fn leases_tree(&self) -> Result<Leases4Tree> {
         let tree = self.open_tree("LEASES4")?;
-        Ok(Leases4Tree::new(tree))
+        let index = self.open_tree("LEASES4_INDEX")?;
+        let declined = self.open_tree("DECLINED4")?;
+        Ok(Leases4Tree::new(tree, index, declined))
+    }
+    /// 再起動しても引き継ぐ DHCPOFFER
+    pub fn offers_tree(&self) -> Result<sled::Tree> {
+        Ok(self.open_tree("OFFERS4")?)
     }
 }
 
@@ -0,0 +1,665 @@
+use std::{
+    net::Ipv4Addr,
+    ops::{Bound, RangeBounds},
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Duration, Local, SecondsFormat, Utc};
+use rusqlite::{
+    params, params_from_iter,
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
+    Connection, OptionalExtension, Row, ToSql,
+};
+use tokio::sync::mpsc;
+
+use super::{
+    Client4, Declined4Record, Lease4Change, Lease4Event, Lease4Info, LeaseStore, Leases4Record,
+    Leases4State, Relay4Info, Reservation,
+};
+
+/// スキーマの変更
+///
+/// 適用した数を `PRAGMA user_version` に記録し、開くときに足りない分を順に適用する。
+/// 一度リリースしたものは書き換えず、末尾に足していく
+const MIGRATIONS: &[&str] = &[
+    // IP アドレスは範囲で引けるよう整数、時刻は文字列で並べても順序が崩れないよう UTC の RFC 3339 で持つ
+    "CREATE TABLE leases4 (
+        ip_addr INTEGER PRIMARY KEY,
+        hardware_address BLOB NOT NULL,
+        client_id BLOB,
+        expires_at TEXT NOT NULL,
+        lease_time INTEGER,
+        state TEXT NOT NULL,
+        started_at TEXT,
+        hostname TEXT,
+        subnet_id INTEGER,
+        last_packet_at TEXT,
+        circuit_id BLOB,
+        remote_id BLOB
+    );
+    CREATE INDEX leases4_hardware_address ON leases4 (hardware_address);
+    CREATE INDEX leases4_client_id ON leases4 (client_id);
+    CREATE INDEX leases4_expires_at ON leases4 (expires_at);
+    CREATE TABLE declined4 (
+        ip_addr INTEGER PRIMARY KEY,
+        hardware_address BLOB NOT NULL,
+        declined_at TEXT NOT NULL,
+        until TEXT NOT NULL
+    );",
+];
+
+const LEASE_COLUMNS: &str = "ip_addr, hardware_address, client_id, expires_at, lease_time, state, \
+    started_at, hostname, subnet_id, last_packet_at, circuit_id, remote_id";
+
+const DECLINED_COLUMNS: &str = "ip_addr, hardware_address, declined_at, until";
+
+/// SQLite に書き込む時刻
+struct SqlTime(DateTime<Local>);
+
+impl ToSql for SqlTime {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let time = self
+            .0
+            .with_timezone(&Utc)
+            .to_rfc3339_opts(SecondsFormat::Nanos, true);
+        Ok(time.into())
+    }
+}
+
+impl FromSql for SqlTime {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        DateTime::parse_from_rfc3339(value.as_str()?)
+            .map(|time| SqlTime(time.with_timezone(&Local)))
+            .map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
+
+impl ToSql for Leases4State {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        use Leases4State::*;
+
+        let state = match self {
+            Offered => "offered",
+            Bound => "bound",
+            Released => "released",
+            Declined => "declined",
+            Expired => "expired",
+            Reclaimed => "reclaimed",
+            Abandoned => "abandoned",
+        };
+        Ok(state.into())
+    }
+}
+
+impl FromSql for Leases4State {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        use Leases4State::*;
+
+        match value.as_str()? {
+            "offered" => Ok(Offered),
+            "bound" => Ok(Bound),
+            "released" => Ok(Released),
+            "declined" => Ok(Declined),
+            "expired" => Ok(Expired),
+            "reclaimed" => Ok(Reclaimed),
+            "abandoned" => Ok(Abandoned),
+            state => Err(FromSqlError::Other(
+                anyhow!("unknown lease state {state}").into(),
+            )),
+        }
+    }
+}
+
+fn lease_of(row: &Row) -> rusqlite::Result<Leases4Record> {
+    let circuit_id: Option<Vec<u8>> = row.get(10)?;
+    let remote_id: Option<Vec<u8>> = row.get(11)?;
+    let relay = (circuit_id.is_some() || remote_id.is_some()).then_some(Relay4Info {
+        circuit_id,
+        remote_id,
+    });
+    Ok(Leases4Record {
+        ip_addr: Ipv4Addr::from(row.get::<_, u32>(0)?),
+        hardware_address: row.get(1)?,
+        client_id: row.get(2)?,
+        ttl: row.get::<_, SqlTime>(3)?.0,
+        lease_time: row.get(4)?,
+        state: row.get(5)?,
+        started_at: row.get::<_, Option<SqlTime>>(6)?.map(|time| time.0),
+        hostname: row.get(7)?,
+        subnet_id: row.get(8)?,
+        last_packet_at: row.get::<_, Option<SqlTime>>(9)?.map(|time| time.0),
+        relay,
+    })
+}
+
+fn declined_of(row: &Row) -> rusqlite::Result<Declined4Record> {
+    Ok(Declined4Record {
+        ip_addr: Ipv4Addr::from(row.get::<_, u32>(0)?),
+        hardware_address: row.get(1)?,
+        declined_at: row.get::<_, SqlTime>(2)?.0,
+        until: row.get::<_, SqlTime>(3)?.0,
+    })
+}
+
+fn migrate(connection: &mut Connection) -> Result<()> {
+    let version: i64 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    let Ok(version) = usize::try_from(version) else {
+        bail!("invalid schema version {version}");
+    };
+    if version > MIGRATIONS.len() {
+        bail!("schema version {version} is newer than this omoi");
+    }
+    let transaction = connection.transaction()?;
+    for (applied, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", applied as i64 + 1)?;
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+fn get_lease(connection: &Connection, address: &Ipv4Addr) -> Result<Option<Leases4Record>> {
+    let record = connection
+        .query_row(
+            &format!("SELECT {LEASE_COLUMNS} FROM leases4 WHERE ip_addr = ?1"),
+            [u32::from(*address)],
+            lease_of,
+        )
+        .optional()?;
+    Ok(record)
+}
+
+fn get_lease_by_client(connection: &Connection, client: &Client4) -> Result<Option<Leases4Record>> {
+    // client_id が一致するものを優先する
+    if let Some(client_id) = client.client_id {
+        let record = connection
+            .query_row(
+                &format!(
+                    "SELECT {LEASE_COLUMNS} FROM leases4 WHERE client_id = ?1 \
+                     ORDER BY expires_at DESC LIMIT 1"
+                ),
+                [client_id],
+                lease_of,
+            )
+            .optional()?;
+        if record.is_some() {
+            return Ok(record);
+        }
+    }
+    let mut statement = connection.prepare(&format!(
+        "SELECT {LEASE_COLUMNS} FROM leases4 WHERE hardware_address = ?1 \
+         ORDER BY expires_at DESC"
+    ))?;
+    for record in statement.query_map([client.hardware_address], lease_of)? {
+        let record = record?;
+        if client.owns(&record) {
+            return Ok(Some(record));
+        }
+    }
+    Ok(None)
+}
+
+fn put_lease(connection: &Connection, record: &Leases4Record) -> Result<()> {
+    let relay = record.relay.as_ref();
+    connection.execute(
+        &format!(
+            "INSERT OR REPLACE INTO leases4 ({LEASE_COLUMNS}) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)"
+        ),
+        params![
+            u32::from(record.ip_addr),
+            record.hardware_address,
+            record.client_id,
+            SqlTime(record.ttl),
+            record.lease_time,
+            record.state,
+            record.started_at.map(SqlTime),
+            record.hostname,
+            record.subnet_id,
+            record.last_packet_at.map(SqlTime),
+            relay.and_then(|relay| relay.circuit_id.as_ref()),
+            relay.and_then(|relay| relay.remote_id.as_ref()),
+        ],
+    )?;
+    Ok(())
+}
+
+fn get_declined(connection: &Connection, address: &Ipv4Addr) -> Result<Option<Declined4Record>> {
+    let record = connection
+        .query_row(
+            &format!("SELECT {DECLINED_COLUMNS} FROM declined4 WHERE ip_addr = ?1"),
+            [u32::from(*address)],
+            declined_of,
+        )
+        .optional()?;
+    Ok(record)
+}
+
+fn is_available(connection: &Connection, client: &Client4, address: &Ipv4Addr) -> Result<bool> {
+    if get_declined(connection, address)?.is_some_and(|record| record.is_quarantined()) {
+        return Ok(false);
+    }
+    Ok(get_lease(connection, address)?
+        .is_none_or(|record| client.owns(&record) || record.is_expired()))
+}
+
+/// `start` から `end` のうち、一度も払い出していない区間の先頭のアドレス
+fn unused(connection: &Connection, start: Ipv4Addr, end: Ipv4Addr) -> Result<Vec<Ipv4Addr>> {
+    let mut statement = connection.prepare(
+        "SELECT ?1 WHERE NOT EXISTS (SELECT 1 FROM leases4 WHERE ip_addr = ?1)
+         UNION ALL
+         SELECT ip_addr + 1 FROM leases4
+         WHERE ip_addr >= ?1 AND ip_addr < ?2
+           AND NOT EXISTS (SELECT 1 FROM leases4 AS next WHERE next.ip_addr = leases4.ip_addr + 1)
+         ORDER BY 1",
+    )?;
+    let addrs = statement
+        .query_map([u32::from(start), u32::from(end)], |row| {
+            row.get::<_, u32>(0).map(Ipv4Addr::from)
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(addrs)
+}
+
+/// `start` から `end` のうち、期限の切れたアドレス
+fn expired(
+    connection: &Connection,
+    start: Ipv4Addr,
+    end: Ipv4Addr,
+    now: DateTime<Local>,
+) -> Result<Vec<Ipv4Addr>> {
+    let mut statement = connection.prepare(
+        "SELECT ip_addr FROM leases4 WHERE ip_addr BETWEEN ?1 AND ?2 AND expires_at <= ?3
+         ORDER BY ip_addr",
+    )?;
+    let addrs = statement
+        .query_map(
+            params![u32::from(start), u32::from(end), SqlTime(now)],
+            |row| row.get::<_, u32>(0).map(Ipv4Addr::from),
+        )?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(addrs)
+}
+
+#[derive(Debug)]
+struct SqliteLeases {
+    connection: Connection,
+    watchers: Vec<mpsc::UnboundedSender<Lease4Change>>,
+}
+
+impl SqliteLeases {
+    fn notify(&mut self, change: Lease4Change) {
+        self.watchers
+            .retain(|watcher| watcher.send(change.clone()).is_ok());
+    }
+}
+
+/// SQLite に保存するリース
+///
+/// sqlite3 などの手元の道具でそのまま覗いたり集計したりできる
+#[derive(Debug)]
+pub struct SqliteLeaseStore {
+    inner: Mutex<SqliteLeases>,
+}
+
+impl SqliteLeaseStore {
+    pub fn open(path: &Path) -> Result<SqliteLeaseStore> {
+        let connection = Connection::open(path)?;
+        // サーバーが書き込んでいる間も他のプロセスから読めるようにする
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        SqliteLeaseStore::new(connection)
+    }
+
+    #[cfg(test)]
+    pub fn temporary() -> SqliteLeaseStore {
+        SqliteLeaseStore::new(Connection::open_in_memory().expect("open sqlite error"))
+            .expect("migrate sqlite error")
+    }
+
+    fn new(mut connection: Connection) -> Result<SqliteLeaseStore> {
+        migrate(&mut connection)?;
+        Ok(SqliteLeaseStore {
+            inner: Mutex::new(SqliteLeases {
+                connection,
+                watchers: vec![],
+            }),
+        })
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, SqliteLeases>> {
+        self.inner
+            .lock()
+            .map_err(|_| anyhow!("sqlite lease store lock failed"))
+    }
+
+    /// 期限が `range` に入るリースを期限の早い順に返す
+    pub fn expiring(&self, range: impl RangeBounds<DateTime<Local>>) -> Result<Vec<Leases4Record>> {
+        let mut conditions = vec![];
+        let mut times = vec![];
+        match range.start_bound() {
+            Bound::Included(time) => {
+                conditions.push("expires_at >= ?");
+                times.push(SqlTime(*time));
+            }
+            Bound::Excluded(time) => {
+                conditions.push("expires_at > ?");
+                times.push(SqlTime(*time));
+            }
+            Bound::Unbounded => {}
+        }
+        match range.end_bound() {
+            Bound::Included(time) => {
+                conditions.push("expires_at <= ?");
+                times.push(SqlTime(*time));
+            }
+            Bound::Excluded(time) => {
+                conditions.push("expires_at < ?");
+                times.push(SqlTime(*time));
+            }
+            Bound::Unbounded => {}
+        }
+        let filter = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+        let leases = self.lock()?;
+        let mut statement = leases.connection.prepare(&format!(
+            "SELECT {LEASE_COLUMNS} FROM leases4 {filter} ORDER BY expires_at"
+        ))?;
+        let records = statement
+            .query_map(params_from_iter(times), lease_of)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(records)
+    }
+}
+
+impl LeaseStore for SqliteLeaseStore {
+    fn get_by_ip(&self, address: &Ipv4Addr) -> Result<Leases4Record> {
+        let Some(record) = get_lease(&self.lock()?.connection, address)? else {
+            bail!("Empty key {address}");
+        };
+        Ok(record)
+    }
+
+    fn get_by_client(&self, client: &Client4) -> Result<Leases4Record> {
+        let Some(record) = get_lease_by_client(&self.lock()?.connection, client)? else {
+            bail!("Not found");
+        };
+        Ok(record)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Leases4Record> + '_> {
+        let leases = self
+            .expiring(..)
+            .map(|mut leases| {
+                leases.sort_by_key(|record| record.ip_addr);
+                leases
+            })
+            .unwrap_or_default();
+        Box::new(leases.into_iter())
+    }
+
+    fn get_declined(&self, address: &Ipv4Addr) -> Result<Declined4Record> {
+        let Some(record) = get_declined(&self.lock()?.connection, address)? else {
+            bail!("Empty key {address}");
+        };
+        Ok(record)
+    }
+
+    fn iter_declined(&self) -> Box<dyn Iterator<Item = Declined4Record> + '_> {
+        let declined = self
+            .lock()
+            .and_then(|leases| {
+                let mut statement = leases.connection.prepare(&format!(
+                    "SELECT {DECLINED_COLUMNS} FROM declined4 ORDER BY ip_addr"
+                ))?;
+                let records = statement
+                    .query_map([], declined_of)?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                Ok(records)
+            })
+            .unwrap_or_default();
+        Box::new(declined.into_iter())
+    }
+
+    /// 一度も払い出していないアドレスを期限切れのアドレスより先に使う
+    fn allocate(
+        &self,
+        client: &Client4,
+        start: Ipv4Addr,
+        end: Ipv4Addr,
+        hold: Duration,
+        info: &Lease4Info,
+    ) -> Result<Ipv4Addr> {
+        let mut guard = self.lock()?;
+        let leases = &mut *guard;
+        let transaction = leases.connection.transaction()?;
+        // 別のサブネットで払い出したアドレスは使いまわさない
+        let current = get_lease_by_client(&transaction, client)?
+            .map(|record| record.ip_addr)
+            .filter(|addr| (start..=end).contains(addr));
+        let candidates = current
+            .into_iter()
+            .chain(unused(&transaction, start, end)?)
+            .chain(expired(&transaction, start, end, Local::now())?);
+        let mut found = None;
+        for addr in candidates {
+            if is_available(&transaction, client, &addr)? {
+                found = Some(addr);
+                break;
+            }
+        }
+        let Some(addr) = found else {
+            bail!("No empty address");
+        };
+        let offer = Leases4Record::offer(client, addr, Local::now() + hold, info);
+        match Leases4Record::reserve(get_lease(&transaction, &addr)?, client, offer) {
+            Reservation::Write(record) => {
+                put_lease(&transaction, &record)?;
+                transaction.commit()?;
+                leases.notify(Lease4Change::Updated(record));
+            }
+            Reservation::Held => {}
+            Reservation::Taken => bail!("{addr} is taken"),
+        }
+        Ok(addr)
+    }
+
+    fn renew(
+        &self,
+        client: &Client4,
+        ip_addr: Ipv4Addr,
+        lease_time: u32,
+        info: &Lease4Info,
+    ) -> Result<Leases4Record> {
+        let mut guard = self.lock()?;
+        let leases = &mut *guard;
+        let transaction = leases.connection.transaction()?;
+        let record = Leases4Record::renew(
+            get_lease(&transaction, &ip_addr)?,
+            client,
+            Leases4Record::bind(client, ip_addr, lease_time, info),
+        )?;
+        put_lease(&transaction, &record)?;
+        transaction.commit()?;
+        leases.notify(Lease4Change::Updated(record.clone()));
+        Ok(record)
+    }
+
+    fn release(&self, client: &Client4, ip_addr: &Ipv4Addr) -> Result<Leases4Record> {
+        let mut guard = self.lock()?;
+        let leases = &mut *guard;
+        let transaction = leases.connection.transaction()?;
+        let Some(record) = get_lease(&transaction, ip_addr)? else {
+            bail!("Empty key {ip_addr}");
+        };
+        let record = record.release(client)?;
+        put_lease(&transaction, &record)?;
+        transaction.commit()?;
+        leases.notify(Lease4Change::Updated(record.clone()));
+        Ok(record)
+    }
+
+    fn decline(
+        &self,
+        client: &Client4,
+        ip_addr: &Ipv4Addr,
+        until: DateTime<Local>,
+    ) -> Result<Declined4Record> {
+        let mut guard = self.lock()?;
+        let leases = &mut *guard;
+        let transaction = leases.connection.transaction()?;
+        let current = get_lease(&transaction, ip_addr)?;
+        let lease = Leases4Record::decline(current, client, until)?;
+        if let Some(lease) = &lease {
+            put_lease(&transaction, lease)?;
+        }
+        let record = Declined4Record::new(client, *ip_addr, until);
+        transaction.execute(
+            &format!(
+                "INSERT OR REPLACE INTO declined4 ({DECLINED_COLUMNS}) VALUES (?1, ?2, ?3, ?4)"
+            ),
+            params![
+                u32::from(record.ip_addr),
+                record.hardware_address,
+                SqlTime(record.declined_at),
+                SqlTime(record.until),
+            ],
+        )?;
+        transaction.commit()?;
+        if let Some(lease) = lease {
+            leases.notify(Lease4Change::Updated(lease));
+        }
+        Ok(record)
+    }
+
+    fn reclaim(
+        &self,
+        now: DateTime<Local>,
+        batch_size: usize,
+        grace: Option<Duration>,
+    ) -> Result<Vec<Lease4Event>> {
+        let candidates = self.expiring(..=now)?;
+        let mut guard = self.lock()?;
+        let leases = &mut *guard;
+        let transaction = leases.connection.transaction()?;
+        let mut events = vec![];
+        for candidate in candidates {
+            if events.len() >= batch_size {
+                break;
+            }
+            // 読んでから書き換えるまでに更新されているかもしれないので、読み直してから回収する
+            let Some(event) = get_lease(&transaction, &candidate.ip_addr)?
+                .and_then(|record| record.reclaim(now, grace))
+            else {
+                continue;
+            };
+            match &event {
+                Lease4Event::Expired(record) => put_lease(&transaction, record)?,
+                Lease4Event::Removed(record) => {
+                    transaction.execute(
+                        "DELETE FROM leases4 WHERE ip_addr = ?1",
+                        [u32::from(record.ip_addr)],
+                    )?;
+                }
+            }
+            events.push(event);
+        }
+        transaction.commit()?;
+        for event in &events {
+            match event {
+                Lease4Event::Expired(record) => {
+                    leases.notify(Lease4Change::Updated(record.clone()))
+                }
+                Lease4Event::Removed(record) => {
+                    leases.notify(Lease4Change::Removed(record.ip_addr))
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    fn purge_declined(&self, now: DateTime<Local>) -> Result<usize> {
+        let count = self
+            .lock()?
+            .connection
+            .execute("DELETE FROM declined4 WHERE until <= ?1", [SqlTime(now)])?;
+        Ok(count)
+    }
+
+    fn put(&self, record: &Leases4Record) -> Result<()> {
+        let mut leases = self.lock()?;
+        put_lease(&leases.connection, record)?;
+        leases.notify(Lease4Change::Updated(record.clone()));
+        Ok(())
+    }
+
+    fn watch(&self) -> Result<mpsc::UnboundedReceiver<Lease4Change>> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.lock()?.watchers.push(sender);
+        Ok(receiver)
+    }
+}
+
+#[test]
+fn migrate_test() {
+    let mut connection = Connection::open_in_memory().unwrap();
+    migrate(&mut connection).unwrap();
+    // 適用済みのものは適用しない
+    migrate(&mut connection).unwrap();
+    let version: i64 = connection
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(version, MIGRATIONS.len() as i64);
+
+    connection
+        .pragma_update(None, "user_version", MIGRATIONS.len() as i64 + 1)
+        .unwrap();
+    assert!(migrate(&mut connection).is_err());
+}
+
+#[test]
+fn expiring_test() {
+    let leases = SqliteLeaseStore::temporary();
+    let client = Client4 {
+        hardware_address: &[1, 2, 3],
+        client_id: None,
+    };
+    let info = Lease4Info {
+        hostname: Some("host".to_string()),
+        subnet_id: Some(1),
+        relay: Some(Relay4Info {
+            circuit_id: Some(b"port1".to_vec()),
+            remote_id: None,
+        }),
+    };
+    let soon = leases
+        .renew(&client, "192.168.1.2".parse().unwrap(), 60, &info)
+        .unwrap();
+    let later = leases
+        .renew(&client, "192.168.1.1".parse().unwrap(), 3600, &info)
+        .unwrap();
+    assert_eq!(leases.get_by_ip(&later.ip_addr).unwrap(), later);
+
+    let now = Local::now();
+    assert_eq!(
+        leases.expiring(..).unwrap(),
+        vec![soon.clone(), later.clone()]
+    );
+    assert_eq!(
+        leases.expiring(now..now + Duration::minutes(2)).unwrap(),
+        vec![soon.clone()]
+    );
+    assert_eq!(leases.expiring(soon.ttl..=later.ttl).unwrap().len(), 2);
+    assert_eq!(leases.expiring(..soon.ttl).unwrap(), vec![]);
+    assert_eq!(
+        leases
+            .iter()
+            .map(|record| record.ip_addr)
+            .collect::<Vec<_>>(),
+        vec![later.ip_addr, soon.ip_addr]
+    );
+}
@@ -0,0 +1,305 @@
+use std::{fmt::Debug, net::Ipv4Addr};
+
+use anyhow::Result;
+use chrono::{DateTime, Duration, Local};
+use tokio::sync::mpsc;
+
+use super::{Client4, Declined4Record, Lease4Event, Lease4Info, Leases4Record};
+
+/// `LeaseStore::watch` で受け取るリースの変更
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Lease4Change {
+    Updated(Leases4Record),
+    Removed(Ipv4Addr),
+}
+
+/// リースの保存先
+///
+/// ハンドラーや HTTP はこの trait だけを使うので、保存先を増やしてもプロトコルの処理には手を入れずに済む
+pub trait LeaseStore: Send + Sync + Debug {
+    fn get_by_ip(&self, address: &Ipv4Addr) -> Result<Leases4Record>;
+
+    /// `client` のリースを探す
+    ///
+    /// client_id が一致するものを、無ければ chaddr が一致するものを返す
+    fn get_by_client(&self, client: &Client4) -> Result<Leases4Record>;
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Leases4Record> + '_>;
+
+    fn get_declined(&self, address: &Ipv4Addr) -> Result<Declined4Record>;
+
+    fn iter_declined(&self) -> Box<dyn Iterator<Item = Declined4Record> + '_>;
+
+    fn is_quarantined(&self, address: &Ipv4Addr) -> bool {
+        self.get_declined(address)
+            .map(|record| record.is_quarantined())
+            .unwrap_or(false)
+    }
+
+    /// `address` を `client` に払い出してよいか
+    fn is_available(&self, client: &Client4, address: &Ipv4Addr) -> bool {
+        if self.is_quarantined(address) {
+            return false;
+        }
+        match self.get_by_ip(address) {
+            Ok(record) => client.owns(&record) || record.is_expired(),
+            // 壊れているレコードは空きとみなす
+            Err(_) => true,
+        }
+    }
+
+    /// `start` から `end` のうち空いているアドレスを `client` のために `hold` の間確保する
+    ///
+    /// クライアントが既に持っているアドレスを優先する。
+    /// 空いているかの確認と確保は不可分に行うので、同時に呼ばれても同じアドレスを返さない。
+    fn allocate(
+        &self,
+        client: &Client4,
+        start: Ipv4Addr,
+        end: Ipv4Addr,
+        hold: Duration,
+        info: &Lease4Info,
+    ) -> Result<Ipv4Addr>;
+
+    /// `ip_addr` を `client` に `lease_time` 秒払い出す。既に払い出していれば延長する
+    fn renew(
+        &self,
+        client: &Client4,
+        ip_addr: Ipv4Addr,
+        lease_time: u32,
+        info: &Lease4Info,
+    ) -> Result<Leases4Record>;
+
+    /// リースをすぐに期限切れにして `allocate` で再び選ばれるようにする
+    fn release(&self, client: &Client4, ip_addr: &Ipv4Addr) -> Result<Leases4Record>;
+
+    /// リースを取り消し、`until` までアドレスを隔離する
+    fn decline(
+        &self,
+        client: &Client4,
+        ip_addr: &Ipv4Addr,
+        until: DateTime<Local>,
+    ) -> Result<Declined4Record>;
+
+    /// 期限の切れたリースを最大 `batch_size` 件回収する
+    ///
+    /// `grace` が無ければ削除せず、同じクライアントに同じアドレスを払い出せるよう残しておく
+    fn reclaim(
+        &self,
+        now: DateTime<Local>,
+        batch_size: usize,
+        grace: Option<Duration>,
+    ) -> Result<Vec<Lease4Event>>;
+
+    /// 隔離期間の過ぎた DHCPDECLINE の記録を消し、消した数を返す
+    fn purge_declined(&self, now: DateTime<Local>) -> Result<usize>;
+
+    /// `record` をそのまま書き込む。他の DHCP サーバーからリースを移すときに使う
+    fn put(&self, record: &Leases4Record) -> Result<()>;
+
+    /// これ以降のリースの変更を受け取る
+    fn watch(&self) -> Result<mpsc::UnboundedReceiver<Lease4Change>>;
+}
+
+/// どの保存先でも同じように振る舞うか確かめる
+#[cfg(test)]
+fn stores() -> Vec<Box<dyn LeaseStore>> {
+    vec![
+        Box::new(super::leases4::temporary_tree()),
+        Box::new(super::MemoryLeaseStore::new()),
+        #[cfg(feature = "sqlite")]
+        Box::new(super::SqliteLeaseStore::temporary()),
+    ]
+}
+
+#[cfg(test)]
+fn client(hardware_address: &[u8]) -> Client4<'_> {
+    Client4 {
+        hardware_address,
+        client_id: None,
+    }
+}
+
+#[test]
+fn release_test() {
+    use super::Leases4State;
+
+    let info = Lease4Info::default();
+    for leases in stores() {
+        let ip_addr = "192.168.1.1".parse().unwrap();
+        leases
+            .renew(&client(&[1, 2, 3]), ip_addr, 3600, &info)
+            .unwrap();
+
+        assert!(leases.release(&client(&[4, 5, 6]), &ip_addr).is_err());
+        assert!(!leases.get_by_ip(&ip_addr).unwrap().is_expired());
+
+        let released = leases.release(&client(&[1, 2, 3]), &ip_addr).unwrap();
+        assert_eq!(released.state, Leases4State::Released);
+        assert!(leases.get_by_ip(&ip_addr).unwrap().is_expired());
+        assert!(leases.release(&client(&[1, 2, 3]), &ip_addr).is_err());
+        assert_eq!(
+            leases
+                .allocate(
+                    &client(&[7, 8, 9]),
+                    ip_addr,
+                    ip_addr,
+                    Duration::minutes(1),
+                    &info
+                )
+                .unwrap(),
+            ip_addr
+        );
+        assert_eq!(
+            leases.get_by_ip(&ip_addr).unwrap().state,
+            Leases4State::Offered
+        );
+    }
+}
+
+#[test]
+fn renew_test() {
+    let info = Lease4Info::default();
+    for leases in stores() {
+        let ip_addr = "192.168.1.1".parse().unwrap();
+        leases
+            .renew(&client(&[1, 2, 3]), ip_addr, 3600, &info)
+            .unwrap();
+
+        // 他のクライアントの有効なリースは奪えない
+        assert!(leases
+            .renew(&client(&[4, 5, 6]), ip_addr, 3600, &info)
+            .is_err());
+        assert_eq!(
+            leases.get_by_ip(&ip_addr).unwrap().hardware_address,
+            vec![1, 2, 3]
+        );
+
+        leases.release(&client(&[1, 2, 3]), &ip_addr).unwrap();
+        let renewed = leases
+            .renew(&client(&[4, 5, 6]), ip_addr, 3600, &info)
+            .unwrap();
+        assert_eq!(renewed.hardware_address, vec![4, 5, 6]);
+    }
+}
+
+#[test]
+fn decline_test() {
+    use super::Leases4State;
+
+    let info = Lease4Info::default();
+    for leases in stores() {
+        let start = "192.168.1.1".parse().unwrap();
+        let end = "192.168.1.2".parse().unwrap();
+        let ttl = Local::now() + Duration::hours(1);
+        leases
+            .renew(&client(&[1, 2, 3]), start, 3600, &info)
+            .unwrap();
+
+        assert!(leases.decline(&client(&[4, 5, 6]), &start, ttl).is_err());
+        assert!(!leases.is_quarantined(&start));
+
+        leases.decline(&client(&[1, 2, 3]), &start, ttl).unwrap();
+        assert!(leases.is_quarantined(&start));
+        assert_eq!(
+            leases.get_by_ip(&start).unwrap().state,
+            Leases4State::Declined
+        );
+        assert_eq!(leases.iter_declined().count(), 1);
+        assert_eq!(
+            leases
+                .allocate(&client(&[1, 2, 3]), start, end, Duration::minutes(1), &info)
+                .unwrap(),
+            end
+        );
+
+        // 隔離期間が過ぎたら再び払い出せる
+        leases
+            .renew(&client(&[1, 2, 3]), start, 3600, &info)
+            .unwrap_err();
+        leases.renew(&client(&[1, 2, 3]), end, 3600, &info).unwrap();
+        leases
+            .decline(&client(&[1, 2, 3]), &end, Local::now())
+            .unwrap();
+        assert!(!leases.is_quarantined(&end));
+        assert_eq!(
+            leases
+                .allocate(&client(&[1, 2, 3]), end, end, Duration::minutes(1), &info)
+                .unwrap(),
+            end
+        );
+
+        // 隔離期間の過ぎた記録だけを消す
+        assert_eq!(leases.purge_declined(Local::now()).unwrap(), 1);
+        assert_eq!(leases.iter_declined().count(), 1);
+        assert!(leases.is_quarantined(&start));
+
+        // 固定アドレスなどリースの記録が無いアドレスも隔離する
+        let fixed = "192.168.1.3".parse().unwrap();
+        leases.decline(&client(&[4, 5, 6]), &fixed, ttl).unwrap();
+        assert!(leases.is_quarantined(&fixed));
+        assert!(leases.get_by_ip(&fixed).is_err());
+    }
+}
+
+#[test]
+fn client_id_test() {
+    let info = Lease4Info::default();
+    for leases in stores() {
+        let start = "192.168.1.1".parse().unwrap();
+        let end = "192.168.1.2".parse().unwrap();
+        // 同じ chaddr でも client_id が違えば別のクライアント
+        let pxe = Client4 {
+            hardware_address: &[1, 2, 3],
+            client_id: Some(&[1, 1, 2, 3]),
+        };
+        let os = Client4 {
+            client_id: Some(&[0, 0xaa]),
+            ..pxe
+        };
+        leases.renew(&pxe, start, 3600, &info).unwrap();
+
+        assert_eq!(leases.get_by_client(&pxe).unwrap().ip_addr, start);
+        assert!(leases.get_by_client(&os).is_err());
+        // client_id を送らないクライアントは chaddr で見分ける
+        assert_eq!(
+            leases.get_by_client(&client(&[1, 2, 3])).unwrap().ip_addr,
+            start
+        );
+        assert!(!leases.is_available(&os, &start));
+        assert_eq!(
+            leases
+                .allocate(&os, start, end, Duration::minutes(1), &info)
+                .unwrap(),
+            end
+        );
+        assert!(leases.release(&os, &start).is_err());
+
+        leases.renew(&os, end, 3600, &info).unwrap();
+        assert_eq!(leases.get_by_client(&os).unwrap().ip_addr, end);
+    }
+}
+
+#[tokio::test]
+async fn watch_test() {
+    let info = Lease4Info::default();
+    for leases in stores() {
+        let ip_addr = "192.168.1.1".parse().unwrap();
+        let mut changes = leases.watch().unwrap();
+        let record = leases
+            .renew(&client(&[1, 2, 3]), ip_addr, 0, &info)
+            .unwrap();
+        assert_eq!(changes.recv().await, Some(Lease4Change::Updated(record)));
+        leases
+            .reclaim(Local::now(), 10, Some(Duration::zero()))
+            .unwrap();
+        assert!(matches!(
+            changes.recv().await,
+            Some(Lease4Change::Updated(_))
+        ));
+        leases
+            .reclaim(Local::now(), 10, Some(Duration::zero()))
+            .unwrap();
+        assert_eq!(changes.recv().await, Some(Lease4Change::Removed(ip_addr)));
+    }
+}
@@ -0,0 +1,114 @@
+use std::{fmt::Write, net::Ipv4Addr};
+
+use anyhow::{bail, Result};
+
+use super::{never, timestamp};
+use crate::conf::{format_hex, parse_hex};
+use crate::db::{Leases4Record, Leases4State};
+
+/// dnsmasq は値が無いことを `*` と書く
+const NONE: &str = "*";
+
+/// `期限 MAC アドレス IP アドレス ホスト名 client_id` が 1 行に 1 つ並ぶ
+///
+/// DHCPv6 のリースは読み飛ばす
+pub fn parse(text: &str) -> Result<Vec<Leases4Record>> {
+    let mut records = vec![];
+    for line in text.lines() {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let [expiry, hardware_address, ip_addr, hostname, client_id] = fields.as_slice() else {
+            if fields.first().is_some_and(|field| *field == "duid") || fields.is_empty() {
+                continue;
+            }
+            bail!("invalid lease: {line}");
+        };
+        let Ok(ip_addr) = ip_addr.parse::<Ipv4Addr>() else {
+            continue;
+        };
+        // イーサネット以外は `6-00:11:...` のようにハードウェアの種類が付く
+        let hardware_address = hardware_address
+            .split_once('-')
+            .map_or(*hardware_address, |(_, address)| address);
+        let ttl = match expiry.parse()? {
+            0 => never(),
+            expiry => timestamp(expiry)?,
+        };
+        records.push(Leases4Record {
+            hardware_address: parse_hex(hardware_address)?,
+            ip_addr,
+            ttl,
+            client_id: Some(*client_id)
+                .filter(|client_id| *client_id != NONE)
+                .map(parse_hex)
+                .transpose()?,
+            lease_time: None,
+            relay: None,
+            state: Leases4State::Bound,
+            started_at: None,
+            hostname: Some(*hostname)
+                .filter(|hostname| *hostname != NONE)
+                .map(str::to_string),
+            subnet_id: None,
+            last_packet_at: None,
+        });
+    }
+    Ok(records)
+}
+
+/// dnsmasq は払い出しているリースしか持たない
+pub fn export(records: &[Leases4Record]) -> String {
+    let mut text = String::new();
+    for record in records
+        .iter()
+        .filter(|record| record.state == Leases4State::Bound)
+    {
+        let expiry = if record.ttl >= never() {
+            0
+        } else {
+            record.ttl.timestamp()
+        };
+        let _ = writeln!(
+            text,
+            "{expiry} {} {} {} {}",
+            format_hex(&record.hardware_address),
+            record.ip_addr,
+            record.hostname.as_deref().unwrap_or(NONE),
+            record
+                .client_id
+                .as_deref()
+                .map(format_hex)
+                .as_deref()
+                .unwrap_or(NONE),
+        );
+    }
+    text
+}
+
+#[test]
+fn parse_test() {
+    let text = "\
+1672916400 00:11:22:33:44:55 192.168.0.101 host 01:00:11:22:33:44:55
+0 6-00:66:77:88:99:aa 192.168.0.102 * *
+duid 00:01:00:01:2b:4c:5d:6e:00:11:22:33:44:55
+1672916400 1234 fd00::1 * 00:01:00:01
+";
+    let records = parse(text).unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].ttl, timestamp(1_672_916_400).unwrap());
+    assert_eq!(records[0].hostname.as_deref(), Some("host"));
+    assert_eq!(
+        records[1].hardware_address,
+        vec![0, 0x66, 0x77, 0x88, 0x99, 0xaa]
+    );
+    assert_eq!(records[1].ttl, never());
+    assert_eq!(records[1].client_id, None);
+}
+
+#[test]
+fn export_test() {
+    let records = super::test_records();
+    assert_eq!(
+        export(&records),
+        "1672916400 00:11:22:33:44:55 192.168.0.101 host,1 01:00:11:22:33:44:55\n"
+    );
+}
@@ -0,0 +1,258 @@
+use std::{collections::BTreeMap, fmt::Write, net::Ipv4Addr};
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
+
+use super::{never, timestamp};
+use crate::{
+    conf::{format_hex, parse_hex},
+    db::{Leases4Record, Leases4State},
+    isc::{self, Statement, Token},
+};
+
+const TIME_FORMAT: &str = "%Y/%m/%d %H:%M:%S";
+
+/// `4 2023/01/05 10:00:00`, `epoch 1672912800`, `never` のいずれか
+fn parse_time(words: &[&str]) -> Result<DateTime<Local>> {
+    match words {
+        ["never"] => Ok(never()),
+        ["epoch", seconds] => timestamp(seconds.parse()?),
+        [_, date, time] => {
+            let time = NaiveDateTime::parse_from_str(&format!("{date} {time}"), TIME_FORMAT)?;
+            Ok(Utc.from_utc_datetime(&time).with_timezone(&Local))
+        }
+        _ => bail!("invalid time {words:?}"),
+    }
+}
+
+fn format_time(time: &DateTime<Local>) -> String {
+    if *time >= never() {
+        return "never".to_string();
+    }
+    time.with_timezone(&Utc)
+        .format(&format!("%w {TIME_FORMAT}"))
+        .to_string()
+}
+
+#[derive(Default)]
+struct Lease {
+    starts: Option<DateTime<Local>>,
+    ends: Option<DateTime<Local>>,
+    cltt: Option<DateTime<Local>>,
+    state: Option<String>,
+    hardware_address: Option<Vec<u8>>,
+    uid: Option<Vec<u8>>,
+    hostname: Option<String>,
+}
+
+impl Lease {
+    fn parse(&mut self, statement: &Statement) -> Result<()> {
+        let words = statement.words();
+        match (words.as_slice(), statement.tokens.last()) {
+            (["starts", time @ ..], _) => self.starts = Some(parse_time(time)?),
+            (["ends", time @ ..], _) => self.ends = Some(parse_time(time)?),
+            (["cltt", time @ ..], _) => self.cltt = Some(parse_time(time)?),
+            (["binding", "state", state], _) => self.state = Some(state.to_string()),
+            (["hardware", _, hardware_address], _) => {
+                self.hardware_address = Some(parse_hex(hardware_address)?)
+            }
+            (["uid"], Some(Token::Str(uid))) => self.uid = Some(uid.clone()),
+            (["uid", uid], _) => self.uid = Some(parse_hex(uid)?),
+            (["client-hostname"], Some(Token::Str(hostname))) => {
+                self.hostname = Some(String::from_utf8_lossy(hostname).into_owned())
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// 払い出していないアドレスは読み込まない
+    fn into_record(self, ip_addr: Ipv4Addr) -> Option<Leases4Record> {
+        let state = match self.state.as_deref()? {
+            "active" | "bootp" => Leases4State::Bound,
+            "expired" => Leases4State::Expired,
+            "released" => Leases4State::Released,
+            "abandoned" => Leases4State::Declined,
+            "free" => Leases4State::Reclaimed,
+            _ => return None,
+        };
+        let ttl = self.ends.unwrap_or_else(never);
+        let lease_time = self
+            .starts
+            .filter(|_| ttl < never())
+            .and_then(|starts| u32::try_from((ttl - starts).num_seconds()).ok());
+        Some(Leases4Record {
+            hardware_address: self.hardware_address?,
+            ip_addr,
+            ttl,
+            client_id: self.uid,
+            lease_time,
+            relay: None,
+            state,
+            started_at: self.starts,
+            hostname: self.hostname,
+            subnet_id: None,
+            last_packet_at: self.cltt,
+        })
+    }
+}
+
+/// 同じアドレスのリースが何度も書かれていれば、後に書かれたものを使う
+pub fn parse(text: &str) -> Result<Vec<Leases4Record>> {
+    let mut records = BTreeMap::new();
+    for statement in isc::parse(text)? {
+        let words = statement.words();
+        let (["lease", ip_addr], Some(block)) = (words.as_slice(), &statement.block) else {
+            continue;
+        };
+        let ip_addr = ip_addr.parse::<Ipv4Addr>()?;
+        let mut lease = Lease::default();
+        for statement in block.iter().filter(|statement| statement.block.is_none()) {
+            lease.parse(statement)?;
+        }
+        match lease.into_record(ip_addr) {
+            Some(record) => records.insert(ip_addr, record),
+            None => records.remove(&ip_addr),
+        };
+    }
+    Ok(records.into_values().collect())
+}
+
+fn quote(bytes: &[u8]) -> String {
+    let mut quoted = String::from("\"");
+    for &byte in bytes {
+        match byte {
+            b'"' | b'\\' => {
+                quoted.push('\\');
+                quoted.push(byte.into());
+            }
+            0x20..=0x7e => quoted.push(byte.into()),
+            _ => {
+                let _ = write!(quoted, "\\{byte:03o}");
+            }
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+pub fn export(records: &[Leases4Record]) -> String {
+    let mut text = String::from("# omoi lease export\n");
+    for record in records {
+        let state = match record.state {
+            Leases4State::Offered => continue,
+            Leases4State::Bound => "active",
+            Leases4State::Released => "released",
+            Leases4State::Declined => "abandoned",
+            Leases4State::Expired => "expired",
+            Leases4State::Reclaimed | Leases4State::Abandoned => "free",
+        };
+        let _ = writeln!(text, "lease {} {{", record.ip_addr);
+        if let Some(started_at) = &record.started_at {
+            let _ = writeln!(text, "  starts {};", format_time(started_at));
+        }
+        let _ = writeln!(text, "  ends {};", format_time(&record.ttl));
+        if let Some(last_packet_at) = &record.last_packet_at {
+            let _ = writeln!(text, "  cltt {};", format_time(last_packet_at));
+        }
+        let _ = writeln!(text, "  binding state {state};");
+        if !record.hardware_address.is_empty() {
+            let _ = writeln!(
+                text,
+                "  hardware ethernet {};",
+                format_hex(&record.hardware_address)
+            );
+        }
+        if let Some(client_id) = &record.client_id {
+            let _ = writeln!(text, "  uid {};", quote(client_id));
+        }
+        if let Some(hostname) = &record.hostname {
+            let _ = writeln!(text, "  client-hostname {};", quote(hostname.as_bytes()));
+        }
+        text.push_str("}\n");
+    }
+    text
+}
+
+#[test]
+fn parse_test() {
+    let text = r#"
+# The format of this file is documented in the dhcpd.leases(5) manual page.
+authoring-byte-order little-endian;
+server-duid "\000\001\000\001";
+
+failover peer "peer" state {
+  my state normal at 4 2023/01/05 10:00:00;
+}
+lease 192.168.0.101 {
+  starts 4 2023/01/05 10:00:00;
+  ends 4 2023/01/05 11:00:00;
+  cltt 4 2023/01/05 10:00:00;
+  binding state active;
+  next binding state free;
+  rewind binding state free;
+  hardware ethernet 00:11:22:33:44:55;
+  uid "\001\000\021\"3DU";
+  set vendor-class-identifier = "MSFT 5.0";
+  client-hostname "host";
+  on expiry { set ddns-fwd-name = "x"; }
+}
+lease 192.168.0.102 {
+  starts epoch 1672912800; # Thu Jan 05 10:00:00 2023
+  ends never;
+  binding state active;
+  hardware ethernet 00:66:77:88:99:aa;
+  uid 01:00:66:77:88:99:aa;
+}
+lease 192.168.0.103 {
+  binding state free;
+}
+lease 192.168.0.102 {
+  starts epoch 1672912800;
+  ends epoch 1672916400;
+  binding state released;
+  hardware ethernet 00:66:77:88:99:aa;
+}
+"#;
+    let records = parse(text).unwrap();
+    assert_eq!(records.len(), 2);
+    let start = timestamp(1_672_912_800).unwrap();
+    assert_eq!(
+        records[0],
+        Leases4Record {
+            hardware_address: vec![0, 0x11, 0x22, 0x33, 0x44, 0x55],
+            ip_addr: "192.168.0.101".parse().unwrap(),
+            ttl: start + chrono::Duration::hours(1),
+            client_id: Some(vec![1, 0, 0x11, 0x22, 0x33, 0x44, 0x55]),
+            lease_time: Some(3600),
+            relay: None,
+            state: Leases4State::Bound,
+            started_at: Some(start),
+            hostname: Some("host".to_string()),
+            subnet_id: None,
+            last_packet_at: Some(start),
+        }
+    );
+    assert_eq!(records[1].state, Leases4State::Released);
+    assert_eq!(records[1].client_id, None);
+}
+
+#[test]
+fn export_test() {
+    let records = super::test_records();
+    let text = export(&records);
+    assert!(text.contains("  starts 4 2023/01/05 10:00:00;\n"));
+    assert!(text.contains("  uid \"\\001\\000\\021\\\"3DU\";\n"));
+    let parsed = parse(&text).unwrap();
+    assert_eq!(parsed.len(), records.len());
+    for (parsed, record) in parsed.iter().zip(&records) {
+        assert_eq!(
+            Leases4Record {
+                relay: record.relay.clone(),
+                subnet_id: record.subnet_id,
+                ..parsed.clone()
+            },
+            *record
+        );
+    }
+}
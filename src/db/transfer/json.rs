@@ -0,0 +1,30 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::db::Leases4Record;
+
+#[derive(Serialize)]
+struct ExportedLeases<'a> {
+    leases: &'a [Leases4Record],
+}
+
+#[derive(Deserialize)]
+struct ImportedLeases {
+    leases: Vec<Leases4Record>,
+}
+
+pub fn export(records: &[Leases4Record]) -> Result<String> {
+    Ok(serde_json::to_string_pretty(&ExportedLeases {
+        leases: records,
+    })?)
+}
+
+pub fn parse(text: &str) -> Result<Vec<Leases4Record>> {
+    Ok(serde_json::from_str::<ImportedLeases>(text)?.leases)
+}
+
+#[test]
+fn export_test() {
+    let records = super::test_records();
+    assert_eq!(parse(&export(&records).unwrap()).unwrap(), records);
+}
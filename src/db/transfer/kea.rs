@@ -0,0 +1,157 @@
+use std::{collections::HashMap, fmt::Write};
+
+use anyhow::{anyhow, Result};
+use chrono::Duration;
+
+use super::timestamp;
+use crate::conf::{format_hex, parse_hex};
+use crate::db::{Leases4Record, Leases4State};
+
+const HEADER: &str =
+    "address,hwaddr,client_id,valid_lifetime,expire,subnet_id,fqdn_fwd,fqdn_rev,hostname,state,user_context";
+
+/// Kea のリースの状態
+const STATE_DEFAULT: &str = "0";
+const STATE_DECLINED: &str = "1";
+const STATE_EXPIRED_RECLAIMED: &str = "2";
+
+/// Kea は値の中のカンマを `&#x2c` と書く
+fn escape(value: &str) -> String {
+    value.replace(',', "&#x2c")
+}
+
+fn unescape(value: &str) -> String {
+    value.replace("&#x2c", ",")
+}
+
+/// 列の並びは 1 行目の見出しに従う
+pub fn parse(text: &str) -> Result<Vec<Leases4Record>> {
+    let mut lines = text.lines().filter(|line| !line.trim().is_empty());
+    let Some(header) = lines.next() else {
+        return Ok(vec![]);
+    };
+    let columns = header
+        .split(',')
+        .enumerate()
+        .map(|(i, column)| (column.trim(), i))
+        .collect::<HashMap<_, _>>();
+    let mut records = vec![];
+    for line in lines {
+        let fields = line.split(',').collect::<Vec<_>>();
+        let field = |name: &str| {
+            columns
+                .get(name)
+                .and_then(|&i| fields.get(i))
+                .copied()
+                .filter(|field| !field.is_empty())
+        };
+        let required = |name: &str| field(name).ok_or_else(|| anyhow!("{name} is missing: {line}"));
+        let valid_lifetime: u32 = required("valid_lifetime")?.parse()?;
+        let ttl = timestamp(required("expire")?.parse()?)?;
+        let started_at = ttl - Duration::seconds(valid_lifetime.into());
+        let state = match field("state").unwrap_or(STATE_DEFAULT) {
+            STATE_DEFAULT => Leases4State::Bound,
+            STATE_DECLINED => Leases4State::Declined,
+            _ => Leases4State::Reclaimed,
+        };
+        records.push(Leases4Record {
+            hardware_address: field("hwaddr")
+                .map(parse_hex)
+                .transpose()?
+                .unwrap_or_default(),
+            ip_addr: required("address")?.parse()?,
+            ttl,
+            client_id: field("client_id").map(parse_hex).transpose()?,
+            lease_time: Some(valid_lifetime),
+            relay: None,
+            state,
+            started_at: Some(started_at),
+            hostname: field("hostname").map(unescape),
+            subnet_id: None,
+            last_packet_at: Some(started_at),
+        });
+    }
+    Ok(records)
+}
+
+pub fn export(records: &[Leases4Record]) -> String {
+    let mut text = format!("{HEADER}\n");
+    for record in records {
+        let state = match record.state {
+            Leases4State::Offered => continue,
+            Leases4State::Bound => STATE_DEFAULT,
+            Leases4State::Declined => STATE_DECLINED,
+            Leases4State::Released
+            | Leases4State::Expired
+            | Leases4State::Reclaimed
+            | Leases4State::Abandoned => STATE_EXPIRED_RECLAIMED,
+        };
+        let valid_lifetime = record
+            .lease_time
+            .or_else(|| {
+                let started_at = record.started_at?;
+                u32::try_from((record.ttl - started_at).num_seconds()).ok()
+            })
+            .unwrap_or_default();
+        let _ = writeln!(
+            text,
+            "{},{},{},{},{},{},0,0,{},{},",
+            record.ip_addr,
+            format_hex(&record.hardware_address),
+            record
+                .client_id
+                .as_deref()
+                .map(format_hex)
+                .unwrap_or_default(),
+            valid_lifetime,
+            record.ttl.timestamp(),
+            record.subnet_id.unwrap_or_default(),
+            record.hostname.as_deref().map(escape).unwrap_or_default(),
+            state,
+        );
+    }
+    text
+}
+
+#[test]
+fn parse_test() {
+    let text = "\
+address,hwaddr,client_id,valid_lifetime,expire,subnet_id,fqdn_fwd,fqdn_rev,hostname,state,user_context,pool_id
+192.168.0.101,00:11:22:33:44:55,01:00:11:22:33:44:55,3600,1672916400,1,0,0,host&#x2c1,0,,0
+192.168.0.102,,,3600,1672916400,1,0,0,,1,,0
+";
+    let records = parse(text).unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(
+        records[0].hardware_address,
+        vec![0, 0x11, 0x22, 0x33, 0x44, 0x55]
+    );
+    assert_eq!(records[0].hostname.as_deref(), Some("host,1"));
+    assert_eq!(
+        records[0].started_at,
+        Some(timestamp(1_672_912_800).unwrap())
+    );
+    assert_eq!(records[0].state, Leases4State::Bound);
+    assert_eq!(records[1].state, Leases4State::Declined);
+    assert!(records[1].hardware_address.is_empty());
+}
+
+#[test]
+fn export_test() {
+    let records = super::test_records();
+    let text = export(&records);
+    assert!(text.contains(
+        "192.168.0.101,00:11:22:33:44:55,01:00:11:22:33:44:55,3600,1672916400,1,0,0,host&#x2c1,0,\n"
+    ));
+    let parsed = parse(&text).unwrap();
+    assert_eq!(
+        Leases4Record {
+            relay: records[0].relay.clone(),
+            subnet_id: records[0].subnet_id,
+            ..parsed[0].clone()
+        },
+        records[0]
+    );
+    // Kea は解放されたリースを回収済みとして扱う
+    assert_eq!(parsed[1].state, Leases4State::Reclaimed);
+}
@@ -0,0 +1,228 @@
+mod dnsmasq;
+mod isc;
+mod json;
+mod kea;
+
+use std::{collections::HashSet, net::Ipv4Addr};
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Local, TimeZone};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use super::{Client4, LeaseStore, Leases4Record};
+use crate::conf::{format_hex, Dhcp4Config, Dhcp4HostKey};
+
+/// リースを書き出したり読み込んだりする形式
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum LeaseFormat {
+    /// ISC dhcpd の dhcpd.leases
+    Isc,
+    /// Kea の memfile (CSV)
+    Kea,
+    /// dnsmasq の dnsmasq.leases
+    Dnsmasq,
+    /// omoi の JSON, `GET /leases4` と同じ形
+    Json,
+}
+
+impl LeaseFormat {
+    /// `records` を書き出す
+    ///
+    /// 他のサーバーの形式には DHCPOFFER しただけのリースのように、その形式で表せないものは含めない
+    pub fn export(self, records: &[Leases4Record]) -> Result<String> {
+        match self {
+            LeaseFormat::Isc => Ok(isc::export(records)),
+            LeaseFormat::Kea => Ok(kea::export(records)),
+            LeaseFormat::Dnsmasq => Ok(dnsmasq::export(records)),
+            LeaseFormat::Json => json::export(records),
+        }
+    }
+
+    pub fn parse(self, text: &str) -> Result<Vec<Leases4Record>> {
+        match self {
+            LeaseFormat::Isc => isc::parse(text),
+            LeaseFormat::Kea => kea::parse(text),
+            LeaseFormat::Dnsmasq => dnsmasq::parse(text),
+            LeaseFormat::Json => json::parse(text),
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            LeaseFormat::Kea => "text/csv",
+            LeaseFormat::Json => "application/json",
+            LeaseFormat::Isc | LeaseFormat::Dnsmasq => "text/plain",
+        }
+    }
+}
+
+/// 読み込めなかったリース
+#[derive(Serialize, Clone, PartialEq, Eq, Debug)]
+pub struct ImportConflict {
+    pub ip_addr: Ipv4Addr,
+    pub reason: String,
+}
+
+#[derive(Serialize, Clone, PartialEq, Eq, Debug, Default)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub conflicts: Vec<ImportConflict>,
+}
+
+/// 設定したサブネットや予約、既にあるリースと矛盾しないリースだけを `leases` に書き込む
+pub fn import(
+    leases: &dyn LeaseStore,
+    config: &Dhcp4Config,
+    records: Vec<Leases4Record>,
+) -> ImportReport {
+    let mut report = ImportReport::default();
+    let mut seen = HashSet::new();
+    for mut record in records {
+        let ip_addr = record.ip_addr;
+        match check(leases, config, &record) {
+            Ok(subnet_id) if seen.insert(ip_addr) => {
+                record.subnet_id = Some(subnet_id);
+                match leases.put(&record) {
+                    Ok(()) => report.imported += 1,
+                    Err(e) => report.conflicts.push(ImportConflict {
+                        ip_addr,
+                        reason: e.to_string(),
+                    }),
+                }
+            }
+            Ok(_) => report.conflicts.push(ImportConflict {
+                ip_addr,
+                reason: "duplicated in the input".to_string(),
+            }),
+            Err(e) => report.conflicts.push(ImportConflict {
+                ip_addr,
+                reason: e.to_string(),
+            }),
+        }
+    }
+    report
+}
+
+/// `record` を読み込んでよいか確かめ、そのアドレスを含むサブネットの ID を返す
+fn check(leases: &dyn LeaseStore, config: &Dhcp4Config, record: &Leases4Record) -> Result<u32> {
+    let ip_addr = record.ip_addr;
+    let Some(subnet) = config
+        .subnets
+        .iter()
+        .find(|subnet| subnet.contains(&ip_addr))
+    else {
+        bail!("{ip_addr} is not on any configured subnet");
+    };
+    let key = Dhcp4HostKey {
+        hardware_address: &record.hardware_address,
+        client_id: record.client_id.as_deref(),
+        ..Default::default()
+    };
+    let reserved = config
+        .find_host(&key, subnet)
+        .is_some_and(|host| host.fixed_address == ip_addr);
+    if let Some(host) = config.find_host_by_address(&ip_addr).filter(|_| !reserved) {
+        bail!("{ip_addr} is reserved for {}", host.name);
+    }
+    let client = Client4 {
+        hardware_address: &record.hardware_address,
+        client_id: record.client_id.as_deref(),
+    };
+    if let Ok(current) = leases.get_by_ip(&ip_addr) {
+        if !current.is_expired() && !client.owns(&current) {
+            bail!(
+                "{ip_addr} is leased to {}",
+                format_hex(&current.hardware_address)
+            );
+        }
+    }
+    Ok(subnet.id())
+}
+
+/// 期限の無いリースの期限
+fn never() -> DateTime<Local> {
+    Local
+        .with_ymd_and_hms(9999, 12, 31, 0, 0, 0)
+        .single()
+        .expect("never is a valid date")
+}
+
+fn timestamp(seconds: i64) -> Result<DateTime<Local>> {
+    let Some(time) = Local.timestamp_opt(seconds, 0).single() else {
+        bail!("invalid timestamp {seconds}");
+    };
+    Ok(time)
+}
+
+#[cfg(test)]
+fn test_records() -> Vec<Leases4Record> {
+    use super::{Lease4Info, Leases4State, Relay4Info};
+
+    let client = Client4 {
+        hardware_address: &[0, 0x11, 0x22, 0x33, 0x44, 0x55],
+        client_id: Some(&[1, 0, 0x11, 0x22, 0x33, 0x44, 0x55]),
+    };
+    let info = Lease4Info {
+        hostname: Some("host,1".to_string()),
+        subnet_id: Some(1),
+        relay: Some(Relay4Info {
+            circuit_id: Some(b"port1".to_vec()),
+            remote_id: None,
+        }),
+    };
+    let start = timestamp(1_672_912_800).unwrap();
+    let mut bound = Leases4Record::bind(&client, "192.168.0.101".parse().unwrap(), 3600, &info);
+    bound.started_at = Some(start);
+    bound.last_packet_at = Some(start);
+    bound.ttl = start + chrono::Duration::hours(1);
+    let mut released = Leases4Record {
+        ip_addr: "192.168.0.102".parse().unwrap(),
+        hardware_address: vec![0, 0x66, 0x77, 0x88, 0x99, 0xaa],
+        client_id: None,
+        hostname: None,
+        relay: None,
+        ..bound.clone()
+    };
+    released.state = Leases4State::Released;
+    vec![bound, released]
+}
+
+#[test]
+fn import_test() {
+    use super::{Lease4Info, MemoryLeaseStore};
+
+    let leases = MemoryLeaseStore::new();
+    let config = crate::dhcp::v4::test_config().dhcp4;
+    let mut records = test_records();
+    for record in &mut records {
+        record.ttl = Local::now() + chrono::Duration::hours(1);
+    }
+    // 他のクライアントに払い出している
+    let taken = Client4 {
+        hardware_address: &[0, 0, 0, 0x33, 0x33, 0x33],
+        client_id: None,
+    };
+    leases
+        .renew(&taken, records[1].ip_addr, 3600, &Lease4Info::default())
+        .unwrap();
+    let mut outside = records[0].clone();
+    outside.ip_addr = "10.0.0.1".parse().unwrap();
+    let mut reserved = records[0].clone();
+    reserved.ip_addr = "192.168.0.11".parse().unwrap();
+    records.extend([outside, reserved, records[0].clone()]);
+
+    let report = import(&leases, &config, records);
+    assert_eq!(report.imported, 1);
+    assert_eq!(
+        report
+            .conflicts
+            .iter()
+            .map(|conflict| conflict.ip_addr.to_string())
+            .collect::<Vec<_>>(),
+        vec!["192.168.0.102", "10.0.0.1", "192.168.0.11", "192.168.0.101"]
+    );
+    let imported = leases.get_by_ip(&"192.168.0.101".parse().unwrap()).unwrap();
+    assert_eq!(imported.subnet_id, Some(config.subnets[0].id()));
+}
@@ -0,0 +1,40 @@
+use std::ops::Add;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use chrono::{Duration, Local};
+use dhcproto::v4;
+
+use super::{client_of, Context, Handler, Request};
+
+pub struct DeclineHandler;
+
+#[async_trait]
+impl Handler for DeclineHandler {
+    async fn handle(
+        &self,
+        Request {
+            context: Context { leases, config, .. },
+            message,
+            ..
+        }: Request,
+    ) -> Result<()> {
+        let Some(v4::DhcpOption::RequestedIpAddress(ip_addr)) =
+            message.opts().get(v4::OptionCode::RequestedIpAddress)
+        else {
+            bail!("requested ip address is missing xid={}", message.xid());
+        };
+
+        if config.dhcp4.find_subnet(ip_addr).is_none() {
+            bail!("{ip_addr} is not in served subnets xid={}", message.xid());
+        }
+
+        // DHCPDECLINE には応答しない (RFC 2131 4.3.3)
+        let until = Local::now().add(Duration::seconds(
+            config.dhcp4.decline_probation_period.into(),
+        ));
+        leases.decline(&client_of(&message), ip_addr, until)?;
+
+        Ok(())
+    }
+}
@@ -1,79 +1,36 @@
 use std::net::Ipv4Addr;
 
-use anyhow::{bail, ensure, Result};
+use anyhow::Result;
 use async_trait::async_trait;
-use dhcproto::{v4, Encodable, Encoder};
+use chrono::Duration;
+use dhcproto::v4;
 
-use super::{Context, Handler, Request, Transactions};
-use crate::{conf::OmoiConfig, db::Db};
+use super::{client_of, host_key_of, lease_info_of, reply, Context, Handler, Request};
+use crate::conf::{Dhcp4HostConfig, Dhcp4SubnetConfig};
 
 pub struct DiscoverHandler;
 
-pub struct OfferResponse {
-    ip_addr: Ipv4Addr,
-    broadcast_address: Ipv4Addr,
-    subnet_mask: Ipv4Addr,
-    routers: Vec<Ipv4Addr>,
-    domain_name_servers: Vec<Ipv4Addr>,
-    address_lease_time: u32,
-}
-
 #[async_trait]
 impl Handler for DiscoverHandler {
-    async fn handle(
-        &self,
-        Request {
-            context:
-                Context {
-                    db,
-                    config,
-                    transactions,
-                    socket,
-                },
+    async fn handle(&self, request: Request) -> Result<()> {
+        let Request {
+            context: Context { config, .. },
             message,
-        }: Request,
-    ) -> Result<()> {
-        ensure!(message.opts().msg_type() != Some(v4::MessageType::Discover));
+            ..
+        } = &request;
+        let subnet = request.subnet()?;
+        let host = config.dhcp4.find_host(&host_key_of(message), subnet);
 
-        let offer = Self::offer(message.xid(), message.chaddr(), &db, &config, transactions)?;
+        let ip_addr = Self::offer(&request, host, subnet)?;
 
-        let mut resp = v4::Message::default();
-
-        resp.opts_mut()
-            .insert(v4::DhcpOption::MessageType(v4::MessageType::Offer));
-        resp.opts_mut()
-            .insert(v4::DhcpOption::BroadcastAddr(offer.broadcast_address));
-        resp.opts_mut()
-            .insert(v4::DhcpOption::DomainNameServer(offer.domain_name_servers));
-        resp.opts_mut()
-            .insert(v4::DhcpOption::Router(offer.routers));
-        resp.opts_mut()
-            .insert(v4::DhcpOption::AddressLeaseTime(offer.address_lease_time));
-        resp.opts_mut()
-            .insert(v4::DhcpOption::SubnetMask(offer.subnet_mask));
-
-        resp.set_secs(0)
-            .set_ciaddr(0)
-            .set_yiaddr(offer.ip_addr)
-            .set_flags(message.flags())
-            .set_giaddr(message.giaddr())
-            .set_chaddr(message.chaddr())
-            .set_opcode(v4::Opcode::BootReply)
-            .set_htype(message.htype())
-            .set_hops(0)
-            .set_xid(message.xid());
-
-        let mut buffer = Vec::with_capacity(1024);
-        let mut encoder = Encoder::new(&mut buffer);
-        resp.encode(&mut encoder)?;
-
-        let dest = if message.ciaddr().is_unspecified() {
-            message.ciaddr()
-        } else {
-            todo!()
-        };
-
-        socket.send_to(&buffer, (dest, v4::CLIENT_PORT)).await?;
+        let resp = reply::build(
+            &request,
+            v4::MessageType::Offer,
+            ip_addr,
+            Some(subnet),
+            Some(request.lease_time(subnet)),
+        );
+        reply::send(&request, &resp).await?;
 
         Ok(())
     }
@@ -81,42 +38,92 @@ impl Handler for DiscoverHandler {
 
 impl DiscoverHandler {
     fn offer(
-        xid: u32,
-        hardware_address: &[u8],
-        db: &Db,
-        config: &OmoiConfig,
-        transactions: Transactions,
-    ) -> Result<OfferResponse> {
-        let Some(subnet) = config.dhcp4.subnets.first() else {
-            bail!("subnets is empty");
-        };
-        let host = config
-            .dhcp4
-            .hosts
-            .iter()
-            .find(|host| host.hardware_ethernet.bytes().to_vec() == hardware_address);
-        let ip_addr = match host {
-            Some(host) => host.fixed_address,
-            None => {
-                let ip = db.leases_tree()?.suggest(
-                    hardware_address,
-                    subnet.range.0,
-                    subnet.range.1,
-                    transactions.offered_ipv4_addresses()?,
-                )?;
-                transactions.new_transaction(xid, ip)?;
-                ip
-            }
-        };
-        let resp = OfferResponse {
-            ip_addr,
-            broadcast_address: subnet.broadcast_address,
-            subnet_mask: subnet.netmask,
-            domain_name_servers: subnet.domain_name_servers.clone(),
-            address_lease_time: subnet.address_lease_time,
-            routers: subnet.routers.clone(),
-        };
+        request: &Request,
+        host: Option<&Dhcp4HostConfig>,
+        subnet: &Dhcp4SubnetConfig,
+    ) -> Result<Ipv4Addr> {
+        let Request {
+            context:
+                Context {
+                    leases,
+                    config,
+                    transactions,
+                    ..
+                },
+            message,
+            ..
+        } = request;
+        if let Some(host) = host {
+            return Ok(host.fixed_address);
+        }
+        let client = client_of(message);
+        let hold = Duration::seconds(config.dhcp4.offer_hold_time.into());
+        let ip = leases.allocate(
+            &client,
+            subnet.range.0,
+            subnet.range.1,
+            hold,
+            &lease_info_of(message, subnet),
+        )?;
+        transactions.new_transaction(message.xid(), client.hardware_address, ip, hold)?;
+        Ok(ip)
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn concurrent_offer_test() {
+    use super::{transport::MockTransport, PacketInfo};
+    use crate::conf::Dhcp4SubnetConfig;
+    use dhcproto::{Decodable, Decoder};
+    use std::{collections::HashSet, sync::Arc};
 
-        Ok(resp)
+    const CLIENTS: u32 = 2000;
+
+    let mut config = super::test_config();
+    config.dhcp4.subnets = vec![Dhcp4SubnetConfig {
+        subnet: Ipv4Addr::new(10, 0, 0, 0),
+        netmask: Ipv4Addr::new(255, 255, 0, 0),
+        range: (Ipv4Addr::new(10, 0, 0, 10), Ipv4Addr::new(10, 0, 255, 254)),
+        broadcast_address: Ipv4Addr::new(10, 0, 255, 255),
+        ..config.dhcp4.subnets[0].clone()
+    }];
+    let transport = Arc::new(MockTransport::default());
+    let context = Context::for_test(config, transport.clone());
+    let packet_info = PacketInfo {
+        interface_addr: Ipv4Addr::new(10, 0, 0, 1),
+        destination_addr: Ipv4Addr::BROADCAST,
+        interface_index: 1,
+    };
+
+    let tasks = (0..CLIENTS)
+        .map(|i| {
+            let mut message = v4::Message::default();
+            let [_, _, high, low] = i.to_be_bytes();
+            message.set_xid(i).set_chaddr(&[2, 0, 0, 0, high, low]);
+            message
+                .opts_mut()
+                .insert(v4::DhcpOption::MessageType(v4::MessageType::Discover));
+            let request = Request {
+                context: context.clone(),
+                message: Arc::new(message),
+                packet_info: Some(packet_info),
+            };
+            tokio::spawn(async move { DiscoverHandler.handle(request).await })
+        })
+        .collect::<Vec<_>>();
+    for task in tasks {
+        task.await.unwrap().unwrap();
     }
+
+    let sent = transport.sent.lock().unwrap();
+    assert_eq!(sent.len(), CLIENTS as usize);
+    let offered = sent
+        .iter()
+        .map(|(buffer, _)| {
+            v4::Message::decode(&mut Decoder::new(buffer))
+                .unwrap()
+                .yiaddr()
+        })
+        .collect::<HashSet<_>>();
+    assert_eq!(offered.len(), CLIENTS as usize);
 }
@@ -0,0 +1,38 @@
+use std::net::Ipv4Addr;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use dhcproto::v4;
+
+use super::{reply, Context, Handler, Request};
+
+/// アドレスを手動で設定しているクライアントに、リースを伴わずに設定だけを返す
+pub struct InformHandler;
+
+#[async_trait]
+impl Handler for InformHandler {
+    async fn handle(&self, request: Request) -> Result<()> {
+        let Request {
+            context: Context { config, .. },
+            message,
+            ..
+        } = &request;
+        let ciaddr = message.ciaddr();
+        let Some(subnet) = config.dhcp4.find_subnet(&ciaddr) else {
+            bail!("no subnet for ciaddr={ciaddr}");
+        };
+
+        // yiaddr もリース時間も含めない (RFC 2131 4.3.5)
+        let resp = reply::build(
+            &request,
+            v4::MessageType::Ack,
+            Ipv4Addr::UNSPECIFIED,
+            Some(subnet),
+            None,
+        );
+
+        reply::send(&request, &resp).await?;
+
+        Ok(())
+    }
+}
@@ -1,29 +1,57 @@
+mod decline;
 mod discover;
+mod inform;
+mod packet;
+pub mod reclaim;
+mod release;
+mod reply;
 mod request;
+mod router;
+mod subnet;
+mod transaction;
+mod transport;
 
 use crate::{
-    conf::{OmoiConfig, OMOI_CONFIG},
-    db::Db,
+    conf::{Dhcp4HostKey, Dhcp4SubnetConfig, Dhcp4Transport, OmoiConfig, OMOI_CONFIG},
+    db::{Client4, Lease4Change, Lease4Info, LeaseStore, Relay4Info, DB, LEASES4},
 };
 use anyhow::{bail, Result};
 use async_trait::async_trait;
-use chrono::{DateTime, Duration, Local};
 use dhcproto::{
-    v4::{self, Message},
+    v4::{
+        self,
+        relay::{RelayCode, RelayInfo},
+        Message,
+    },
     Decodable, Decoder,
 };
+use nix::sys::{
+    socket::{
+        recvmsg, setsockopt, sockopt::Ipv4PacketInfo, ControlMessageOwned, MsgFlags, SockAddr,
+    },
+    uio::IoVec,
+};
+use once_cell::sync::Lazy;
 use std::{
-    collections::{HashMap, HashSet},
     net::{Ipv4Addr, SocketAddr},
-    ops::Add,
-    sync::{Arc, Mutex},
+    os::unix::io::AsRawFd,
+    sync::Arc,
 };
-use tokio::net::UdpSocket;
+use tokio::{io::Interest, net::UdpSocket, sync::mpsc};
 
-use self::{discover::DiscoverHandler, request::RequestHandler};
+use self::{
+    decline::DeclineHandler,
+    discover::DiscoverHandler,
+    inform::InformHandler,
+    packet::PacketTransport,
+    release::ReleaseHandler,
+    request::RequestHandler,
+    router::Router,
+    transaction::Transactions,
+    transport::{Transport, UdpTransport},
+};
 
 pub const BUFFER_SIZE: usize = 1024;
-pub const TRANSACTION_EXPIRATION_HOURS: i64 = 1;
 
 fn decode(buffer: &[u8]) -> Result<Message> {
     let mut decoder = Decoder::new(buffer);
@@ -31,86 +59,222 @@ fn decode(buffer: &[u8]) -> Result<Message> {
     Ok(message)
 }
 
-pub async fn handle_request(context: Context, buffer: Vec<u8>, _addr: SocketAddr) -> Result<()> {
-    let message = decode(&buffer)?;
-    let request = Request {
-        message: Arc::new(message),
-        context,
+/// `message` を送ってきたクライアント
+pub fn client_of(message: &Message) -> Client4<'_> {
+    let client_id = match message.opts().get(v4::OptionCode::ClientIdentifier) {
+        Some(v4::DhcpOption::ClientIdentifier(client_id)) => Some(client_id.as_slice()),
+        _ => None,
     };
+    Client4 {
+        hardware_address: message.chaddr(),
+        client_id,
+    }
+}
 
-    if let Ok(_) = DiscoverHandler.handle(request.clone()).await {
-        return Ok(());
+/// Relay Agent Information (option 82) のサブオプション
+fn relay_suboption(message: &Message, code: RelayCode) -> Option<&[u8]> {
+    let Some(v4::DhcpOption::RelayAgentInformation(info)) =
+        message.opts().get(v4::OptionCode::RelayAgentInformation)
+    else {
+        return None;
+    };
+    match info.get(code)? {
+        RelayInfo::AgentCircuitId(id) | RelayInfo::AgentRemoteId(id) => Some(id),
+        _ => None,
     }
-    if let Ok(_) = RequestHandler.handle(request).await {
-        return Ok(());
+}
+
+/// 予約やサブネットを探すために `message` から取り出した値
+pub fn host_key_of(message: &Message) -> Dhcp4HostKey<'_> {
+    let client = client_of(message);
+    Dhcp4HostKey {
+        hardware_address: client.hardware_address,
+        client_id: client.client_id,
+        circuit_id: relay_suboption(message, RelayCode::AgentCircuitId),
+        remote_id: relay_suboption(message, RelayCode::AgentRemoteId),
     }
+}
 
-    Ok(())
+/// リースと共に記録するリレーエージェントの情報
+pub fn relay_info_of(message: &Message) -> Option<Relay4Info> {
+    let circuit_id = relay_suboption(message, RelayCode::AgentCircuitId);
+    let remote_id = relay_suboption(message, RelayCode::AgentRemoteId);
+    if circuit_id.is_none() && remote_id.is_none() {
+        return None;
+    }
+    Some(Relay4Info {
+        circuit_id: circuit_id.map(<[u8]>::to_vec),
+        remote_id: remote_id.map(<[u8]>::to_vec),
+    })
 }
 
-#[derive(PartialEq, Eq, Debug)]
-pub struct Transaction {
-    xid: u32,
-    offered_ipv4_addr: Ipv4Addr,
-    created_at: DateTime<Local>,
+/// リースと共に記録するクライアントの情報
+pub fn lease_info_of(message: &Message, subnet: &Dhcp4SubnetConfig) -> Lease4Info {
+    let hostname = match message.opts().get(v4::OptionCode::Hostname) {
+        Some(v4::DhcpOption::Hostname(hostname)) => Some(hostname.clone()),
+        _ => None,
+    };
+    Lease4Info {
+        hostname,
+        subnet_id: Some(subnet.id()),
+        relay: relay_info_of(message),
+    }
 }
 
-#[derive(Clone, Debug)]
-pub struct Transactions(Arc<Mutex<HashMap<u32, Transaction>>>);
+/// IP_PKTINFO から得られる受信パケットの情報
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PacketInfo {
+    /// パケットを受信したインターフェースのアドレス
+    pub interface_addr: Ipv4Addr,
+    /// パケットの宛先アドレス
+    pub destination_addr: Ipv4Addr,
+    /// パケットを受信したインターフェースのインデックス
+    pub interface_index: u32,
+}
 
-impl Transactions {
-    pub fn new() -> Transactions {
-        Transactions(Arc::new(Mutex::new(HashMap::new())))
-    }
-    pub fn new_transaction(&self, xid: u32, offered_ipv4_addr: Ipv4Addr) -> Result<()> {
-        let Ok(mut transactions) = self.0.lock() else {
-            bail!("transactions lock failed");
-        };
-        transactions.insert(
-            xid,
-            Transaction {
-                xid,
-                offered_ipv4_addr,
-                created_at: Local::now(),
-            },
-        );
-        Ok(())
-    }
-    pub fn remove(&self, xid: u32) -> Result<Transaction> {
-        let Ok(mut transactions) = self.0.lock() else {
-            bail!("transactions lock failed");
-        };
-        let Some(transaction) = transactions.remove(&xid) else {
-            bail!("transaction xid={xid} not found");
-        };
-        Ok(transaction)
-    }
-    pub fn offered_ipv4_addresses(&self) -> Result<HashSet<Ipv4Addr>> {
-        let Ok(transactions) = self.0.lock() else {
-            bail!("transaction lock failed");
-        };
-        let now = Local::now();
-        let duration = Duration::hours(TRANSACTION_EXPIRATION_HOURS);
-        Ok(transactions
-            .iter()
-            .filter(|(_, t)| now < t.created_at.add(duration))
-            .map(|(_, t)| t.offered_ipv4_addr)
-            .collect())
+/// 受信したパケットと、そのパケットを受け取ったインターフェースの情報を返す
+async fn recv_from(
+    socket: &UdpSocket,
+    buffer: &mut [u8],
+) -> Result<(usize, SocketAddr, Option<PacketInfo>)> {
+    loop {
+        socket.readable().await?;
+        let received = socket.try_io(Interest::READABLE, || {
+            let mut cmsg_buffer = nix::cmsg_space!(nix::libc::in_pktinfo);
+            let message = recvmsg(
+                socket.as_raw_fd(),
+                &[IoVec::from_mut_slice(buffer)],
+                Some(&mut cmsg_buffer),
+                MsgFlags::empty(),
+            )
+            .map_err(std::io::Error::from)?;
+            let packet_info = message.cmsgs().find_map(|cmsg| match cmsg {
+                ControlMessageOwned::Ipv4PacketInfo(info) => Some(PacketInfo {
+                    interface_addr: Ipv4Addr::from(u32::from_be(info.ipi_spec_dst.s_addr)),
+                    destination_addr: Ipv4Addr::from(u32::from_be(info.ipi_addr.s_addr)),
+                    interface_index: info.ipi_ifindex as u32,
+                }),
+                _ => None,
+            });
+            let addr = match message.address {
+                Some(SockAddr::Inet(addr)) => addr.to_std(),
+                _ => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            };
+            Ok((message.bytes, addr, packet_info))
+        });
+        match received {
+            Ok(received) => return Ok(received),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e.into()),
+        }
     }
 }
 
+/// このサーバーが扱うメッセージとハンドラー
+pub static ROUTER: Lazy<Router> = Lazy::new(router);
+
+fn router() -> Router {
+    Router::builder()
+        .handler(v4::MessageType::Discover, DiscoverHandler)
+        .handler(v4::MessageType::Request, RequestHandler)
+        .handler(v4::MessageType::Release, ReleaseHandler)
+        .handler(v4::MessageType::Decline, DeclineHandler)
+        .handler(v4::MessageType::Inform, InformHandler)
+        .build()
+}
+
+pub async fn handle_request(
+    context: Context,
+    buffer: Vec<u8>,
+    _addr: SocketAddr,
+    packet_info: Option<PacketInfo>,
+) -> Result<()> {
+    let request = |message| Request {
+        message: Arc::new(message),
+        context,
+        packet_info,
+    };
+    ROUTER.route(request, &buffer).await
+}
+
 #[derive(Clone, Debug)]
 pub struct Request {
     pub context: Context,
     pub message: Arc<v4::Message>,
+    pub packet_info: Option<PacketInfo>,
+}
+
+impl Request {
+    /// パケットを受信したインターフェースのアドレス
+    pub fn interface_addr(&self) -> Option<Ipv4Addr> {
+        self.packet_info.map(|info| info.interface_addr)
+    }
+
+    /// ブロードキャストで届いたパケットか
+    ///
+    /// 宛先が分からなければユニキャストとみなす
+    pub fn is_broadcast(&self) -> bool {
+        self.packet_info
+            .is_some_and(|info| info.destination_addr.is_broadcast())
+    }
+
+    /// このパケットに対して使うサブネットを選ぶ
+    pub fn subnet(&self) -> Result<&Dhcp4SubnetConfig> {
+        let Some(subnet) = subnet::select(
+            &self.context.config.dhcp4,
+            &self.message,
+            self.interface_addr(),
+        ) else {
+            bail!("no subnet for xid={}", self.message.xid());
+        };
+        Ok(subnet)
+    }
+
+    /// クライアントに与えるリース時間
+    ///
+    /// option 51 で要求されていればサブネットの上下限に収める
+    pub fn lease_time(&self, subnet: &Dhcp4SubnetConfig) -> u32 {
+        let requested = match self.message.opts().get(v4::OptionCode::AddressLeaseTime) {
+            Some(v4::DhcpOption::AddressLeaseTime(lease_time)) => Some(*lease_time),
+            _ => None,
+        };
+        subnet.lease_time(requested)
+    }
+
+    /// このサーバーを識別するアドレス
+    ///
+    /// サブネット、全体の順に設定を探し、無ければ受信したインターフェースのアドレスを使う
+    pub fn server_identifier(&self) -> Option<Ipv4Addr> {
+        self.server_identifier_in(self.subnet().ok())
+    }
+
+    /// `subnet` のクライアントに応答するときのサーバー識別子
+    pub fn server_identifier_in(&self, subnet: Option<&Dhcp4SubnetConfig>) -> Option<Ipv4Addr> {
+        subnet
+            .and_then(|subnet| subnet.server_identifier)
+            .or(self.context.config.dhcp4.server_identifier)
+            .or_else(|| self.interface_addr())
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct Context {
-    pub db: Db,
+    pub leases: Arc<dyn LeaseStore>,
     pub config: Arc<OmoiConfig>,
     pub transactions: Transactions,
-    pub socket: Arc<UdpSocket>,
+    pub transport: Arc<dyn Transport>,
+}
+
+#[cfg(test)]
+impl Context {
+    pub fn for_test(config: OmoiConfig, transport: Arc<dyn Transport>) -> Context {
+        Context {
+            leases: Arc::new(crate::db::Db::temporary().leases_tree().unwrap()),
+            config: Arc::new(config),
+            transactions: Transactions::new(),
+            transport,
+        }
+    }
 }
 
 #[async_trait]
@@ -118,25 +282,160 @@ pub trait Handler {
     async fn handle(&self, request: Request) -> Result<()>;
 }
 
+/// `debug.log-leases` が有効なときにリースの変更を書き出す
+async fn log_changes(mut changes: mpsc::UnboundedReceiver<Lease4Change>) {
+    while let Some(change) = changes.recv().await {
+        match change {
+            Lease4Change::Updated(record) => eprintln!(
+                "lease {} {:?} until {}",
+                record.ip_addr, record.state, record.ttl
+            ),
+            Lease4Change::Removed(ip_addr) => eprintln!("lease {ip_addr} removed"),
+        }
+    }
+}
+
 pub async fn serve() -> Result<()> {
     let socket = UdpSocket::bind((Ipv4Addr::new(0, 0, 0, 0), v4::SERVER_PORT)).await?;
     socket.set_broadcast(true)?;
+    setsockopt(socket.as_raw_fd(), Ipv4PacketInfo, &true)?;
     let socket = Arc::new(socket);
+    let udp = UdpTransport::new(socket.clone());
+    // 読み込み直しても反映されない設定は起動したときのものを使う
+    let config = OMOI_CONFIG.current();
+    let transport: Arc<dyn Transport> = match config.dhcp4.transport {
+        Dhcp4Transport::Udp => Arc::new(udp),
+        Dhcp4Transport::Packet => Arc::new(PacketTransport::new(udp)?),
+    };
+    let leases = LEASES4.clone();
+    let transactions = if config.dhcp4.persist_offers {
+        Transactions::persistent(DB.offers_tree()?)?
+    } else {
+        Transactions::new()
+    };
+    tokio::spawn(transactions.clone().sweep_every_hold_time());
+    // 回収の間隔や件数は起動したときのまま。変えたときは読み込み直しのログで再起動を促す
+    tokio::spawn(reclaim::reclaim_every(
+        leases.clone(),
+        config.dhcp4.reclaim.clone(),
+    ));
+    if config.log_leases() {
+        tokio::spawn(log_changes(leases.watch()?));
+    }
     let context = Context {
-        db: Db::open(),
-        config: Arc::new(OMOI_CONFIG.clone()),
-        transactions: Transactions::new(),
-        socket,
+        leases,
+        config,
+        transactions,
+        transport,
     };
 
     loop {
-        let context = context.clone();
+        let mut context = context.clone();
         let mut buffer = vec![0u8; BUFFER_SIZE];
-        let (_size, addr) = context.socket.recv_from(&mut buffer).await?;
+        let (_size, addr, packet_info) = recv_from(&socket, &mut buffer).await?;
+        // 受信したときの設定で最後まで処理する
+        context.config = OMOI_CONFIG.current();
         tokio::spawn(async move {
-            if let Err(e) = handle_request(context, buffer, addr).await {
-                eprintln!("{e}");
+            if let Err(e) = handle_request(context, buffer, addr, packet_info).await {
+                eprintln!("{e:#}");
             }
         });
     }
 }
+
+#[cfg(test)]
+pub fn test_config() -> OmoiConfig {
+    toml::from_str(
+        r#"
+[common]
+database-dir = "omoi-db"
+
+[http]
+addr = "0.0.0.0:11003"
+
+[[dhcp4.subnet]]
+subnet = "192.168.0.0"
+netmask = "255.255.255.0"
+range = ["192.168.0.101", "192.168.0.250"]
+domain-name-servers = ["192.168.0.1"]
+routers = ["192.168.0.1"]
+broadcast-address = "192.168.0.255"
+address-lease-time = 3600
+
+[[dhcp4.host]]
+name = "host1"
+hardware-ethernet = "00:00:00:11:11:11"
+fixed-address = "192.168.0.11"
+
+[[dhcp4.host]]
+name = "host2"
+client-id = "01:00:00:00:22:22:22"
+fixed-address = "192.168.0.12"
+
+[[dhcp4.host]]
+name = "host3"
+circuit-id = "70:6f:72:74:33"
+fixed-address = "192.168.0.13"
+"#,
+    )
+    .expect("test config")
+}
+
+#[tokio::test]
+async fn handle_request_test() {
+    use self::{reply::Destination, transport::MockTransport};
+    use dhcproto::{Encodable, Encoder};
+
+    let transport = Arc::new(MockTransport::default());
+    let context = Context::for_test(test_config(), transport.clone());
+    let packet_info = PacketInfo {
+        interface_addr: Ipv4Addr::new(192, 168, 0, 1),
+        destination_addr: Ipv4Addr::BROADCAST,
+        interface_index: 1,
+    };
+    let chaddr = [0, 0, 0, 0x22, 0x22, 0x22];
+    let mut discover = Message::default();
+    discover.set_chaddr(&chaddr);
+    discover
+        .opts_mut()
+        .insert(v4::DhcpOption::MessageType(v4::MessageType::Discover));
+    let mut buffer = Vec::new();
+    discover.encode(&mut Encoder::new(&mut buffer)).unwrap();
+
+    handle_request(
+        context,
+        buffer,
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, v4::CLIENT_PORT)),
+        Some(packet_info),
+    )
+    .await
+    .unwrap();
+
+    let sent = transport.sent.lock().unwrap();
+    assert_eq!(sent.len(), 1);
+    let (buffer, dest) = &sent[0];
+    let offer = decode(buffer).unwrap();
+    assert_eq!(offer.opts().msg_type(), Some(v4::MessageType::Offer));
+    assert_eq!(offer.xid(), discover.xid());
+    assert_eq!(
+        offer.opts().get(v4::OptionCode::ServerIdentifier),
+        Some(&v4::DhcpOption::ServerIdentifier(
+            packet_info.interface_addr
+        ))
+    );
+    assert_eq!(
+        offer.opts().get(v4::OptionCode::Renewal),
+        Some(&v4::DhcpOption::Renewal(1800))
+    );
+    assert_eq!(
+        offer.opts().get(v4::OptionCode::Rebinding),
+        Some(&v4::DhcpOption::Rebinding(3150))
+    );
+    assert_eq!(
+        dest,
+        &Destination::Client {
+            ip_addr: offer.yiaddr(),
+            hardware_address: chaddr.to_vec(),
+        }
+    );
+}
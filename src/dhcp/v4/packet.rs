@@ -0,0 +1,295 @@
+use std::{io, net::Ipv4Addr, os::unix::io::RawFd};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use dhcproto::v4;
+use nix::{
+    ifaddrs::getifaddrs,
+    libc,
+    sys::socket::{
+        sendto, socket, AddressFamily, LinkAddr, MsgFlags, SockAddr, SockFlag, SockType,
+    },
+    unistd::close,
+};
+use tokio::io::unix::AsyncFd;
+
+use super::{
+    reply::Destination,
+    transport::{Transport, UdpTransport},
+    PacketInfo,
+};
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const IPV4_HEADER_LEN: usize = 20;
+const UDP_HEADER_LEN: usize = 8;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const IPPROTO_UDP: u8 = 17;
+const DEFAULT_TTL: u8 = 64;
+
+/// AF_PACKET で Ethernet フレームを組み立てて送る
+///
+/// まだアドレスを持たないクライアントにも ARP を引かずに chaddr 宛てでユニキャストできる。
+/// それ以外の宛先は UDP ソケットで送る。
+#[derive(Debug)]
+pub struct PacketTransport {
+    socket: AsyncFd<RawFd>,
+    fallback: UdpTransport,
+}
+
+impl PacketTransport {
+    pub fn new(fallback: UdpTransport) -> Result<PacketTransport> {
+        let fd = socket(
+            AddressFamily::Packet,
+            SockType::Raw,
+            SockFlag::SOCK_CLOEXEC | SockFlag::SOCK_NONBLOCK,
+            None,
+        )?;
+        let socket = AsyncFd::new(fd).inspect_err(|_| {
+            let _ = close(fd);
+        })?;
+        Ok(PacketTransport { socket, fallback })
+    }
+
+    async fn send_frame(
+        &self,
+        payload: &[u8],
+        ip_addr: Ipv4Addr,
+        hardware_address: [u8; 6],
+        packet_info: PacketInfo,
+    ) -> Result<()> {
+        let ifindex = packet_info.interface_index;
+        let source_mac = hardware_address_of(ifindex)?;
+        let frame = build_frame(
+            source_mac,
+            hardware_address,
+            packet_info.interface_addr,
+            ip_addr,
+            v4::SERVER_PORT,
+            v4::CLIENT_PORT,
+            payload,
+        );
+
+        let mut sll: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+        sll.sll_family = libc::AF_PACKET as u16;
+        sll.sll_protocol = ETHERTYPE_IPV4.to_be();
+        sll.sll_ifindex = ifindex as i32;
+        sll.sll_halen = 6;
+        sll.sll_addr[..6].copy_from_slice(&hardware_address);
+        let addr = SockAddr::Link(LinkAddr(sll));
+        send_when_writable(&self.socket, |fd| {
+            sendto(fd, &frame, &addr, MsgFlags::empty())
+        })
+        .await
+    }
+}
+
+impl Drop for PacketTransport {
+    fn drop(&mut self) {
+        let _ = close(*self.socket.get_ref());
+    }
+}
+
+/// ノンブロッキングのソケットが書き込めるようになるのを待って `send` する
+async fn send_when_writable(
+    socket: &AsyncFd<RawFd>,
+    mut send: impl FnMut(RawFd) -> nix::Result<usize>,
+) -> Result<()> {
+    loop {
+        let mut guard = socket.writable().await?;
+        match guard.try_io(|socket| send(*socket.get_ref()).map_err(io::Error::from)) {
+            Ok(sent) => {
+                sent?;
+                return Ok(());
+            }
+            // 送信バッファが空くまで待ち直す
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for PacketTransport {
+    async fn send(
+        &self,
+        buffer: &[u8],
+        dest: &Destination,
+        packet_info: Option<PacketInfo>,
+    ) -> Result<()> {
+        if let (
+            Destination::Client {
+                ip_addr,
+                hardware_address,
+            },
+            Some(packet_info),
+        ) = (dest, packet_info)
+        {
+            if let Ok(hardware_address) = <[u8; 6]>::try_from(hardware_address.as_slice()) {
+                return self
+                    .send_frame(buffer, *ip_addr, hardware_address, packet_info)
+                    .await;
+            }
+        }
+        self.fallback.send(buffer, dest, packet_info).await
+    }
+}
+
+/// インターフェースの MAC アドレスを調べる
+fn hardware_address_of(ifindex: u32) -> Result<[u8; 6]> {
+    for ifaddr in getifaddrs()? {
+        if let Some(SockAddr::Link(link)) = ifaddr.address {
+            if link.ifindex() == ifindex as usize {
+                return Ok(link.addr());
+            }
+        }
+    }
+    bail!("no hardware address for ifindex={ifindex}");
+}
+
+/// RFC 1071 のチェックサム
+fn checksum(chunks: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for chunk in chunks {
+        let mut words = chunk.chunks_exact(2);
+        for word in &mut words {
+            sum += u32::from(u16::from_be_bytes([word[0], word[1]]));
+        }
+        if let [last] = words.remainder() {
+            sum += u32::from(u16::from_be_bytes([*last, 0]));
+        }
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Ethernet/IPv4/UDP のフレームを組み立てる
+pub fn build_frame(
+    source_mac: [u8; 6],
+    destination_mac: [u8; 6],
+    source_ip: Ipv4Addr,
+    destination_ip: Ipv4Addr,
+    source_port: u16,
+    destination_port: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let udp_len = (UDP_HEADER_LEN + payload.len()) as u16;
+    let ip_len = IPV4_HEADER_LEN as u16 + udp_len;
+
+    let mut ip = Vec::with_capacity(IPV4_HEADER_LEN);
+    ip.push(0x45);
+    ip.push(0);
+    ip.extend_from_slice(&ip_len.to_be_bytes());
+    ip.extend_from_slice(&[0, 0, 0, 0]);
+    ip.push(DEFAULT_TTL);
+    ip.push(IPPROTO_UDP);
+    ip.extend_from_slice(&[0, 0]);
+    ip.extend_from_slice(&source_ip.octets());
+    ip.extend_from_slice(&destination_ip.octets());
+    let ip_checksum = checksum(&[&ip]);
+    ip[10..12].copy_from_slice(&ip_checksum.to_be_bytes());
+
+    let mut udp = Vec::with_capacity(UDP_HEADER_LEN);
+    udp.extend_from_slice(&source_port.to_be_bytes());
+    udp.extend_from_slice(&destination_port.to_be_bytes());
+    udp.extend_from_slice(&udp_len.to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    let mut pseudo_header = Vec::with_capacity(12);
+    pseudo_header.extend_from_slice(&source_ip.octets());
+    pseudo_header.extend_from_slice(&destination_ip.octets());
+    pseudo_header.extend_from_slice(&[0, IPPROTO_UDP]);
+    pseudo_header.extend_from_slice(&udp_len.to_be_bytes());
+    let udp_checksum = match checksum(&[&pseudo_header, &udp, payload]) {
+        // 0 はチェックサム無しを意味するので 0xffff で送る (RFC 768)
+        0 => 0xffff,
+        sum => sum,
+    };
+    udp[6..8].copy_from_slice(&udp_checksum.to_be_bytes());
+
+    let mut frame = Vec::with_capacity(ETHERNET_HEADER_LEN + ip_len as usize);
+    frame.extend_from_slice(&destination_mac);
+    frame.extend_from_slice(&source_mac);
+    frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    frame.extend_from_slice(&ip);
+    frame.extend_from_slice(&udp);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[test]
+fn build_frame_test() {
+    let source_mac = [0x02, 0, 0, 0, 0, 0x01];
+    let destination_mac = [0x02, 0, 0, 0, 0, 0x02];
+    let source_ip = Ipv4Addr::new(192, 168, 0, 1);
+    let destination_ip = Ipv4Addr::new(192, 168, 0, 100);
+    let payload = b"omoi!";
+    let frame = build_frame(
+        source_mac,
+        destination_mac,
+        source_ip,
+        destination_ip,
+        67,
+        68,
+        payload,
+    );
+
+    assert_eq!(frame.len(), 14 + 20 + 8 + payload.len());
+    assert_eq!(frame[0..6], destination_mac);
+    assert_eq!(frame[6..12], source_mac);
+    assert_eq!(frame[12..14], [0x08, 0x00]);
+
+    let ip = &frame[14..34];
+    assert_eq!(ip[0], 0x45);
+    assert_eq!(u16::from_be_bytes([ip[2], ip[3]]), 20 + 8 + 5);
+    assert_eq!(ip[9], 17);
+    assert_eq!(ip[12..16], source_ip.octets());
+    assert_eq!(ip[16..20], destination_ip.octets());
+    assert_eq!(checksum(&[ip]), 0);
+
+    let udp = &frame[34..];
+    assert_eq!(u16::from_be_bytes([udp[0], udp[1]]), 67);
+    assert_eq!(u16::from_be_bytes([udp[2], udp[3]]), 68);
+    assert_eq!(u16::from_be_bytes([udp[4], udp[5]]), 8 + 5);
+    assert_eq!(&udp[8..], payload);
+    let mut pseudo_header = Vec::new();
+    pseudo_header.extend_from_slice(&ip[12..20]);
+    pseudo_header.extend_from_slice(&[0, 17, 0, 13]);
+    assert_eq!(checksum(&[&pseudo_header, udp]), 0);
+}
+
+#[tokio::test]
+async fn send_when_writable_test() {
+    use nix::sys::socket::{recv, send, socketpair};
+    use std::time::Duration;
+
+    let (writer, reader) = socketpair(
+        AddressFamily::Unix,
+        SockType::Datagram,
+        None,
+        SockFlag::SOCK_CLOEXEC | SockFlag::SOCK_NONBLOCK,
+    )
+    .unwrap();
+    // 送信バッファを埋める
+    while send(writer, &[0; 1024], MsgFlags::empty()).is_ok() {}
+
+    let socket = AsyncFd::new(writer).unwrap();
+    let mut sending = tokio::spawn(async move {
+        send_when_writable(&socket, |fd| send(fd, &[1], MsgFlags::empty())).await
+    });
+    // 書き込めるまでスレッドを止めずに待つ
+    assert!(
+        tokio::time::timeout(Duration::from_millis(50), &mut sending)
+            .await
+            .is_err()
+    );
+    let mut buffer = [0; 1024];
+    while recv(reader, &mut buffer, MsgFlags::empty()).is_ok() {}
+    tokio::time::timeout(Duration::from_secs(1), sending)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+
+    let _ = close(writer);
+    let _ = close(reader);
+}
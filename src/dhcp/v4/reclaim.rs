@@ -0,0 +1,92 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use chrono::Local;
+use once_cell::sync::Lazy;
+use tokio::sync::broadcast;
+
+use crate::{
+    conf::Dhcp4ReclaimConfig,
+    db::{Lease4Event, LeaseStore},
+};
+
+/// 受け取り手が読み遅れたときに溜めておく通知の数
+const EVENT_CAPACITY: usize = 1024;
+
+/// 回収したリースの通知
+///
+/// DNS の後始末などをしたければ `subscribe` して受け取る
+pub static LEASE4_EVENTS: Lazy<broadcast::Sender<Lease4Event>> =
+    Lazy::new(|| broadcast::channel(EVENT_CAPACITY).0);
+
+/// 期限の切れたリースを一度回収して通知し、回収した数を返す
+///
+/// 隔離期間の過ぎた DHCPDECLINE の記録もここで消す
+pub fn reclaim(
+    leases: &dyn LeaseStore,
+    config: &Dhcp4ReclaimConfig,
+    events: &broadcast::Sender<Lease4Event>,
+) -> Result<usize> {
+    let grace = config
+        .grace_period
+        .map(|grace| chrono::Duration::seconds(grace.into()));
+    let now = Local::now();
+    leases.purge_declined(now)?;
+    let reclaimed = leases.reclaim(now, config.batch_size, grace)?;
+    let count = reclaimed.len();
+    for event in reclaimed {
+        // 受け取り手がいなければ捨てる
+        let _ = events.send(event);
+    }
+    Ok(count)
+}
+
+/// `config.interval` ごとに期限の切れたリースを回収し続ける
+pub async fn reclaim_every(leases: Arc<dyn LeaseStore>, config: Dhcp4ReclaimConfig) {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval.max(1).into()));
+    loop {
+        interval.tick().await;
+        if let Err(e) = reclaim(leases.as_ref(), &config, &LEASE4_EVENTS) {
+            eprintln!("{e}");
+        }
+    }
+}
+
+#[test]
+fn reclaim_test() {
+    use crate::db::{Client4, Lease4Info, Leases4State, MemoryLeaseStore};
+
+    let leases = MemoryLeaseStore::new();
+    let client = Client4 {
+        hardware_address: &[1, 2, 3],
+        client_id: None,
+    };
+    let expired = "192.168.1.1".parse().unwrap();
+    let active = "192.168.1.2".parse().unwrap();
+    let info = Lease4Info::default();
+    leases.renew(&client, expired, 0, &info).unwrap();
+    leases.renew(&client, active, 3600, &info).unwrap();
+
+    let (sender, mut receiver) = broadcast::channel(EVENT_CAPACITY);
+    let config = Dhcp4ReclaimConfig {
+        grace_period: Some(0),
+        ..Default::default()
+    };
+    assert_eq!(reclaim(&leases, &config, &sender).unwrap(), 1);
+    let Ok(Lease4Event::Expired(record)) = receiver.try_recv() else {
+        panic!("no event");
+    };
+    assert_eq!(record.ip_addr, expired);
+    assert_eq!(
+        leases.get_by_ip(&expired).unwrap().state,
+        Leases4State::Expired
+    );
+
+    assert_eq!(reclaim(&leases, &config, &sender).unwrap(), 1);
+    assert!(matches!(receiver.try_recv(), Ok(Lease4Event::Removed(_))));
+    assert!(leases.get_by_ip(&expired).is_err());
+    assert_eq!(
+        leases.get_by_ip(&active).unwrap().state,
+        Leases4State::Bound
+    );
+}
@@ -0,0 +1,23 @@
+use anyhow::Result;
+use async_trait::async_trait;
+
+use super::{client_of, Context, Handler, Request};
+
+pub struct ReleaseHandler;
+
+#[async_trait]
+impl Handler for ReleaseHandler {
+    async fn handle(
+        &self,
+        Request {
+            context: Context { leases, .. },
+            message,
+            ..
+        }: Request,
+    ) -> Result<()> {
+        // DHCPRELEASE には応答しない (RFC 2131 4.3.4)
+        leases.release(&client_of(&message), &message.ciaddr())?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,503 @@
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use anyhow::Result;
+use dhcproto::{
+    v4::{self, OptionCode},
+    Encodable, Encoder,
+};
+
+use super::{host_key_of, Request};
+use crate::conf::Dhcp4SubnetConfig;
+
+/// BOOTP のヘッダーとマジッククッキーの長さ
+const HEADER_LEN: usize = 240;
+const SNAME_LEN: usize = 64;
+const FILE_LEN: usize = 128;
+/// BOOTP メッセージの最小の長さ (RFC 1542 2.1)
+const MIN_MESSAGE_LEN: usize = 300;
+/// option 57 が無いときにクライアントが受け取れる IP データグラムの長さ (RFC 2131 2)
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 576;
+/// IP ヘッダーと UDP ヘッダーの長さ
+const IP_UDP_HEADER_LEN: usize = 28;
+
+/// 要求されていなくても常に載せるオプション
+///
+/// この順で先頭に並べるので、Message Type と Server Identifier は必ず options に入る
+const ALWAYS_SENT: [OptionCode; 8] = [
+    OptionCode::MessageType,
+    OptionCode::ServerIdentifier,
+    OptionCode::AddressLeaseTime,
+    OptionCode::Renewal,
+    OptionCode::Rebinding,
+    OptionCode::Message,
+    OptionCode::ClientIdentifier,
+    OptionCode::RelayAgentInformation,
+];
+
+/// 応答の宛先 (RFC 2131 4.1)
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Destination {
+    /// リレーエージェント (giaddr のサーバーポート)
+    Relay(Ipv4Addr),
+    /// 既にアドレスを持っているクライアント (ciaddr)
+    Unicast(Ipv4Addr),
+    /// 255.255.255.255
+    Broadcast,
+    /// まだアドレスを設定していないクライアント (chaddr 宛ての yiaddr)
+    Client {
+        ip_addr: Ipv4Addr,
+        hardware_address: Vec<u8>,
+    },
+}
+
+impl Destination {
+    /// `request` に対する `reply` をどこへ送るか決める
+    pub fn of(request: &v4::Message, reply: &v4::Message) -> Destination {
+        if !request.giaddr().is_unspecified() {
+            return Destination::Relay(request.giaddr());
+        }
+        // リレー経由でなければ DHCPNAK は常にブロードキャストする
+        if reply.opts().has_msg_type(v4::MessageType::Nak) {
+            return Destination::Broadcast;
+        }
+        if !request.ciaddr().is_unspecified() {
+            return Destination::Unicast(request.ciaddr());
+        }
+        if request.flags().broadcast() || reply.yiaddr().is_unspecified() {
+            return Destination::Broadcast;
+        }
+        Destination::Client {
+            ip_addr: reply.yiaddr(),
+            hardware_address: request.chaddr().to_vec(),
+        }
+    }
+
+    /// UDP ソケットで送るときの宛先
+    ///
+    /// ARP テーブルに無いクライアントへはユニキャストできないのでブロードキャストで代用する
+    pub fn socket_addr(&self) -> SocketAddrV4 {
+        match self {
+            Destination::Relay(addr) => SocketAddrV4::new(*addr, v4::SERVER_PORT),
+            Destination::Unicast(addr) => SocketAddrV4::new(*addr, v4::CLIENT_PORT),
+            Destination::Broadcast | Destination::Client { .. } => {
+                SocketAddrV4::new(Ipv4Addr::BROADCAST, v4::CLIENT_PORT)
+            }
+        }
+    }
+}
+
+/// `request` に対する応答を組み立てる
+///
+/// サーバー識別子は常に載せる。`subnet` があればその設定を、`lease_time` があればリース時間と T1, T2 も載せる。
+pub fn build(
+    request: &Request,
+    msg_type: v4::MessageType,
+    yiaddr: Ipv4Addr,
+    subnet: Option<&Dhcp4SubnetConfig>,
+    lease_time: Option<u32>,
+) -> v4::Message {
+    let message = &request.message;
+    let mut resp = v4::Message::default();
+
+    resp.opts_mut()
+        .insert(v4::DhcpOption::MessageType(msg_type));
+    // DHCPINFORM では受信したサブネットではなく ciaddr のサブネットを使う
+    let server_identifier = match subnet {
+        Some(subnet) => request.server_identifier_in(Some(subnet)),
+        None => request.server_identifier(),
+    };
+    if let Some(id) = server_identifier {
+        resp.opts_mut().insert(v4::DhcpOption::ServerIdentifier(id));
+    }
+    if let Some(subnet) = subnet {
+        resp.opts_mut()
+            .insert(v4::DhcpOption::SubnetMask(subnet.netmask));
+        resp.opts_mut()
+            .insert(v4::DhcpOption::BroadcastAddr(subnet.broadcast_address));
+        resp.opts_mut()
+            .insert(v4::DhcpOption::Router(subnet.routers.clone()));
+        resp.opts_mut().insert(v4::DhcpOption::DomainNameServer(
+            subnet.domain_name_servers.clone(),
+        ));
+        // 設定された追加のオプションで上書きする
+        let address = if yiaddr.is_unspecified() {
+            message.ciaddr()
+        } else {
+            yiaddr
+        };
+        let vendor_class = match message.opts().get(OptionCode::ClassIdentifier) {
+            Some(v4::DhcpOption::ClassIdentifier(class)) => Some(class.as_slice()),
+            _ => None,
+        };
+        let config = &request.context.config.dhcp4;
+        let host = config.find_host(&host_key_of(message), subnet);
+        for option in config.options_for(subnet, &address, vendor_class, host) {
+            if !ALWAYS_SENT.contains(&OptionCode::from(&option)) {
+                resp.opts_mut().insert(option);
+            }
+        }
+        if let Some(lease_time) = lease_time {
+            resp.opts_mut()
+                .insert(v4::DhcpOption::AddressLeaseTime(lease_time));
+            resp.opts_mut()
+                .insert(v4::DhcpOption::Renewal(subnet.renewal_time(lease_time)));
+            resp.opts_mut()
+                .insert(v4::DhcpOption::Rebinding(subnet.rebinding_time(lease_time)));
+        }
+    }
+
+    // Client Identifier (RFC 6842) と Relay Agent Information (RFC 3046 2.2) はそのまま返す
+    for code in [
+        OptionCode::ClientIdentifier,
+        OptionCode::RelayAgentInformation,
+    ] {
+        if let Some(opt) = message.opts().get(code) {
+            resp.opts_mut().insert(opt.clone());
+        }
+    }
+
+    // Parameter Request List があれば要求されたものだけを返す
+    if let Some(requested) = requested_options(message) {
+        resp.opts_mut()
+            .retain(|code, _| ALWAYS_SENT.contains(code) || requested.contains(code));
+    }
+
+    // DHCPACK 以外では ciaddr を 0 にする (RFC 2131 Table 3)
+    let ciaddr = if msg_type == v4::MessageType::Ack {
+        message.ciaddr()
+    } else {
+        Ipv4Addr::UNSPECIFIED
+    };
+    // リレー経由の DHCPNAK はブロードキャストビットを立てる (RFC 2131 4.3.2)
+    let flags = if msg_type == v4::MessageType::Nak && !message.giaddr().is_unspecified() {
+        message.flags().set_broadcast()
+    } else {
+        message.flags()
+    };
+    resp.set_secs(0)
+        .set_ciaddr(ciaddr)
+        .set_yiaddr(yiaddr)
+        .set_flags(flags)
+        .set_giaddr(message.giaddr())
+        .set_chaddr(message.chaddr())
+        .set_opcode(v4::Opcode::BootReply)
+        .set_htype(message.htype())
+        .set_hops(0)
+        .set_xid(message.xid());
+    resp
+}
+
+/// Parameter Request List (option 55)
+fn requested_options(message: &v4::Message) -> Option<&[OptionCode]> {
+    match message.opts().get(OptionCode::ParameterRequestList) {
+        Some(v4::DhcpOption::ParameterRequestList(codes)) => Some(codes),
+        _ => None,
+    }
+}
+
+/// クライアントが受け取れる DHCP メッセージの長さ
+///
+/// option 57 は IP データグラム全体の長さなので、IP と UDP のヘッダーの分を引く
+fn max_message_size(message: &v4::Message) -> usize {
+    let size = match message.opts().get(OptionCode::MaxMessageSize) {
+        Some(v4::DhcpOption::MaxMessageSize(size)) => {
+            usize::from(*size).max(DEFAULT_MAX_MESSAGE_SIZE)
+        }
+        _ => DEFAULT_MAX_MESSAGE_SIZE,
+    };
+    size - IP_UDP_HEADER_LEN
+}
+
+/// オプションを送る順に並べて個別にエンコードする
+///
+/// 常に載せるもの、Parameter Request List の順、残りはコード順
+fn encode_options(request: &v4::Message, reply: &v4::Message) -> Result<Vec<Vec<u8>>> {
+    let requested = requested_options(request).unwrap_or_default();
+    let mut codes = reply
+        .opts()
+        .iter()
+        .map(|(code, _)| *code)
+        .collect::<Vec<_>>();
+    codes.sort_by_key(|code| {
+        match (
+            ALWAYS_SENT.iter().position(|c| c == code),
+            requested.iter().position(|c| c == code),
+        ) {
+            (Some(i), _) => (0, i),
+            (None, Some(i)) => (1, i),
+            (None, None) => (2, usize::from(u8::from(*code))),
+        }
+    });
+    codes
+        .into_iter()
+        .flat_map(|code| reply.opts().get(code))
+        .map(|opt| {
+            let mut buffer = Vec::new();
+            opt.encode(&mut Encoder::new(&mut buffer))?;
+            Ok(buffer)
+        })
+        .collect()
+}
+
+/// `options` のうち `capacity` バイトに収まるものを前から詰め、End を付ける
+///
+/// 収まらなかったものは `options` に残る
+fn fill(options: &mut Vec<Vec<u8>>, capacity: usize) -> Vec<u8> {
+    let mut area = Vec::new();
+    options.retain(|opt| {
+        // End の 1 バイトを残す
+        if area.len() + opt.len() + 1 > capacity {
+            return true;
+        }
+        area.extend_from_slice(opt);
+        false
+    });
+    area.push(u8::from(OptionCode::End));
+    area
+}
+
+/// `request` のクライアントが受け取れる長さに収まるよう `reply` をエンコードする
+///
+/// options に収まらなければ file, sname の順に溢れさせ、Option Overload (option 52) を付ける
+pub fn encode(request: &v4::Message, reply: &v4::Message) -> Result<Vec<u8>> {
+    let max_size = max_message_size(request);
+    let mut options = encode_options(request, reply)?;
+    let total = options.iter().map(Vec::len).sum::<usize>() + 1;
+
+    let mut header = reply.clone();
+    header.opts_mut().clear();
+    let area = if HEADER_LEN + total <= max_size {
+        fill(&mut options, total)
+    } else {
+        // Option Overload の 3 バイトを残す
+        let mut area = fill(&mut options, max_size - HEADER_LEN - 3);
+        let mut overload = 0;
+        if !options.is_empty() {
+            header.set_fname(&fill(&mut options, FILE_LEN));
+            overload |= 1;
+        }
+        if !options.is_empty() {
+            header.set_sname(&fill(&mut options, SNAME_LEN));
+            overload |= 2;
+        }
+        if !options.is_empty() {
+            eprintln!("{} options dropped xid={}", options.len(), reply.xid());
+        }
+        let mut overload_option = Vec::new();
+        v4::DhcpOption::OptionOverload(overload).encode(&mut Encoder::new(&mut overload_option))?;
+        area.splice(0..0, overload_option);
+        area
+    };
+
+    let mut buffer = Vec::with_capacity(max_size);
+    header.encode(&mut Encoder::new(&mut buffer))?;
+    buffer.extend(area);
+    if buffer.len() < MIN_MESSAGE_LEN {
+        buffer.resize(MIN_MESSAGE_LEN, u8::from(OptionCode::Pad));
+    }
+    Ok(buffer)
+}
+
+/// `request` に対する応答として `reply` を送る
+pub async fn send(request: &Request, reply: &v4::Message) -> Result<()> {
+    let buffer = encode(&request.message, reply)?;
+    let dest = Destination::of(&request.message, reply);
+    request
+        .context
+        .transport
+        .send(&buffer, &dest, request.packet_info)
+        .await
+}
+
+#[test]
+fn destination_test() {
+    let giaddr = Ipv4Addr::new(192, 168, 1, 1);
+    let ciaddr = Ipv4Addr::new(192, 168, 0, 10);
+    let yiaddr = Ipv4Addr::new(192, 168, 0, 100);
+    let chaddr = [0, 0, 0, 0x11, 0x11, 0x11];
+    let reply = |msg_type| {
+        let mut reply = v4::Message::default();
+        reply.set_yiaddr(yiaddr);
+        reply
+            .opts_mut()
+            .insert(v4::DhcpOption::MessageType(msg_type));
+        reply
+    };
+    let mut request = v4::Message::default();
+    request.set_chaddr(&chaddr);
+
+    assert_eq!(
+        Destination::of(&request, &reply(v4::MessageType::Offer)),
+        Destination::Client {
+            ip_addr: yiaddr,
+            hardware_address: chaddr.to_vec(),
+        }
+    );
+    assert_eq!(
+        Destination::of(&request, &reply(v4::MessageType::Nak)),
+        Destination::Broadcast
+    );
+
+    request.set_flags(v4::Flags::default().set_broadcast());
+    assert_eq!(
+        Destination::of(&request, &reply(v4::MessageType::Offer)),
+        Destination::Broadcast
+    );
+
+    request.set_ciaddr(ciaddr);
+    assert_eq!(
+        Destination::of(&request, &reply(v4::MessageType::Ack)),
+        Destination::Unicast(ciaddr)
+    );
+    assert_eq!(
+        Destination::of(&request, &reply(v4::MessageType::Nak)),
+        Destination::Broadcast
+    );
+
+    request.set_giaddr(giaddr);
+    assert_eq!(
+        Destination::of(&request, &reply(v4::MessageType::Ack)),
+        Destination::Relay(giaddr)
+    );
+    assert_eq!(
+        Destination::of(&request, &reply(v4::MessageType::Nak)),
+        Destination::Relay(giaddr)
+    );
+    assert_eq!(
+        Destination::Relay(giaddr).socket_addr(),
+        SocketAddrV4::new(giaddr, v4::SERVER_PORT)
+    );
+}
+
+#[test]
+fn parameter_request_list_test() {
+    use super::{test_config, transport::MockTransport, Context};
+    use std::sync::Arc;
+
+    let config = test_config();
+    let subnet = config.dhcp4.subnets[0].clone();
+    let mut info = v4::relay::RelayAgentInformation::default();
+    info.insert(v4::relay::RelayInfo::AgentCircuitId(b"port1".to_vec()));
+    let mut message = v4::Message::default();
+    message
+        .opts_mut()
+        .insert(v4::DhcpOption::ClientIdentifier(vec![1, 2, 3]));
+    message
+        .opts_mut()
+        .insert(v4::DhcpOption::RelayAgentInformation(info.clone()));
+    message
+        .opts_mut()
+        .insert(v4::DhcpOption::ParameterRequestList(vec![
+            OptionCode::SubnetMask,
+            OptionCode::DomainNameServer,
+        ]));
+    let request = Request {
+        context: Context::for_test(config, Arc::new(MockTransport::default())),
+        message: Arc::new(message),
+        packet_info: None,
+    };
+    let resp = build(
+        &request,
+        v4::MessageType::Ack,
+        Ipv4Addr::new(192, 168, 0, 101),
+        Some(&subnet),
+        Some(3600),
+    );
+    let mut codes = resp
+        .opts()
+        .iter()
+        .map(|(code, _)| u8::from(*code))
+        .collect::<Vec<_>>();
+    codes.sort();
+    assert_eq!(codes, vec![1, 6, 51, 53, 58, 59, 61, 82]);
+    assert_eq!(
+        resp.opts().get(OptionCode::ClientIdentifier),
+        Some(&v4::DhcpOption::ClientIdentifier(vec![1, 2, 3]))
+    );
+    assert_eq!(
+        resp.opts().get(OptionCode::RelayAgentInformation),
+        Some(&v4::DhcpOption::RelayAgentInformation(info))
+    );
+
+    // 要求が無ければ設定されたものをすべて返す
+    let request = Request {
+        message: Arc::new(v4::Message::default()),
+        ..request
+    };
+    let resp = build(
+        &request,
+        v4::MessageType::Ack,
+        Ipv4Addr::new(192, 168, 0, 101),
+        Some(&subnet),
+        Some(3600),
+    );
+    assert!(resp.opts().get(OptionCode::Router).is_some());
+    assert!(resp.opts().get(OptionCode::BroadcastAddr).is_some());
+
+    // 受信したサブネットではなく応答するサブネットのサーバー識別子を載せる
+    let server_identifier = Ipv4Addr::new(192, 168, 10, 1);
+    let other = Dhcp4SubnetConfig {
+        server_identifier: Some(server_identifier),
+        ..subnet
+    };
+    let resp = build(
+        &request,
+        v4::MessageType::Ack,
+        Ipv4Addr::UNSPECIFIED,
+        Some(&other),
+        None,
+    );
+    assert_eq!(
+        resp.opts().get(OptionCode::ServerIdentifier),
+        Some(&v4::DhcpOption::ServerIdentifier(server_identifier))
+    );
+}
+
+#[test]
+fn encode_test() {
+    use dhcproto::{Decodable, Decoder};
+
+    let mut request = v4::Message::default();
+    let mut reply = v4::Message::default();
+    reply
+        .opts_mut()
+        .insert(v4::DhcpOption::MessageType(v4::MessageType::Ack));
+
+    // 最小の長さまで埋める
+    let buffer = encode(&request, &reply).unwrap();
+    assert_eq!(buffer.len(), MIN_MESSAGE_LEN);
+    assert_eq!(buffer[HEADER_LEN..HEADER_LEN + 4], [53, 1, 5, 255]);
+
+    // options に収まらない分は file, sname に溢れさせる
+    let servers = |n| {
+        (0..n)
+            .map(|i| Ipv4Addr::new(10, 0, 0, i))
+            .collect::<Vec<_>>()
+    };
+    reply.opts_mut().insert(v4::DhcpOption::Router(servers(50)));
+    reply
+        .opts_mut()
+        .insert(v4::DhcpOption::TimeServer(servers(15)));
+    reply
+        .opts_mut()
+        .insert(v4::DhcpOption::DomainNameServer(servers(30)));
+    reply
+        .opts_mut()
+        .insert(v4::DhcpOption::NTPServers(servers(15)));
+    let buffer = encode(&request, &reply).unwrap();
+    assert!(buffer.len() <= DEFAULT_MAX_MESSAGE_SIZE - IP_UDP_HEADER_LEN);
+    assert_eq!(buffer[HEADER_LEN..HEADER_LEN + 5], [52, 1, 3, 53, 1]);
+    let file = &buffer[HEADER_LEN - 4 - FILE_LEN..HEADER_LEN - 4];
+    let sname = &buffer[HEADER_LEN - 4 - FILE_LEN - SNAME_LEN..HEADER_LEN - 4 - FILE_LEN];
+    assert_eq!(file[0], 6);
+    assert_eq!(sname[0], 42);
+
+    // option 57 で大きなメッセージを受け取れるなら溢れさせない
+    request
+        .opts_mut()
+        .insert(v4::DhcpOption::MaxMessageSize(1500));
+    let buffer = encode(&request, &reply).unwrap();
+    assert!(buffer.len() > DEFAULT_MAX_MESSAGE_SIZE);
+    let decoded = v4::Message::decode(&mut Decoder::new(&buffer)).unwrap();
+    assert_eq!(decoded.opts(), reply.opts());
+}
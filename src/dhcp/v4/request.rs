@@ -1,91 +1,410 @@
-use std::ops::Add;
+use std::net::Ipv4Addr;
 
-use anyhow::{bail, ensure, Result};
+use anyhow::{bail, Result};
 use async_trait::async_trait;
-use chrono::{Duration, Local};
-use dhcproto::{v4, Encodable, Encoder};
+use dhcproto::v4;
 
-use super::{Context, Handler, Request};
+use super::{client_of, host_key_of, lease_info_of, reply, Context, Handler, Request};
+use crate::{
+    conf::{Dhcp4Config, Dhcp4SubnetConfig},
+    db::LeaseStore,
+};
 
 pub struct RequestHandler;
 
+/// DHCPREQUEST を送ってきたクライアントの状態 (RFC 2131 4.3.2)
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum RequestState {
+    /// DHCPOFFER の中からサーバーを選んだ
+    Selecting {
+        server_identifier: Ipv4Addr,
+        requested: Ipv4Addr,
+    },
+    /// 再起動して以前のアドレスを確認している
+    InitReboot { requested: Ipv4Addr },
+    /// リースを払い出したサーバーにユニキャストで延長を求めている
+    Renewing { ciaddr: Ipv4Addr },
+    /// 任意のサーバーにブロードキャストで延長を求めている
+    Rebinding { ciaddr: Ipv4Addr },
+}
+
+impl RequestState {
+    fn classify(message: &v4::Message, broadcast: bool) -> Option<RequestState> {
+        let server_identifier = match message.opts().get(v4::OptionCode::ServerIdentifier) {
+            Some(v4::DhcpOption::ServerIdentifier(id)) => Some(*id),
+            _ => None,
+        };
+        let requested = match message.opts().get(v4::OptionCode::RequestedIpAddress) {
+            Some(v4::DhcpOption::RequestedIpAddress(ip_addr)) => Some(*ip_addr),
+            _ => None,
+        };
+        let ciaddr = message.ciaddr();
+        match (server_identifier, requested) {
+            (Some(server_identifier), Some(requested)) if ciaddr.is_unspecified() => {
+                Some(RequestState::Selecting {
+                    server_identifier,
+                    requested,
+                })
+            }
+            (None, Some(requested)) if ciaddr.is_unspecified() => {
+                Some(RequestState::InitReboot { requested })
+            }
+            (None, None) if !ciaddr.is_unspecified() && broadcast => {
+                Some(RequestState::Rebinding { ciaddr })
+            }
+            (None, None) if !ciaddr.is_unspecified() => Some(RequestState::Renewing { ciaddr }),
+            _ => None,
+        }
+    }
+}
+
+/// DHCPREQUEST に対する判定
+#[derive(PartialEq, Eq, Debug)]
+enum Verdict {
+    Ack(Ipv4Addr),
+    Nak(String),
+    /// 他のサーバーが選ばれた、あるいはこのクライアントを知らないので応答しない
+    Ignore,
+}
+
 #[async_trait]
 impl Handler for RequestHandler {
-    async fn handle(
-        &self,
-        Request {
+    async fn handle(&self, request: Request) -> Result<()> {
+        let Request {
             context:
                 Context {
-                    db,
+                    leases,
                     config,
                     transactions,
-                    socket,
+                    ..
                 },
             message,
-        }: Request,
-    ) -> Result<()> {
-        ensure!(message.opts().msg_type() != Some(v4::MessageType::Request));
-        let Some(subnet) = config.dhcp4.subnets.first() else {
-            bail!("subnets is empty");
-        };
+            ..
+        } = &request;
+        let subnet = request.subnet()?;
 
-        let ip_addr = match transactions.remove(message.xid()) {
-            Ok(transaction) => transaction.offered_ipv4_addr,
-            Err(_) => {
-                let ip = db.leases_tree()?.suggest(
-                    message.chaddr(),
-                    subnet.range.0,
-                    subnet.range.1,
-                    transactions.offered_ipv4_addresses()?,
-                )?;
-                ip
+        let transaction = transactions.remove(message.xid(), message.chaddr()).ok();
+        let verdict = Self::verdict(
+            message,
+            leases.as_ref(),
+            &config.dhcp4,
+            subnet,
+            request.server_identifier(),
+            transaction.map(|t| t.offered_ipv4_addr),
+            request.is_broadcast(),
+        )?;
+        let ip_addr = match verdict {
+            Verdict::Ack(ip_addr) => ip_addr,
+            Verdict::Nak(reason) => {
+                return Self::nak(&request, reason).await;
             }
+            Verdict::Ignore => return Ok(()),
         };
-        db.leases_tree()?.acquire(
-            message.chaddr().to_vec(),
+        let lease_time = request.lease_time(subnet);
+        leases.renew(
+            &client_of(message),
             ip_addr,
-            Local::now().add(Duration::seconds(subnet.address_lease_time.into())),
+            lease_time,
+            &lease_info_of(message, subnet),
         )?;
 
-        let mut resp = v4::Message::default();
-
-        resp.opts_mut()
-            .insert(v4::DhcpOption::MessageType(v4::MessageType::Ack));
-        resp.opts_mut()
-            .insert(v4::DhcpOption::BroadcastAddr(subnet.broadcast_address));
-        resp.opts_mut().insert(v4::DhcpOption::DomainNameServer(
-            subnet.domain_name_servers.clone(),
-        ));
-        resp.opts_mut()
-            .insert(v4::DhcpOption::Router(subnet.routers.clone()));
-        resp.opts_mut()
-            .insert(v4::DhcpOption::AddressLeaseTime(subnet.address_lease_time));
-        resp.opts_mut()
-            .insert(v4::DhcpOption::SubnetMask(subnet.netmask));
-
-        resp.set_secs(0)
-            .set_ciaddr(0)
-            .set_yiaddr(ip_addr)
-            .set_flags(message.flags())
-            .set_giaddr(message.giaddr())
-            .set_chaddr(message.chaddr())
-            .set_opcode(v4::Opcode::BootReply)
-            .set_htype(message.htype())
-            .set_hops(0)
-            .set_xid(message.xid());
-
-        let mut buffer = Vec::with_capacity(1024);
-        let mut encoder = Encoder::new(&mut buffer);
-        resp.encode(&mut encoder)?;
-
-        let dest = if message.ciaddr().is_unspecified() {
-            message.ciaddr()
-        } else {
-            todo!()
+        let resp = reply::build(
+            &request,
+            v4::MessageType::Ack,
+            ip_addr,
+            Some(subnet),
+            Some(lease_time),
+        );
+
+        reply::send(&request, &resp).await?;
+
+        Ok(())
+    }
+}
+
+impl RequestHandler {
+    /// クライアントの状態に応じて要求を確認する (RFC 2131 4.3.2)
+    fn verdict(
+        message: &v4::Message,
+        leases: &dyn LeaseStore,
+        config: &Dhcp4Config,
+        subnet: &Dhcp4SubnetConfig,
+        server_identifier: Option<Ipv4Addr>,
+        offered: Option<Ipv4Addr>,
+        broadcast: bool,
+    ) -> Result<Verdict> {
+        let Some(state) = RequestState::classify(message, broadcast) else {
+            bail!("malformed request xid={}", message.xid());
         };
+        let client = client_of(message);
+        let host = config.find_host(&host_key_of(message), subnet);
+        match state {
+            RequestState::Selecting {
+                server_identifier: id,
+                requested,
+            } => {
+                if server_identifier.is_some_and(|ours| ours != id) {
+                    return Ok(Verdict::Ignore);
+                }
+                if let Some(offered) = offered {
+                    if offered != requested {
+                        return Ok(Verdict::Nak(format!("{requested} was not offered")));
+                    }
+                }
+                Self::check_address(message, leases, config, subnet, requested)
+            }
+            RequestState::InitReboot { requested } => {
+                if !subnet.contains(&requested) {
+                    return Ok(Verdict::Nak(format!("{requested} is not on this network")));
+                }
+                let lease = leases.get_by_client(&client).ok();
+                if host.is_none() && lease.is_none() {
+                    return Ok(Verdict::Ignore);
+                }
+                if let Some(lease) = lease.filter(|lease| !lease.is_expired()) {
+                    if lease.ip_addr != requested {
+                        return Ok(Verdict::Nak(format!("{requested} is not your address")));
+                    }
+                }
+                Self::check_address(message, leases, config, subnet, requested)
+            }
+            RequestState::Renewing { ciaddr } => {
+                Self::check_address(message, leases, config, subnet, ciaddr)
+            }
+            RequestState::Rebinding { ciaddr } => {
+                let leased = leases
+                    .get_by_ip(&ciaddr)
+                    .is_ok_and(|record| client.owns(&record));
+                let reserved = host.is_some_and(|host| host.fixed_address == ciaddr);
+                // 他のサーバーのリースかもしれないので、知らなければ応答しない
+                if !leased && !reserved {
+                    return Ok(Verdict::Ignore);
+                }
+                Self::check_address(message, leases, config, subnet, ciaddr)
+            }
+        }
+    }
+
+    /// `requested` がこのクライアントに払い出せるアドレスか確認する
+    fn check_address(
+        message: &v4::Message,
+        leases: &dyn LeaseStore,
+        config: &Dhcp4Config,
+        subnet: &Dhcp4SubnetConfig,
+        requested: Ipv4Addr,
+    ) -> Result<Verdict> {
+        if !subnet.contains(&requested) {
+            return Ok(Verdict::Nak(format!("{requested} is not on this network")));
+        }
+
+        if let Some(host) = config.find_host(&host_key_of(message), subnet) {
+            if host.fixed_address != requested {
+                return Ok(Verdict::Nak(format!("{requested} is not reserved for you")));
+            }
+            return Ok(Verdict::Ack(requested));
+        }
+        if config.find_host_by_address(&requested).is_some() {
+            return Ok(Verdict::Nak(format!("{requested} is reserved")));
+        }
+
+        if !(subnet.range.0..=subnet.range.1).contains(&requested) {
+            return Ok(Verdict::Nak(format!("{requested} is out of range")));
+        }
+        if !leases.is_available(&client_of(message), &requested) {
+            return Ok(Verdict::Nak(format!("{requested} is in use")));
+        }
+
+        Ok(Verdict::Ack(requested))
+    }
 
-        socket.send_to(&buffer, (dest, v4::CLIENT_PORT)).await?;
+    async fn nak(request: &Request, reason: String) -> Result<()> {
+        let mut resp = reply::build(
+            request,
+            v4::MessageType::Nak,
+            Ipv4Addr::UNSPECIFIED,
+            None,
+            None,
+        );
+        resp.opts_mut().insert(v4::DhcpOption::Message(reason));
+
+        reply::send(request, &resp).await?;
 
         Ok(())
     }
 }
+
+#[test]
+fn verdict_test() {
+    let leases = crate::db::MemoryLeaseStore::new();
+    let config = super::test_config().dhcp4;
+    let subnet = config.subnets[0].clone();
+    let server = Ipv4Addr::new(192, 168, 0, 1);
+    let select = |chaddr: &[u8], requested: Ipv4Addr| {
+        let mut message = v4::Message::default();
+        message.set_chaddr(chaddr);
+        message
+            .opts_mut()
+            .insert(v4::DhcpOption::ServerIdentifier(server));
+        message
+            .opts_mut()
+            .insert(v4::DhcpOption::RequestedIpAddress(requested));
+        message
+    };
+    let init_reboot = |chaddr: &[u8], requested: Ipv4Addr| {
+        let mut message = select(chaddr, requested);
+        message.opts_mut().remove(v4::OptionCode::ServerIdentifier);
+        message
+    };
+    let renew = |chaddr: &[u8], ciaddr: Ipv4Addr| {
+        let mut message = v4::Message::default();
+        message.set_chaddr(chaddr).set_ciaddr(ciaddr);
+        message
+    };
+    let verdict = |message: &v4::Message, offered, broadcast| {
+        RequestHandler::verdict(
+            message,
+            &leases,
+            &config,
+            &subnet,
+            Some(server),
+            offered,
+            broadcast,
+        )
+        .unwrap()
+    };
+    let client = [0, 0, 0, 0x22, 0x22, 0x22];
+    let other = [0, 0, 0, 0x33, 0x33, 0x33];
+    let host = [0, 0, 0, 0x11, 0x11, 0x11];
+    let ip_addr = Ipv4Addr::new(192, 168, 0, 101);
+    let fixed_address = Ipv4Addr::new(192, 168, 0, 11);
+
+    // SELECTING
+    assert_eq!(
+        verdict(&select(&client, ip_addr), None, true),
+        Verdict::Ack(ip_addr)
+    );
+    assert_eq!(
+        verdict(&select(&client, ip_addr), Some(ip_addr), true),
+        Verdict::Ack(ip_addr)
+    );
+    assert!(matches!(
+        verdict(
+            &select(&client, ip_addr),
+            Some(Ipv4Addr::new(192, 168, 0, 102)),
+            true
+        ),
+        Verdict::Nak(_)
+    ));
+    assert!(matches!(
+        verdict(&select(&client, Ipv4Addr::new(10, 0, 0, 100)), None, true),
+        Verdict::Nak(_)
+    ));
+    assert!(matches!(
+        verdict(&select(&client, fixed_address), None, true),
+        Verdict::Nak(_)
+    ));
+    assert_eq!(
+        verdict(&select(&host, fixed_address), None, true),
+        Verdict::Ack(fixed_address)
+    );
+    let mut message = select(&client, ip_addr);
+    message
+        .opts_mut()
+        .insert(v4::DhcpOption::ServerIdentifier(Ipv4Addr::new(
+            192, 168, 0, 2,
+        )));
+    assert_eq!(verdict(&message, Some(ip_addr), true), Verdict::Ignore);
+
+    // INIT-REBOOT: 知らないクライアントには応答しない
+    assert_eq!(
+        verdict(&init_reboot(&client, ip_addr), None, true),
+        Verdict::Ignore
+    );
+    assert!(matches!(
+        verdict(
+            &init_reboot(&client, Ipv4Addr::new(10, 0, 0, 100)),
+            None,
+            true
+        ),
+        Verdict::Nak(_)
+    ));
+    assert_eq!(
+        verdict(&init_reboot(&host, fixed_address), None, true),
+        Verdict::Ack(fixed_address)
+    );
+
+    leases
+        .renew(
+            &client_of(&renew(&other, ip_addr)),
+            ip_addr,
+            3600,
+            &Default::default(),
+        )
+        .unwrap();
+    assert!(matches!(
+        verdict(&select(&client, ip_addr), None, true),
+        Verdict::Nak(_)
+    ));
+    assert_eq!(
+        verdict(&select(&other, ip_addr), None, true),
+        Verdict::Ack(ip_addr)
+    );
+    assert_eq!(
+        verdict(&init_reboot(&other, ip_addr), None, true),
+        Verdict::Ack(ip_addr)
+    );
+    assert!(matches!(
+        verdict(
+            &init_reboot(&other, Ipv4Addr::new(192, 168, 0, 102)),
+            None,
+            true
+        ),
+        Verdict::Nak(_)
+    ));
+
+    // client-id で予約したホスト
+    let mut message = select(&client, Ipv4Addr::new(192, 168, 0, 12));
+    message
+        .opts_mut()
+        .insert(v4::DhcpOption::ClientIdentifier(vec![
+            1, 0, 0, 0, 0x22, 0x22, 0x22,
+        ]));
+    assert_eq!(
+        verdict(&message, None, true),
+        Verdict::Ack(Ipv4Addr::new(192, 168, 0, 12))
+    );
+    assert!(matches!(
+        verdict(&select(&client, Ipv4Addr::new(192, 168, 0, 12)), None, true),
+        Verdict::Nak(_)
+    ));
+
+    // リレーエージェントのポートで予約したホスト
+    let mut info = v4::relay::RelayAgentInformation::default();
+    info.insert(v4::relay::RelayInfo::AgentCircuitId(b"port3".to_vec()));
+    let mut message = select(&client, Ipv4Addr::new(192, 168, 0, 13));
+    message
+        .opts_mut()
+        .insert(v4::DhcpOption::RelayAgentInformation(info));
+    assert_eq!(
+        verdict(&message, None, true),
+        Verdict::Ack(Ipv4Addr::new(192, 168, 0, 13))
+    );
+
+    // RENEWING / REBINDING
+    assert_eq!(
+        verdict(&renew(&other, ip_addr), None, false),
+        Verdict::Ack(ip_addr)
+    );
+    assert_eq!(
+        verdict(&renew(&other, ip_addr), None, true),
+        Verdict::Ack(ip_addr)
+    );
+    assert!(matches!(
+        verdict(&renew(&client, ip_addr), None, false),
+        Verdict::Nak(_)
+    ));
+    assert_eq!(
+        verdict(&renew(&client, ip_addr), None, true),
+        Verdict::Ignore
+    );
+}
@@ -0,0 +1,203 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{anyhow, Context as _, Result};
+use dhcproto::v4;
+
+use super::{decode, Handler, Request};
+
+/// 受け取ったメッセージの件数
+#[derive(Default, Debug)]
+pub struct RouterStats {
+    /// デコードできなかった、または DHCP Message Type の無いメッセージ
+    pub malformed: AtomicU64,
+    /// 扱うハンドラーの無いメッセージ
+    pub unknown: AtomicU64,
+    handled: HashMap<v4::MessageType, AtomicU64>,
+    failed: HashMap<v4::MessageType, AtomicU64>,
+}
+
+impl RouterStats {
+    /// `message_type` のハンドラーが正常に処理した件数
+    pub fn handled(&self, message_type: v4::MessageType) -> u64 {
+        self.handled
+            .get(&message_type)
+            .map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+
+    /// `message_type` のハンドラーがエラーを返した件数
+    pub fn failed(&self, message_type: v4::MessageType) -> u64 {
+        self.failed
+            .get(&message_type)
+            .map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+
+    /// ハンドラーのあるメッセージの種類ごとに、処理した件数とエラーの件数を返す
+    pub fn by_message_type(&self) -> Vec<(v4::MessageType, u64, u64)> {
+        let mut counts = self
+            .handled
+            .keys()
+            .map(|message_type| {
+                (
+                    *message_type,
+                    self.handled(*message_type),
+                    self.failed(*message_type),
+                )
+            })
+            .collect::<Vec<_>>();
+        counts.sort_by_key(|(message_type, _, _)| u8::from(*message_type));
+        counts
+    }
+}
+
+/// DHCP Message Type (option 53) ごとにハンドラーを振り分ける
+pub struct Router {
+    handlers: HashMap<v4::MessageType, Arc<dyn Handler + Send + Sync>>,
+    stats: RouterStats,
+}
+
+impl Router {
+    pub fn builder() -> RouterBuilder {
+        RouterBuilder::default()
+    }
+
+    pub fn stats(&self) -> &RouterStats {
+        &self.stats
+    }
+
+    /// `buffer` をデコードして、メッセージの種類に対応するハンドラーに渡す
+    ///
+    /// 壊れたメッセージやハンドラーの無いメッセージは数えて捨てる
+    pub async fn route(
+        &self,
+        request: impl FnOnce(v4::Message) -> Request,
+        buffer: &[u8],
+    ) -> Result<()> {
+        let message = match decode(buffer) {
+            Ok(message) => message,
+            Err(e) => {
+                self.stats.malformed.fetch_add(1, Ordering::Relaxed);
+                return Err(e.context("malformed message"));
+            }
+        };
+        let Some(message_type) = message.opts().msg_type() else {
+            self.stats.malformed.fetch_add(1, Ordering::Relaxed);
+            return Err(anyhow!("no message type xid={}", message.xid()));
+        };
+        let Some(handler) = self.handlers.get(&message_type) else {
+            self.stats.unknown.fetch_add(1, Ordering::Relaxed);
+            eprintln!("ignored {message_type:?} xid={}: no handler", message.xid());
+            return Ok(());
+        };
+
+        let xid = message.xid();
+        let result = handler.handle(request(message)).await;
+        let counts = match result {
+            Ok(_) => &self.stats.handled,
+            Err(_) => &self.stats.failed,
+        };
+        if let Some(count) = counts.get(&message_type) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+        result.with_context(|| format!("{message_type:?} xid={xid}"))
+    }
+}
+
+#[derive(Default)]
+pub struct RouterBuilder {
+    handlers: HashMap<v4::MessageType, Arc<dyn Handler + Send + Sync>>,
+}
+
+impl RouterBuilder {
+    /// `message_type` のハンドラーを登録する。既に登録されていれば置き換える
+    pub fn handler(
+        mut self,
+        message_type: v4::MessageType,
+        handler: impl Handler + Send + Sync + 'static,
+    ) -> RouterBuilder {
+        self.handlers.insert(message_type, Arc::new(handler));
+        self
+    }
+
+    pub fn build(self) -> Router {
+        let counters = || {
+            self.handlers
+                .keys()
+                .map(|message_type| (*message_type, AtomicU64::new(0)))
+                .collect()
+        };
+        Router {
+            stats: RouterStats {
+                handled: counters(),
+                failed: counters(),
+                ..Default::default()
+            },
+            handlers: self.handlers,
+        }
+    }
+}
+
+#[tokio::test]
+async fn route_test() {
+    use super::{test_config, transport::MockTransport, Context};
+    use async_trait::async_trait;
+    use dhcproto::{Encodable, Encoder};
+
+    struct Failing;
+
+    #[async_trait]
+    impl Handler for Failing {
+        async fn handle(&self, _request: Request) -> Result<()> {
+            Err(anyhow!("failed"))
+        }
+    }
+
+    let transport = Arc::new(MockTransport::default());
+    let context = Context::for_test(test_config(), transport.clone());
+    let router = Router::builder()
+        .handler(v4::MessageType::Release, Failing)
+        .build();
+    let request = |message| Request {
+        context: context.clone(),
+        message: Arc::new(message),
+        packet_info: None,
+    };
+    let encode = |message_type: Option<v4::MessageType>| {
+        let mut message = v4::Message::default();
+        if let Some(message_type) = message_type {
+            message
+                .opts_mut()
+                .insert(v4::DhcpOption::MessageType(message_type));
+        }
+        let mut buffer = Vec::new();
+        message.encode(&mut Encoder::new(&mut buffer)).unwrap();
+        buffer
+    };
+
+    assert!(router.route(request, &[0; 8]).await.is_err());
+    assert!(router.route(request, &encode(None)).await.is_err());
+    assert_eq!(router.stats().malformed.load(Ordering::Relaxed), 2);
+
+    router
+        .route(request, &encode(Some(v4::MessageType::Offer)))
+        .await
+        .unwrap();
+    assert_eq!(router.stats().unknown.load(Ordering::Relaxed), 1);
+
+    // ハンドラーのエラーは握り潰さず、種類ごとに数える
+    let e = router
+        .route(request, &encode(Some(v4::MessageType::Release)))
+        .await
+        .unwrap_err();
+    assert!(format!("{e:#}").contains("Release"));
+    assert_eq!(
+        router.stats().by_message_type(),
+        vec![(v4::MessageType::Release, 0, 1)]
+    );
+    assert!(transport.sent.lock().unwrap().is_empty());
+}
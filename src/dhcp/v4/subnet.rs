@@ -0,0 +1,142 @@
+use std::net::Ipv4Addr;
+
+use dhcproto::v4::{
+    self,
+    relay::{RelayCode, RelayInfo},
+};
+
+use super::host_key_of;
+use crate::conf::{Dhcp4Config, Dhcp4SubnetConfig};
+
+/// クライアントやリレーエージェントがサブネットを明示していればそのアドレスを返す
+///
+/// 1. Subnet Selection (option 118, RFC 3011)
+/// 2. Relay Agent Information の Link Selection (option 82 suboption 5, RFC 3527)
+fn explicit_selector(message: &v4::Message) -> Option<Ipv4Addr> {
+    if let Some(v4::DhcpOption::SubnetSelection(addr)) =
+        message.opts().get(v4::OptionCode::SubnetSelection)
+    {
+        return Some(*addr);
+    }
+    if let Some(v4::DhcpOption::RelayAgentInformation(info)) =
+        message.opts().get(v4::OptionCode::RelayAgentInformation)
+    {
+        if let Some(RelayInfo::LinkSelection(addr)) = info.get(RelayCode::LinkSelection) {
+            return Some(*addr);
+        }
+    }
+    None
+}
+
+/// パケットごとにサブネットを選ぶための手がかりとなるアドレスを返す
+///
+/// 明示されたアドレス, giaddr (リレー経由), 受信したインターフェースのアドレス (直接受信) の順に使う
+pub fn selector(message: &v4::Message, interface_addr: Option<Ipv4Addr>) -> Option<Ipv4Addr> {
+    if let Some(addr) = explicit_selector(message) {
+        return Some(addr);
+    }
+    if !message.giaddr().is_unspecified() {
+        return Some(message.giaddr());
+    }
+    interface_addr
+}
+
+/// パケットに対して使うサブネットを返す
+///
+/// サブネットが明示されていなければ、リレーエージェントのポート (circuit-id, remote-id) に
+/// 割り当てたサブネットを giaddr より優先する。
+/// 一致するサブネットが無ければ、優先度の低い手がかりには頼らず応答しない (RFC 3011)
+pub fn select<'a>(
+    config: &'a Dhcp4Config,
+    message: &v4::Message,
+    interface_addr: Option<Ipv4Addr>,
+) -> Option<&'a Dhcp4SubnetConfig> {
+    if explicit_selector(message).is_none() {
+        if let Some(subnet) = config.find_subnet_by_relay(&host_key_of(message)) {
+            return Some(subnet);
+        }
+    }
+    let addr = selector(message, interface_addr)?;
+    config.find_subnet(&addr)
+}
+
+#[test]
+fn select_test() {
+    use dhcproto::v4::relay::RelayAgentInformation;
+
+    let mut config = super::test_config().dhcp4;
+    config.subnets = (0..4)
+        .map(|octet| Dhcp4SubnetConfig {
+            subnet: Ipv4Addr::new(192, 168, octet, 0),
+            broadcast_address: Ipv4Addr::new(192, 168, octet, 255),
+            range: (
+                Ipv4Addr::new(192, 168, octet, 100),
+                Ipv4Addr::new(192, 168, octet, 200),
+            ),
+            ..config.subnets[0].clone()
+        })
+        .collect();
+    config.subnets[0].circuit_id = Some(b"port0".to_vec());
+    let selected = |message: &v4::Message, interface_addr| {
+        select(&config, message, interface_addr).map(|subnet| subnet.subnet)
+    };
+
+    let mut message = v4::Message::default();
+    assert_eq!(selected(&message, None), None);
+    assert_eq!(
+        selected(&message, Some(Ipv4Addr::new(192, 168, 0, 1))),
+        Some(Ipv4Addr::new(192, 168, 0, 0))
+    );
+
+    message.set_giaddr(Ipv4Addr::new(192, 168, 1, 1));
+    assert_eq!(
+        selected(&message, Some(Ipv4Addr::new(192, 168, 0, 1))),
+        Some(Ipv4Addr::new(192, 168, 1, 0))
+    );
+
+    // ポートに割り当てたサブネットは giaddr より優先する
+    let mut info = RelayAgentInformation::default();
+    info.insert(RelayInfo::AgentCircuitId(b"port1".to_vec()));
+    message
+        .opts_mut()
+        .insert(v4::DhcpOption::RelayAgentInformation(info.clone()));
+    assert_eq!(
+        selected(&message, Some(Ipv4Addr::new(192, 168, 0, 1))),
+        Some(Ipv4Addr::new(192, 168, 1, 0))
+    );
+    info.insert(RelayInfo::AgentCircuitId(b"port0".to_vec()));
+    message
+        .opts_mut()
+        .insert(v4::DhcpOption::RelayAgentInformation(info.clone()));
+    assert_eq!(
+        selected(&message, None),
+        Some(Ipv4Addr::new(192, 168, 0, 0))
+    );
+
+    info.insert(RelayInfo::LinkSelection(Ipv4Addr::new(192, 168, 2, 1)));
+    message
+        .opts_mut()
+        .insert(v4::DhcpOption::RelayAgentInformation(info));
+    assert_eq!(
+        selected(&message, Some(Ipv4Addr::new(192, 168, 0, 1))),
+        Some(Ipv4Addr::new(192, 168, 2, 0))
+    );
+
+    message
+        .opts_mut()
+        .insert(v4::DhcpOption::SubnetSelection(Ipv4Addr::new(
+            192, 168, 3, 1,
+        )));
+    assert_eq!(
+        selected(&message, Some(Ipv4Addr::new(192, 168, 0, 1))),
+        Some(Ipv4Addr::new(192, 168, 3, 0))
+    );
+
+    message
+        .opts_mut()
+        .insert(v4::DhcpOption::SubnetSelection(Ipv4Addr::new(10, 0, 0, 1)));
+    assert_eq!(
+        selected(&message, Some(Ipv4Addr::new(192, 168, 0, 1))),
+        None
+    );
+}
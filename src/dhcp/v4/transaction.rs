@@ -0,0 +1,197 @@
+use std::{
+    collections::HashMap,
+    net::Ipv4Addr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Duration, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::conf::OMOI_CONFIG;
+
+/// DHCPOFFER した内容
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Transaction {
+    pub xid: u32,
+    pub chaddr: Vec<u8>,
+    pub offered_ipv4_addr: Ipv4Addr,
+    pub created_at: DateTime<Local>,
+    pub expires_at: DateTime<Local>,
+}
+
+impl Transaction {
+    fn key(xid: u32, chaddr: &[u8]) -> Vec<u8> {
+        xid.to_be_bytes().iter().chain(chaddr).copied().collect()
+    }
+
+    pub fn is_expired(&self, now: DateTime<Local>) -> bool {
+        self.expires_at <= now
+    }
+}
+
+/// (xid, chaddr)
+type TransactionKey = (u32, Vec<u8>);
+
+/// DHCPREQUEST を待っている DHCPOFFER
+///
+/// xid はクライアントごとに選ばれるので、chaddr と組にして区別する。
+/// `tree` があればそこにも記録し、再起動しても引き継ぐ
+#[derive(Clone, Debug)]
+pub struct Transactions {
+    inner: Arc<Mutex<HashMap<TransactionKey, Transaction>>>,
+    tree: Option<sled::Tree>,
+}
+
+impl Transactions {
+    pub fn new() -> Transactions {
+        Transactions {
+            inner: Arc::new(Mutex::new(HashMap::new())),
+            tree: None,
+        }
+    }
+
+    /// `tree` に記録した DHCPOFFER のうち、期限の切れていないものを読み込む
+    pub fn persistent(tree: sled::Tree) -> Result<Transactions> {
+        let now = Local::now();
+        let mut transactions = HashMap::new();
+        for entry in tree.iter() {
+            let (key, value) = entry?;
+            match bincode::deserialize::<Transaction>(&value) {
+                Ok(transaction) if !transaction.is_expired(now) => {
+                    transactions.insert((transaction.xid, transaction.chaddr.clone()), transaction);
+                }
+                _ => {
+                    tree.remove(key)?;
+                }
+            }
+        }
+        Ok(Transactions {
+            inner: Arc::new(Mutex::new(transactions)),
+            tree: Some(tree),
+        })
+    }
+
+    pub fn new_transaction(
+        &self,
+        xid: u32,
+        chaddr: &[u8],
+        offered_ipv4_addr: Ipv4Addr,
+        hold: Duration,
+    ) -> Result<()> {
+        let now = Local::now();
+        let transaction = Transaction {
+            xid,
+            chaddr: chaddr.to_vec(),
+            offered_ipv4_addr,
+            created_at: now,
+            expires_at: now + hold,
+        };
+        let Ok(mut transactions) = self.inner.lock() else {
+            bail!("transactions lock failed");
+        };
+        if let Some(tree) = &self.tree {
+            tree.insert(
+                Transaction::key(xid, chaddr),
+                bincode::serialize(&transaction)?,
+            )?;
+        }
+        transactions.insert((xid, chaddr.to_vec()), transaction);
+        Ok(())
+    }
+
+    /// DHCPREQUEST を受けたので取り出す。期限切れなら見つからない扱いにする
+    pub fn remove(&self, xid: u32, chaddr: &[u8]) -> Result<Transaction> {
+        let Ok(mut transactions) = self.inner.lock() else {
+            bail!("transactions lock failed");
+        };
+        if let Some(tree) = &self.tree {
+            tree.remove(Transaction::key(xid, chaddr))?;
+        }
+        let Some(transaction) = transactions.remove(&(xid, chaddr.to_vec())) else {
+            bail!("transaction xid={xid} not found");
+        };
+        if transaction.is_expired(Local::now()) {
+            bail!("transaction xid={xid} expired");
+        }
+        Ok(transaction)
+    }
+
+    /// 期限の切れた DHCPOFFER を捨て、その数を返す
+    pub fn sweep(&self, now: DateTime<Local>) -> Result<usize> {
+        let Ok(mut transactions) = self.inner.lock() else {
+            bail!("transactions lock failed");
+        };
+        let expired = transactions
+            .iter()
+            .filter(|(_, transaction)| transaction.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for (xid, chaddr) in &expired {
+            if let Some(tree) = &self.tree {
+                tree.remove(Transaction::key(*xid, chaddr))?;
+            }
+            transactions.remove(&(*xid, chaddr.clone()));
+        }
+        Ok(expired.len())
+    }
+
+    /// `dhcp4.offer-hold-time` ごとに期限の切れた DHCPOFFER を捨て続ける
+    ///
+    /// 間隔は毎回その時の設定から決めるので、読み込み直した保持時間もそのまま使われる
+    pub async fn sweep_every_hold_time(self) {
+        loop {
+            let hold_time = OMOI_CONFIG.current().dhcp4.offer_hold_time.max(1);
+            tokio::time::sleep(std::time::Duration::from_secs(hold_time.into())).await;
+            if let Err(e) = self.sweep(Local::now()) {
+                eprintln!("{e}");
+            }
+        }
+    }
+}
+
+#[test]
+fn transactions_test() {
+    let chaddr = [0, 0, 0, 0x11, 0x11, 0x11];
+    let other = [0, 0, 0, 0x22, 0x22, 0x22];
+    let addr = Ipv4Addr::new(192, 168, 0, 101);
+    let tree = sled::Config::new()
+        .temporary(true)
+        .open()
+        .unwrap()
+        .open_tree("OFFERS4")
+        .unwrap();
+    let transactions = Transactions::persistent(tree.clone()).unwrap();
+
+    // 同じ xid でも chaddr が違えば別の DHCPOFFER
+    transactions
+        .new_transaction(1, &chaddr, addr, Duration::seconds(60))
+        .unwrap();
+    assert!(transactions.remove(1, &other).is_err());
+    assert_eq!(
+        transactions.remove(1, &chaddr).unwrap().offered_ipv4_addr,
+        addr
+    );
+    assert!(transactions.remove(1, &chaddr).is_err());
+
+    // 再起動しても期限内のものは引き継ぐ
+    transactions
+        .new_transaction(2, &chaddr, addr, Duration::seconds(60))
+        .unwrap();
+    transactions
+        .new_transaction(3, &other, addr, Duration::seconds(1))
+        .unwrap();
+    let transactions = Transactions::persistent(tree.clone()).unwrap();
+    assert_eq!(
+        transactions
+            .sweep(Local::now() + Duration::seconds(30))
+            .unwrap(),
+        1
+    );
+    assert!(transactions.remove(3, &other).is_err());
+    assert_eq!(
+        transactions.remove(2, &chaddr).unwrap().offered_ipv4_addr,
+        addr
+    );
+    assert!(tree.is_empty());
+}
@@ -0,0 +1,68 @@
+use std::{fmt::Debug, sync::Arc};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::net::UdpSocket;
+
+use super::{reply::Destination, PacketInfo};
+
+/// 応答を送り出す手段
+#[async_trait]
+pub trait Transport: Send + Sync + Debug {
+    /// `packet_info` は応答元となる受信パケットの情報
+    async fn send(
+        &self,
+        buffer: &[u8],
+        dest: &Destination,
+        packet_info: Option<PacketInfo>,
+    ) -> Result<()>;
+}
+
+/// UDP ソケットで送る (既定)
+#[derive(Clone, Debug)]
+pub struct UdpTransport {
+    socket: Arc<UdpSocket>,
+}
+
+impl UdpTransport {
+    pub fn new(socket: Arc<UdpSocket>) -> UdpTransport {
+        UdpTransport { socket }
+    }
+}
+
+#[async_trait]
+impl Transport for UdpTransport {
+    async fn send(
+        &self,
+        buffer: &[u8],
+        dest: &Destination,
+        _packet_info: Option<PacketInfo>,
+    ) -> Result<()> {
+        self.socket.send_to(buffer, dest.socket_addr()).await?;
+        Ok(())
+    }
+}
+
+/// 送ろうとしたパケットを記録するだけのテスト用の実装
+#[cfg(test)]
+#[derive(Default, Debug)]
+pub struct MockTransport {
+    pub sent: std::sync::Mutex<Vec<(Vec<u8>, Destination)>>,
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Transport for MockTransport {
+    async fn send(
+        &self,
+        buffer: &[u8],
+        dest: &Destination,
+        _packet_info: Option<PacketInfo>,
+    ) -> Result<()> {
+        self.sent
+            .lock()
+            .expect("mock transport lock failed")
+            .push((buffer.to_vec(), dest.clone()));
+        Ok(())
+    }
+}
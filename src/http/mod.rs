@@ -1,13 +1,23 @@
-use std::net::Ipv4Addr;
+use std::{net::Ipv4Addr, sync::atomic::Ordering};
 
 use anyhow::Result;
-use axum::{http::StatusCode, response::IntoResponse, routing::get, Json, Router};
+use axum::{
+    extract::Query,
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
 use chrono::{DateTime, Local};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    conf::OMOI_CONFIG,
-    db::{Db, Leases4Record},
+    conf::{ConfigErrors, OMOI_CONFIG},
+    db::{
+        transfer::{self, ImportReport, LeaseFormat},
+        Declined4Record, Leases4Record, Leases4State, Relay4Info, LEASES4,
+    },
+    dhcp::v4::ROUTER,
 };
 
 #[derive(Serialize, Debug)]
@@ -15,40 +25,236 @@ pub struct Lease4 {
     hardware_address: Vec<u8>,
     ip_addr: Ipv4Addr,
     ttl: DateTime<Local>,
+    client_id: Option<Vec<u8>>,
+    lease_time: Option<u32>,
+    relay: Option<Relay4Info>,
+    state: Leases4State,
+    started_at: Option<DateTime<Local>>,
+    hostname: Option<String>,
+    subnet_id: Option<u32>,
+    last_packet_at: Option<DateTime<Local>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Lease4AllResponse {
+    leases: Vec<Lease4>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Declined4 {
+    hardware_address: Vec<u8>,
+    ip_addr: Ipv4Addr,
+    declined_at: DateTime<Local>,
+    until: DateTime<Local>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Declined4AllResponse {
+    declined: Vec<Declined4>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FormatQuery {
+    format: LeaseFormat,
 }
 
 #[derive(Serialize, Debug)]
 #[serde(untagged)]
-pub enum Lease4AllResponse {
-    Ok { leases: Vec<Lease4> },
+pub enum ImportResponse {
+    Ok(ImportReport),
     Err(String),
 }
 
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum ReloadResponse {
+    Ok { restart_required: Vec<&'static str> },
+    Err { errors: Vec<String> },
+}
+
+#[derive(Serialize, Debug)]
+pub struct Handler4Stats {
+    message_type: String,
+    handled: u64,
+    failed: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Stats4 {
+    malformed: u64,
+    unknown: u64,
+    handlers: Vec<Handler4Stats>,
+}
+
 impl From<Leases4Record> for Lease4 {
     fn from(value: Leases4Record) -> Self {
         Lease4 {
             hardware_address: value.hardware_address,
             ip_addr: value.ip_addr,
             ttl: value.ttl,
+            client_id: value.client_id,
+            lease_time: value.lease_time,
+            relay: value.relay,
+            state: value.state,
+            started_at: value.started_at,
+            hostname: value.hostname,
+            subnet_id: value.subnet_id,
+            last_packet_at: value.last_packet_at,
+        }
+    }
+}
+
+impl From<Declined4Record> for Declined4 {
+    fn from(value: Declined4Record) -> Self {
+        Declined4 {
+            hardware_address: value.hardware_address,
+            ip_addr: value.ip_addr,
+            declined_at: value.declined_at,
+            until: value.until,
         }
     }
 }
 
 async fn get_all_leases() -> impl IntoResponse {
-    let Ok(leases) = Db::open().leases_tree() else {
-        return (StatusCode::INTERNAL_SERVER_ERROR, Json(Lease4AllResponse::Err("Internal Server Error".to_string())));
-    };
     (
         StatusCode::OK,
-        Json(Lease4AllResponse::Ok {
-            leases: leases.all().into_iter().map(Lease4::from).collect(),
+        Json(Lease4AllResponse {
+            leases: LEASES4.iter().map(Lease4::from).collect(),
+        }),
+    )
+}
+
+async fn get_all_declined() -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        Json(Declined4AllResponse {
+            declined: LEASES4
+                .iter_declined()
+                .filter(Declined4Record::is_quarantined)
+                .map(Declined4::from)
+                .collect(),
         }),
     )
 }
 
+async fn export_leases(Query(query): Query<FormatQuery>) -> impl IntoResponse {
+    let records = LEASES4.iter().collect::<Vec<_>>();
+    match query.format.export(&records) {
+        Ok(body) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, query.format.content_type())],
+            body,
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            [(header::CONTENT_TYPE, "text/plain")],
+            e.to_string(),
+        ),
+    }
+}
+
+/// トークンの長さ以外が比べる時間から分からないように比べる
+fn token_matches(given: &[u8], token: &[u8]) -> bool {
+    given.len() == token.len()
+        && given
+            .iter()
+            .zip(token)
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+/// `Authorization: Bearer` のトークンが `http.admin-token` と一致するか
+///
+/// トークンが設定されていなければ、管理用のエンドポイントは無いものとする
+fn authorize(headers: &HeaderMap) -> Result<(), (StatusCode, &'static str)> {
+    let Some(token) = OMOI_CONFIG.current().http.admin_token.clone() else {
+        return Err((StatusCode::NOT_FOUND, "http.admin-token is not configured"));
+    };
+    let authorized = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| token_matches(given.as_bytes(), token.as_bytes()));
+    if !authorized {
+        return Err((StatusCode::UNAUTHORIZED, "invalid token"));
+    }
+    Ok(())
+}
+
+async fn import_leases(
+    headers: HeaderMap,
+    Query(query): Query<FormatQuery>,
+    body: String,
+) -> impl IntoResponse {
+    if let Err((status, error)) = authorize(&headers) {
+        return (status, Json(ImportResponse::Err(error.to_string())));
+    }
+    let records = match query.format.parse(&body) {
+        Ok(records) => records,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(ImportResponse::Err(e.to_string())),
+            )
+        }
+    };
+    let report = transfer::import(LEASES4.as_ref(), &OMOI_CONFIG.current().dhcp4, records);
+    (StatusCode::OK, Json(ImportResponse::Ok(report)))
+}
+
+async fn reload_config(headers: HeaderMap) -> impl IntoResponse {
+    if let Err((status, error)) = authorize(&headers) {
+        return (
+            status,
+            Json(ReloadResponse::Err {
+                errors: vec![error.to_string()],
+            }),
+        );
+    }
+    match OMOI_CONFIG.reload() {
+        Ok(restart_required) => (
+            StatusCode::OK,
+            Json(ReloadResponse::Ok { restart_required }),
+        ),
+        Err(e) => {
+            let errors = match e.downcast_ref::<ConfigErrors>() {
+                Some(errors) => errors.0.iter().map(ToString::to_string).collect(),
+                None => vec![format!("{e:#}")],
+            };
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(ReloadResponse::Err { errors }),
+            )
+        }
+    }
+}
+
+async fn get_stats() -> impl IntoResponse {
+    let stats = ROUTER.stats();
+    Json(Stats4 {
+        malformed: stats.malformed.load(Ordering::Relaxed),
+        unknown: stats.unknown.load(Ordering::Relaxed),
+        handlers: stats
+            .by_message_type()
+            .into_iter()
+            .map(|(message_type, handled, failed)| Handler4Stats {
+                message_type: format!("{message_type:?}"),
+                handled,
+                failed,
+            })
+            .collect(),
+    })
+}
+
 pub async fn serve() -> Result<()> {
-    let app = Router::new().route("/leases4", get(get_all_leases));
-    axum::Server::bind(&OMOI_CONFIG.http.addr)
+    let app = Router::new()
+        .route("/leases4", get(get_all_leases))
+        .route("/leases4/export", get(export_leases))
+        .route("/leases4/import", post(import_leases))
+        .route("/declined4", get(get_all_declined))
+        .route("/stats4", get(get_stats))
+        .route("/config/reload", post(reload_config));
+    axum::Server::bind(&OMOI_CONFIG.current().http.addr)
         .serve(app.into_make_service())
         .await?;
     Ok(())
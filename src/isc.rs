@@ -0,0 +1,198 @@
+//! ISC dhcpd の dhcpd.conf や dhcpd.leases に共通する構文
+
+use anyhow::{bail, Result};
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Token {
+    Word(String),
+    /// 引用符で囲まれた文字列, `\ooo` のエスケープを解いたもの
+    Str(Vec<u8>),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Lexeme {
+    Token(Token),
+    Open,
+    Close,
+    Semicolon,
+}
+
+/// `;` で終わる文か、`{ ... }` の付いた文
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Statement {
+    /// 文が始まる行
+    pub line: usize,
+    pub tokens: Vec<Token>,
+    pub block: Option<Vec<Statement>>,
+}
+
+impl Statement {
+    /// 引用符で囲まれていない語, `,` も 1 語になる
+    pub fn words(&self) -> Vec<&str> {
+        self.tokens
+            .iter()
+            .filter_map(|token| match token {
+                Token::Word(word) => Some(word.as_str()),
+                Token::Str(_) => None,
+            })
+            .collect()
+    }
+
+    /// 最後の引用符で囲まれた文字列
+    pub fn string(&self) -> Option<&[u8]> {
+        self.tokens.iter().rev().find_map(|token| match token {
+            Token::Str(bytes) => Some(bytes.as_slice()),
+            Token::Word(_) => None,
+        })
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<(usize, Lexeme)>> {
+    let mut lexemes = vec![];
+    let mut line = 1;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let lexeme = match c {
+            '\n' => {
+                line += 1;
+                continue;
+            }
+            '#' => {
+                while chars.next_if(|&c| c != '\n').is_some() {}
+                continue;
+            }
+            c if c.is_whitespace() => continue,
+            '{' => Lexeme::Open,
+            '}' => Lexeme::Close,
+            ';' => Lexeme::Semicolon,
+            ',' => Lexeme::Token(Token::Word(",".to_string())),
+            '"' => {
+                let mut bytes = vec![];
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            let octal = (0..3)
+                                .map_while(|_| chars.next_if(|c| c.is_digit(8)))
+                                .collect::<String>();
+                            if octal.is_empty() {
+                                let Some(c) = chars.next() else {
+                                    bail!("unterminated string at line {line}");
+                                };
+                                bytes.extend(c.to_string().as_bytes());
+                            } else {
+                                bytes.push(u8::from_str_radix(&octal, 8)?);
+                            }
+                        }
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            bytes.extend(c.to_string().as_bytes());
+                        }
+                        None => bail!("unterminated string at line {line}"),
+                    }
+                }
+                Lexeme::Token(Token::Str(bytes))
+            }
+            c => {
+                let mut word = c.to_string();
+                while let Some(c) = chars
+                    .next_if(|c| !c.is_whitespace() && !matches!(c, '{' | '}' | ';' | ',' | '"'))
+                {
+                    word.push(c);
+                }
+                Lexeme::Token(Token::Word(word))
+            }
+        };
+        lexemes.push((line, lexeme));
+    }
+    Ok(lexemes)
+}
+
+fn statements(
+    lexemes: &mut impl Iterator<Item = (usize, Lexeme)>,
+    nested: bool,
+) -> Result<Vec<Statement>> {
+    let mut statements = vec![];
+    let mut tokens = vec![];
+    let mut start = None;
+    loop {
+        let Some((line, lexeme)) = lexemes.next() else {
+            if nested {
+                bail!("unterminated block");
+            }
+            if !tokens.is_empty() {
+                bail!("missing ; at the end");
+            }
+            return Ok(statements);
+        };
+        match lexeme {
+            Lexeme::Token(token) => {
+                start.get_or_insert(line);
+                tokens.push(token);
+            }
+            Lexeme::Semicolon if tokens.is_empty() => {}
+            Lexeme::Semicolon => statements.push(Statement {
+                line: start.take().unwrap_or(line),
+                tokens: std::mem::take(&mut tokens),
+                block: None,
+            }),
+            Lexeme::Open => statements.push(Statement {
+                line: start.take().unwrap_or(line),
+                tokens: std::mem::take(&mut tokens),
+                block: Some(self::statements(lexemes, true)?),
+            }),
+            Lexeme::Close if nested => {
+                if !tokens.is_empty() {
+                    bail!("missing ; at line {line}");
+                }
+                return Ok(statements);
+            }
+            Lexeme::Close => bail!("unexpected }} at line {line}"),
+        }
+    }
+}
+
+pub fn parse(text: &str) -> Result<Vec<Statement>> {
+    statements(&mut tokenize(text)?.into_iter(), false)
+}
+
+#[test]
+fn parse_test() {
+    let statements = parse(
+        r#"
+# comment
+option domain-name-servers 192.168.0.1, 192.168.0.2;
+host "a b" {
+  uid "\001\000\021\"3DU";
+  on expiry { set x = "y"; }
+}
+"#,
+    )
+    .unwrap();
+    assert_eq!(statements.len(), 2);
+    assert_eq!(statements[0].line, 3);
+    assert_eq!(
+        statements[0].words(),
+        vec![
+            "option",
+            "domain-name-servers",
+            "192.168.0.1",
+            ",",
+            "192.168.0.2"
+        ]
+    );
+    assert_eq!(statements[1].string(), Some(b"a b".as_slice()));
+    let block = statements[1].block.as_ref().unwrap();
+    assert_eq!(
+        block[0].string(),
+        Some([1, 0, 0x11, b'"', b'3', b'D', b'U'].as_slice())
+    );
+    assert_eq!(block[1].line, 6);
+    assert!(block[1].block.is_some());
+
+    assert!(parse("lease 192.168.0.1 {").is_err());
+    assert!(parse("option x 1").is_err());
+    assert!(parse("}").is_err());
+}
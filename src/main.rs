@@ -2,16 +2,142 @@ mod conf;
 mod db;
 mod dhcp;
 mod http;
+mod isc;
 
-use self::conf::OMOI_CONFIG;
-use anyhow::{anyhow, ensure, Result};
+use std::path::PathBuf;
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    ensure!(OMOI_CONFIG.dhcp4.subnets.len() == 1);
+use self::{
+    conf::{convert::ConfigFormat, OmoiConfig, OMOI_CONFIG},
+    db::{
+        transfer::{self, LeaseFormat},
+        LEASES4,
+    },
+};
+use anyhow::{anyhow, bail, ensure, Result};
+use clap::{Parser, Subcommand};
+use tokio::signal::unix::{signal, SignalKind};
+
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// DHCP サーバーと HTTP サーバーを起動する (省略したときも同じ)
+    Serve,
+    /// リースを書き出したり読み込んだりする
+    ///
+    /// サーバーが動いている間は sled を開けないので HTTP の `/leases4/export` や `/leases4/import` を使う
+    #[command(subcommand)]
+    Leases(LeasesCommand),
+    /// サーバーを起動せずに設定を確かめる
+    CheckConfig {
+        /// 省略すると `OMOI_CONFIG_PATH` か /etc/omoi.toml
+        path: Option<PathBuf>,
+    },
+    /// ISC dhcpd や Kea の設定を omoi.toml にする
+    ///
+    /// 変換できなかったものは標準エラー出力と omoi.toml の先頭のコメントに書く
+    ConvertConfig {
+        #[arg(long, value_enum)]
+        from: ConfigFormat,
+        path: PathBuf,
+        /// 省略すると標準出力に書く
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum LeasesCommand {
+    /// リースを標準出力に書き出す
+    Export {
+        #[arg(long, value_enum)]
+        format: LeaseFormat,
+    },
+    /// ファイルからリースを読み込む
+    Import {
+        #[arg(long, value_enum)]
+        format: LeaseFormat,
+        path: PathBuf,
+    },
+}
+
+impl LeasesCommand {
+    fn run(self) -> Result<()> {
+        match self {
+            LeasesCommand::Export { format } => {
+                print!("{}", format.export(&LEASES4.iter().collect::<Vec<_>>())?);
+            }
+            LeasesCommand::Import { format, path } => {
+                let records = format.parse(&std::fs::read_to_string(path)?)?;
+                let report =
+                    transfer::import(LEASES4.as_ref(), &OMOI_CONFIG.current().dhcp4, records);
+                println!("imported {} leases", report.imported);
+                for conflict in &report.conflicts {
+                    eprintln!("{}: {}", conflict.ip_addr, conflict.reason);
+                }
+                if !report.conflicts.is_empty() {
+                    bail!("{} leases were not imported", report.conflicts.len());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check_config(path: Option<PathBuf>) -> Result<()> {
+    let path = path.unwrap_or_else(OmoiConfig::path);
+    let config = OmoiConfig::read(&path)?;
+    if let Err(errors) = config.validate() {
+        for error in &errors.0 {
+            eprintln!("{error}");
+        }
+        bail!("{} errors in {}", errors.0.len(), path.display());
+    }
+    println!("{}: ok", path.display());
+    Ok(())
+}
+
+fn convert_config(from: ConfigFormat, path: PathBuf, output: Option<PathBuf>) -> Result<()> {
+    let conversion = from.convert(&std::fs::read_to_string(path)?)?;
+    let text = conversion.to_toml()?;
+    match output {
+        Some(output) => std::fs::write(output, text)?,
+        None => print!("{text}"),
+    }
+    for untranslated in &conversion.untranslated {
+        eprintln!("{untranslated}");
+    }
+    if !conversion.untranslated.is_empty() {
+        eprintln!(
+            "{} constructs were not translated",
+            conversion.untranslated.len()
+        );
+    }
+    Ok(())
+}
+
+/// SIGHUP を受けるたびに設定を読み込み直す
+async fn reload_on_hangup() -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        if let Err(e) = OMOI_CONFIG.reload() {
+            eprintln!("config was not reloaded: {e:#}");
+        }
+    }
+    Ok(())
+}
+
+async fn serve() -> Result<()> {
+    ensure!(!OMOI_CONFIG.current().dhcp4.subnets.is_empty());
     let r = tokio::select! {
         r = dhcp::v4::serve() => {r},
         r = http::serve() => {r},
+        r = reload_on_hangup() => {r},
         r = tokio::signal::ctrl_c() => {r.map_err(|e| anyhow!(e))},
     };
     if let Err(e) = r {
@@ -19,3 +145,13 @@ async fn main() -> Result<()> {
     }
     Ok(())
 }
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    match Args::parse().command.unwrap_or(Command::Serve) {
+        Command::Serve => serve().await,
+        Command::Leases(command) => command.run(),
+        Command::CheckConfig { path } => check_config(path),
+        Command::ConvertConfig { from, path, output } => convert_config(from, path, output),
+    }
+}